@@ -0,0 +1,191 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use fastly::http::{Method, Url};
+use fastly::Request;
+use govuk_edge::cdn_config;
+use govuk_edge::cdn_headers::FASTLY_SSL_HEADER_NAME;
+use govuk_edge::cdn_rules::{self, accounts, simulate};
+use std::net::IpAddr;
+
+/// The configuration deployed with the service
+const CONFIG: &str = include_str!("../../config.yaml");
+
+/// A typical `Cookie` header for a visitor who has accepted cookies
+/// and is in an A/B test
+const COOKIE: &str = "cookies_policy=%7B%22essential%22%3Atrue%2C%22settings%22%3Atrue%2C%22usage%22%3Atrue%2C%22campaigns%22%3Atrue%7D; cookies_preferences_set=true; ABTest-Example=B; govuk_edge_id=0b5d7a2c-5c5e-4a8e-8f4e-3f7c1c2a9b11; _ga=GA1.3.123456789.1620000000; _gid=GA1.3.987654321.1620000000";
+
+/// Representative request URLs: the homepage, a guide, a search with
+/// tracking params, and a finder with many facets
+const URLS: [(&str, &str); 4] = [
+    ("homepage", "https://www.gov.uk/"),
+    ("guide", "https://www.gov.uk/vehicle-tax"),
+    ("search", "https://www.gov.uk/search/all?keywords=self+assessment&order=relevance&utm_source=newsletter&utm_medium=email&utm_campaign=tax"),
+    ("finder", "https://www.gov.uk/search/news-and-communications?level_one_taxon=3cf97f69-84de-41ae-bc7b-7e2cc238fa58&content_store_document_type=news_stories&public_timestamp%5Bfrom%5D=01%2F01%2F2021&public_timestamp%5Bto%5D=31%2F12%2F2021&organisations%5B%5D=hm-revenue-customs&organisations%5B%5D=cabinet-office&page=3"),
+];
+
+/// Build an HTML page in the shape of a GOV.UK page (header with the
+/// account links, breadcrumbs, `sections` sections of body text, and
+/// footer), at roughly 2.5KB per section.
+fn govuk_page(sections: usize) -> Vec<u8> {
+    let mut page = String::from(
+        r#"<!DOCTYPE html>
+<html lang="en" class="govuk-template">
+<head>
+  <meta charset="utf-8">
+  <title>Vehicle tax - GOV.UK</title>
+  <link rel="stylesheet" href="/assets/static/application.css">
+</head>
+<body class="govuk-template__body">
+  <header role="banner" class="govuk-header">
+    <div class="govuk-header__container govuk-width-container">
+      <a href="/" class="govuk-header__link govuk-header__link--homepage">GOV.UK</a>
+      <nav class="govuk-header__navigation">
+        <ul class="govuk-header__navigation-list">
+          <li class="compute_at_edge--show-if-cookie"><a href="/account/home">Your account</a></li>
+          <li class="compute_at_edge--show-if-cookie"><a href="/sign-out">Sign out</a></li>
+          <li class="compute_at_edge--show-if-not-cookie"><a href="/sign-in">Sign in</a></li>
+        </ul>
+      </nav>
+    </div>
+  </header>
+  <div class="govuk-width-container">
+    <div class="compute_at_edge--show-if-mirrored govuk-notification-banner">
+      <p>This is a cached copy of the page and may be out of date.</p>
+    </div>
+    <nav class="govuk-breadcrumbs">
+      <ol class="govuk-breadcrumbs__list">
+        <li class="govuk-breadcrumbs__list-item"><a href="/">Home</a></li>
+        <li class="govuk-breadcrumbs__list-item"><a href="/browse/driving">Driving and transport</a></li>
+      </ol>
+    </nav>
+    <main class="govuk-main-wrapper" id="main-content" role="main">
+"#,
+    );
+
+    for section in 0..sections {
+        page.push_str(&format!(
+            r#"      <h2 class="govuk-heading-m" id="section-{0}">Section {0}</h2>
+      <p class="govuk-body">You must tax your vehicle even if you do not have to pay anything, for example if you're exempt because you're disabled. You'll need to tell DVLA when you sell, transfer or buy a vehicle.</p>
+      <p class="govuk-body">You can <a href="/check-vehicle-tax" class="govuk-link">check if a vehicle is taxed</a> or <a href="/make-a-sorn" class="govuk-link">make a SORN</a> if you're keeping it off the road.</p>
+      <ul class="govuk-list govuk-list--bullet">
+        <li>a reference number from a recent reminder (V11) or 'last chance' warning letter from DVLA</li>
+        <li>the 16-digit reference number from your vehicle log book (V5C)</li>
+        <li>the reference number from a green 'new keeper' slip, if you've just bought the vehicle</li>
+      </ul>
+      <div class="govuk-inset-text">You'll get a reminder when your vehicle tax is due to run out.</div>
+      <p class="govuk-body">The fastest way to pay is online. You can also pay by phone, or at a Post Office that deals with vehicle tax. The DVLA vehicle tax service is available 24 hours a day, 7 days a week, and you can pay by debit or credit card, or by Direct Debit.</p>
+      <table class="govuk-table">
+        <tr class="govuk-table__row"><th class="govuk-table__header">Payment</th><th class="govuk-table__header">Amount</th></tr>
+        <tr class="govuk-table__row"><td class="govuk-table__cell">12 months</td><td class="govuk-table__cell">£165</td></tr>
+        <tr class="govuk-table__row"><td class="govuk-table__cell">6 months</td><td class="govuk-table__cell">£90.75</td></tr>
+      </table>
+"#,
+            section
+        ));
+    }
+
+    page.push_str(
+        r#"    </main>
+  </div>
+  <footer class="govuk-footer" role="contentinfo">
+    <div class="govuk-width-container">
+      <a class="govuk-footer__link" href="/help">Help</a>
+      <a class="govuk-footer__link" href="/help/cookies">Cookies</a>
+      <a class="govuk-footer__link" href="/contact">Contact</a>
+    </div>
+  </footer>
+  <script src="/assets/static/application.js"></script>
+</body>
+</html>
+"#,
+    );
+
+    page.into_bytes()
+}
+
+fn request(url: &str) -> Request {
+    let mut req = Request::new(Method::GET, Url::parse(url).unwrap());
+    req.set_header("Host", "www.gov.uk");
+    req.set_header(FASTLY_SSL_HEADER_NAME, "1");
+    req.set_header("Authorization", "Basic foo");
+    req.set_header("Cookie", COOKIE);
+    req
+}
+
+fn parse_config(c: &mut Criterion) {
+    c.bench_function("parse_config", |b| {
+        b.iter(|| cdn_config::parse_host_configs(black_box(CONFIG)))
+    });
+}
+
+fn get_cookies(c: &mut Criterion) {
+    c.bench_function("get_cookies", |b| {
+        b.iter(|| cdn_rules::get_cookies(black_box(Some(COOKIE))))
+    });
+}
+
+fn normalise_querystring(c: &mut Criterion) {
+    let host_configs = cdn_config::parse_host_configs(CONFIG)
+        .unwrap_or_else(|error| panic!("could not parse config.yaml: {}", error));
+    let settings = &host_configs.default;
+    let mut group = c.benchmark_group("normalise_querystring");
+    for (name, url) in &URLS {
+        let req = request(url);
+        group.bench_function(*name, |b| {
+            b.iter(|| cdn_rules::normalise_querystring(settings, black_box(&req)))
+        });
+    }
+    group.finish();
+}
+
+fn recv(c: &mut Criterion) {
+    let host_configs = cdn_config::parse_host_configs(CONFIG)
+        .unwrap_or_else(|error| panic!("could not parse config.yaml: {}", error));
+    let settings = &host_configs.default;
+    let client_ip: Option<IpAddr> = Some("203.0.113.1".parse().unwrap());
+
+    let mut group = c.benchmark_group("recv");
+    for (name, url) in &URLS {
+        let req = request(url);
+        group.bench_function(*name, |b| {
+            b.iter(|| simulate::recv(settings, black_box(&req), client_ip))
+        });
+    }
+    group.finish();
+}
+
+fn transform_html(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transform_html");
+    for (name, sections) in &[("start_page", 4), ("guide", 40), ("manual", 400)] {
+        let page = govuk_page(*sections);
+        group.throughput(Throughput::Bytes(page.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut output = Vec::with_capacity(page.len());
+                accounts::rewrite_classes(
+                    black_box(page.as_slice()),
+                    &mut output,
+                    &[
+                        ("compute_at_edge--show-if-mirrored", "compute_at_edge--hide"),
+                        ("compute_at_edge--show-if-stale", "compute_at_edge--hide"),
+                        ("compute_at_edge--show-if-abtest-preview", "compute_at_edge--hide"),
+                        ("compute_at_edge--show-if-cookie", "compute_at_edge--show"),
+                        ("compute_at_edge--show-if-not-cookie", "compute_at_edge--hide"),
+                        ("compute_at_edge--show-if-maintenance", "compute_at_edge--hide"),
+                    ],
+                );
+                output
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    parse_config,
+    get_cookies,
+    normalise_querystring,
+    recv,
+    transform_html
+);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+//! Compile the bulk redirect table (`redirects.tsv`) into a sorted
+//! array, so looking up a path is a binary search rather than parsing
+//! the whole table on every request.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=redirects.tsv");
+
+    let tsv = fs::read_to_string("redirects.tsv").expect("could not read redirects.tsv");
+
+    let mut redirects = BTreeMap::new();
+    for (index, line) in tsv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() != 2 || !fields[0].starts_with('/') {
+            panic!(
+                "redirects.tsv:{}: expected <path>\\t<destination>",
+                index + 1
+            );
+        }
+        if redirects.insert(fields[0], fields[1]).is_some() {
+            panic!("redirects.tsv:{}: duplicate path {}", index + 1, fields[0]);
+        }
+    }
+
+    let mut out = String::from("static REDIRECTS: &[(&str, &str)] = &[\n");
+    for (path, destination) in redirects.iter() {
+        out.push_str(&format!("    ({:?}, {:?}),\n", path, destination));
+    }
+    out.push_str("];\n");
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("redirects.rs");
+    fs::write(out_path, out).expect("could not write redirects.rs");
+}
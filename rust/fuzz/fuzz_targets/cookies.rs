@@ -0,0 +1,7 @@
+#![no_main]
+use govuk_edge::cdn_rules;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = cdn_rules::get_cookies(Some(data));
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use govuk_edge::cdn_rules::accounts;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut output = Vec::new();
+    accounts::rewrite_classes(
+        data,
+        &mut output,
+        &[
+            ("compute_at_edge--show-if-mirrored", "compute_at_edge--hide"),
+            ("compute_at_edge--show-if-stale", "compute_at_edge--hide"),
+            ("compute_at_edge--show-if-abtest-preview", "compute_at_edge--hide"),
+            ("compute_at_edge--show-if-cookie", "compute_at_edge--show"),
+            ("compute_at_edge--show-if-not-cookie", "compute_at_edge--hide"),
+            ("compute_at_edge--show-if-maintenance", "compute_at_edge--hide"),
+        ],
+    );
+});
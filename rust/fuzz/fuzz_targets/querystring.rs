@@ -0,0 +1,25 @@
+#![no_main]
+use fastly::http::{Method, Url};
+use fastly::Request;
+use govuk_edge::cdn_config::{self, Config};
+use govuk_edge::cdn_rules;
+use libfuzzer_sys::fuzz_target;
+
+/// The configuration deployed with the service
+const CONFIG: &str = include_str!("../../config.yaml");
+
+thread_local! {
+    static SETTINGS: Config = match cdn_config::parse_host_configs(CONFIG) {
+        Ok(host_configs) => host_configs.default,
+        Err(error) => panic!("could not parse config.yaml: {}", error),
+    };
+}
+
+fuzz_target!(|data: &str| {
+    if let Ok(url) = Url::parse(&format!("https://www.gov.uk{}", data)) {
+        let req = Request::new(Method::GET, url);
+        SETTINGS.with(|settings| {
+            let _ = cdn_rules::normalise_querystring(settings, &req);
+        });
+    }
+});
@@ -0,0 +1,120 @@
+//! Run a request description through the edge rules and print what
+//! they decided, for debugging the rules without deploying them.
+//!
+//! Usage: `simulate [config.yaml] < request.json`, where the request
+//! looks like:
+//!
+//! ```json
+//! {
+//!   "method": "GET",
+//!   "host": "www.gov.uk",
+//!   "path": "/search?q=tax&utm_source=email",
+//!   "headers": { "User-Agent": "curl" },
+//!   "cookies": { "cookies_policy": "{\"usage\":true}" },
+//!   "client_ip": "203.0.113.1",
+//!   "ssl": true
+//! }
+//! ```
+//!
+//! Every field is optional.
+
+use fastly::Request;
+use govuk_edge::cdn_config;
+use govuk_edge::cdn_headers::FASTLY_SSL_HEADER_NAME;
+use govuk_edge::cdn_rules::simulate;
+use serde_json::{Map, Value};
+use std::io::Read;
+use std::net::IpAddr;
+use std::process;
+
+fn main() {
+    let config_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "config.yaml".to_string());
+    let config_str = std::fs::read_to_string(&config_path)
+        .unwrap_or_else(|error| fail(&format!("could not read {}: {}", config_path, error)));
+    let host_configs = cdn_config::parse_host_configs(&config_str)
+        .unwrap_or_else(|error| fail(&format!("could not parse {}: {}", config_path, error)));
+
+    let mut input = String::new();
+    if let Err(error) = std::io::stdin().read_to_string(&mut input) {
+        fail(&format!("could not read request: {}", error));
+    }
+    let description: Map<String, Value> = serde_json::from_str(&input)
+        .unwrap_or_else(|error| fail(&format!("could not parse request: {}", error)));
+
+    let (req, client_ip) = build_request(&description);
+    let settings = host_configs.for_host(req.get_header_str("host"));
+
+    let decisions = simulate::recv(settings, &req, client_ip)
+        .into_iter()
+        .map(|decision| {
+            let mut fields = Map::new();
+            fields.insert("step".to_string(), Value::from(decision.step));
+            fields.insert("outcome".to_string(), Value::from(decision.outcome));
+            Value::Object(fields)
+        })
+        .collect::<Vec<_>>();
+
+    match serde_json::to_string_pretty(&decisions) {
+        Ok(output) => println!("{}", output),
+        Err(error) => fail(&format!("could not print decisions: {}", error)),
+    }
+}
+
+/// Turn the request description into a client request.
+fn build_request(description: &Map<String, Value>) -> (Request, Option<IpAddr>) {
+    let field = |name: &str, default: &'static str| {
+        description
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or(default)
+            .to_string()
+    };
+
+    let host = field("host", "www.gov.uk");
+    let url = format!("https://{}{}", host, field("path", "/"));
+    let mut req = Request::new(field("method", "GET").as_str(), url);
+    req.set_header("Host", host);
+
+    if description
+        .get("ssl")
+        .and_then(Value::as_bool)
+        .unwrap_or(true)
+    {
+        req.set_header(FASTLY_SSL_HEADER_NAME, "1");
+    }
+
+    if let Some(Value::Object(headers)) = description.get("headers") {
+        for (name, value) in headers {
+            if let Some(value) = value.as_str() {
+                req.append_header(name.as_str(), value);
+            }
+        }
+    }
+
+    if let Some(Value::Object(cookies)) = description.get("cookies") {
+        let cookie_str = cookies
+            .iter()
+            .filter_map(|(name, value)| value.as_str().map(|value| format!("{}={}", name, value)))
+            .collect::<Vec<_>>()
+            .join("; ");
+        req.set_header("Cookie", cookie_str);
+    }
+
+    let client_ip = match description.get("client_ip").and_then(Value::as_str) {
+        Some(ip) => Some(
+            ip.parse()
+                .unwrap_or_else(|_| fail(&format!("invalid client_ip: {}", ip))),
+        ),
+        None => None,
+    };
+
+    (req, client_ip)
+}
+
+/// Print an error and exit.
+fn fail(message: &str) -> ! {
+    eprintln!("simulate: {}", message);
+    process::exit(1)
+}
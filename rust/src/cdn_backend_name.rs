@@ -0,0 +1,139 @@
+use crate::cdn_config::Config;
+
+use fastly::Response;
+
+/// Response header naming what served a response
+pub const BACKEND_NAME_HEADER_NAME: &str = "Fastly-Backend-Name";
+
+/// What served a response, as given in the `Fastly-Backend-Name`
+/// header, the access logs, and the metrics.  Dashboards group on
+/// these values, so they're only ever turned into strings here.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendNameTag {
+    /// An origin, shield, or mirror
+    Backend(String),
+    /// The synthetic 503, when no backend could be reached
+    Error,
+    /// The A/B test report
+    ABTestReport,
+    /// A malformed path
+    BadPath,
+    /// An absurd search pagination param
+    BadPagination,
+    /// A forced backend which wasn't allowed
+    ForcedBackend,
+    /// A mirror bucket listing, hidden behind the synthetic 404
+    BucketListing,
+    /// A proof-of-work challenge
+    Challenge,
+    /// A failed CSRF check
+    Csrf,
+    /// An embargoed country
+    Embargo,
+    /// A form submission which looked like spam
+    FormSpam,
+    /// A header rule match
+    HeaderRule,
+    /// A disallowed method
+    MethodNotAllowed,
+    /// An unacceptable Accept header
+    NotAcceptable,
+    /// The synthetic 404
+    NotFound,
+    /// An OPTIONS request which isn't a CORS preflight
+    Options,
+    /// A redirect to another origin
+    OriginRedirect,
+    /// A purge request
+    Purge,
+    /// A browser report
+    Report,
+    /// A rate-limited search
+    SearchRateLimit,
+    /// A request from another Fastly node which couldn't show it came
+    /// from this service
+    Shield,
+    /// An HTTPS redirect or rejection
+    Ssl,
+}
+
+impl BackendNameTag {
+    /// Parse a `Fastly-Backend-Name` value.  Anything which isn't one
+    /// of the synthetic tags is a backend.
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "error" => BackendNameTag::Error,
+            "force_abtest_report" => BackendNameTag::ABTestReport,
+            "force_bad_path" => BackendNameTag::BadPath,
+            "force_bad_pagination" => BackendNameTag::BadPagination,
+            "force_backend" => BackendNameTag::ForcedBackend,
+            "force_bucket_listing" => BackendNameTag::BucketListing,
+            "force_challenge" => BackendNameTag::Challenge,
+            "force_csrf" => BackendNameTag::Csrf,
+            "force_embargo" => BackendNameTag::Embargo,
+            "force_form_spam" => BackendNameTag::FormSpam,
+            "force_header_rule" => BackendNameTag::HeaderRule,
+            "force_method_not_allowed" => BackendNameTag::MethodNotAllowed,
+            "force_not_acceptable" => BackendNameTag::NotAcceptable,
+            "force_not_found" => BackendNameTag::NotFound,
+            "force_options" => BackendNameTag::Options,
+            "force_origin_redirect" => BackendNameTag::OriginRedirect,
+            "force_purge" => BackendNameTag::Purge,
+            "force_report" => BackendNameTag::Report,
+            "force_search_rate_limit" => BackendNameTag::SearchRateLimit,
+            "force_shield" => BackendNameTag::Shield,
+            "force_ssl" => BackendNameTag::Ssl,
+            _ => BackendNameTag::Backend(name.to_string()),
+        }
+    }
+
+    /// Get the tag of a response, if it has one.
+    pub fn from_response(resp: &Response) -> Option<Self> {
+        resp.get_header_str(BACKEND_NAME_HEADER_NAME)
+            .map(BackendNameTag::parse)
+    }
+
+    /// The `Fastly-Backend-Name` value.
+    pub fn as_str(&self) -> &str {
+        match self {
+            BackendNameTag::Backend(name) => name,
+            BackendNameTag::Error => "error",
+            BackendNameTag::ABTestReport => "force_abtest_report",
+            BackendNameTag::BadPath => "force_bad_path",
+            BackendNameTag::BadPagination => "force_bad_pagination",
+            BackendNameTag::ForcedBackend => "force_backend",
+            BackendNameTag::BucketListing => "force_bucket_listing",
+            BackendNameTag::Challenge => "force_challenge",
+            BackendNameTag::Csrf => "force_csrf",
+            BackendNameTag::Embargo => "force_embargo",
+            BackendNameTag::FormSpam => "force_form_spam",
+            BackendNameTag::HeaderRule => "force_header_rule",
+            BackendNameTag::MethodNotAllowed => "force_method_not_allowed",
+            BackendNameTag::NotAcceptable => "force_not_acceptable",
+            BackendNameTag::NotFound => "force_not_found",
+            BackendNameTag::Options => "force_options",
+            BackendNameTag::OriginRedirect => "force_origin_redirect",
+            BackendNameTag::Purge => "force_purge",
+            BackendNameTag::Report => "force_report",
+            BackendNameTag::SearchRateLimit => "force_search_rate_limit",
+            BackendNameTag::Shield => "force_shield",
+            BackendNameTag::Ssl => "force_ssl",
+        }
+    }
+
+    /// The broad kind of tag: `backend`, `mirror` (one of
+    /// `backends.mirrors`), `synthetic`, or `error`.
+    pub fn kind(&self, settings: &Config) -> &'static str {
+        match self {
+            BackendNameTag::Backend(name) if settings.backends_mirrors.contains(name) => "mirror",
+            BackendNameTag::Backend(_) => "backend",
+            BackendNameTag::Error => "error",
+            _ => "synthetic",
+        }
+    }
+
+    /// Set the `Fastly-Backend-Name` header.
+    pub fn set_header(&self, resp: &mut Response) {
+        resp.set_header(BACKEND_NAME_HEADER_NAME, self.as_str());
+    }
+}
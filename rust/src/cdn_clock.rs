@@ -0,0 +1,76 @@
+use httpdate::fmt_http_date;
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current (wall clock) time.
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+
+    /// The current time, in seconds since the Unix epoch.
+    fn unix_time(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which is stopped at a fixed time, for tests and for
+/// replaying requests.
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+thread_local! {
+    /// The clock used by all the time-dependent rules (embargoes,
+    /// token expiry, and `Date` headers).
+    static CLOCK: RefCell<Box<dyn Clock>> = RefCell::new(Box::new(SystemClock));
+}
+
+/// Replace the clock.
+pub fn set_clock(clock: Box<dyn Clock>) {
+    CLOCK.with(|current| *current.borrow_mut() = clock);
+}
+
+/// The current time.
+pub fn now() -> SystemTime {
+    CLOCK.with(|clock| clock.borrow().now())
+}
+
+/// The current time, in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    CLOCK.with(|clock| clock.borrow().unix_time())
+}
+
+/// The current time, formatted for a `Date` header.
+pub fn http_date() -> String {
+    fmt_http_date(now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn date_header_comes_from_the_clock() {
+        set_clock(Box::new(FixedClock(
+            UNIX_EPOCH + Duration::from_secs(784_111_777),
+        )));
+        assert_eq!(unix_time(), 784_111_777);
+        assert_eq!(http_date(), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
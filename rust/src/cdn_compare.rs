@@ -0,0 +1,137 @@
+use crate::cdn_config::Config;
+use crate::cdn_http;
+use crate::cdn_logging;
+use crate::cdn_rand;
+
+use fastly::http::request::PendingRequest;
+use fastly::http::Method;
+use fastly::{Request, Response};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// A sampled request which has also been sent to the legacy VCL
+/// service, so its response can be compared with ours.
+pub struct Comparison {
+    path: String,
+    pending: PendingRequest,
+}
+
+/// Send `compare.percentage` percent of GET and HEAD requests to the
+/// legacy VCL service as well, in the background.
+///
+/// Requests with a body aren't sampled, as they can't be replayed
+/// without buffering the body.
+pub fn sample(settings: &Config, req: &Request) -> Option<Comparison> {
+    if req.get_method() != Method::GET && req.get_method() != Method::HEAD {
+        return None;
+    }
+
+    if !cdn_rand::percent_chance(settings.compare_percentage) {
+        return None;
+    }
+
+    req.clone_without_body()
+        .send_async(&settings.legacy_backend)
+        .ok()
+        .map(|pending| Comparison {
+            path: req.get_path().to_string(),
+            pending,
+        })
+}
+
+/// Wait for the legacy response, and log a report of any differences
+/// in status and (normalised) headers, and in body hash if
+/// `compare.body_hashes` is set.
+///
+/// Our response is returned unchanged.
+pub fn compare(settings: &Config, comparison: Comparison, resp: &mut Response) {
+    let mut legacy_resp = match comparison.pending.wait() {
+        Ok(legacy_resp) => legacy_resp,
+        Err(_) => {
+            let mut fields = Map::new();
+            fields.insert("path".to_string(), Value::from(comparison.path));
+            cdn_logging::log_event(settings, "compare_error", fields);
+            return;
+        }
+    };
+
+    let mut fields = Map::new();
+
+    if resp.get_status() != legacy_resp.get_status() {
+        fields.insert(
+            "status".to_string(),
+            Value::from(vec![
+                resp.get_status().as_u16(),
+                legacy_resp.get_status().as_u16(),
+            ]),
+        );
+    }
+
+    let headers = normalise_headers(settings, resp);
+    let legacy_headers = normalise_headers(settings, &legacy_resp);
+    let mut header_diffs = Map::new();
+    for name in headers.keys().chain(legacy_headers.keys()) {
+        let value = headers.get(name);
+        let legacy_value = legacy_headers.get(name);
+        if value != legacy_value {
+            header_diffs.insert(
+                name.to_string(),
+                Value::from(vec![
+                    value.map_or(Value::Null, |v| Value::from(v.as_str())),
+                    legacy_value.map_or(Value::Null, |v| Value::from(v.as_str())),
+                ]),
+            );
+        }
+    }
+    if !header_diffs.is_empty() {
+        fields.insert("headers".to_string(), Value::Object(header_diffs));
+    }
+
+    if settings.compare_body_hashes {
+        let body = resp.take_body_bytes();
+        let hash = format!("{:x}", Sha256::digest(&body));
+        let legacy_hash = format!("{:x}", Sha256::digest(&legacy_resp.take_body_bytes()));
+        resp.set_body(body);
+        if hash != legacy_hash {
+            fields.insert(
+                "body_sha256".to_string(),
+                Value::from(vec![hash, legacy_hash]),
+            );
+        }
+    }
+
+    if !fields.is_empty() {
+        fields.insert("path".to_string(), Value::from(comparison.path));
+        cdn_logging::log_event(settings, "compare_mismatch", fields);
+    }
+}
+
+/// Get the response headers, with lowercased names and multiple values
+/// joined, leaving out the ones which are expected to differ (eg,
+/// `Date`).  `Cache-Control` is normalised the way ours is before
+/// delivery, so equivalent values compare equal.
+fn normalise_headers(settings: &Config, resp: &Response) -> BTreeMap<String, String> {
+    resp.get_header_names()
+        .map(|name| name.as_str().to_lowercase())
+        .filter(|name| {
+            !settings
+                .compare_ignore_headers
+                .iter()
+                .any(|ignored| ignored.to_lowercase() == *name)
+        })
+        .map(|name| {
+            let values = resp
+                .get_header_all(name.as_str())
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).trim().to_string())
+                .collect::<Vec<_>>();
+            let value = if name == "cache-control" {
+                let values = values.iter().map(String::as_str).collect::<Vec<_>>();
+                cdn_http::normalise_cache_control(&values).unwrap_or_default()
+            } else {
+                values.join(", ")
+            };
+            (name, value)
+        })
+        .collect()
+}
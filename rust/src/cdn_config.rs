@@ -1,35 +1,629 @@
+use crate::cdn_headers;
+
+use chrono::{DateTime, NaiveTime, Weekday};
 use config::{FileFormat, Value};
-use ipnet::{AddrParseError, Ipv4Net};
+use fastly::http::{HeaderName, HeaderValue, Url};
+use ipnet::{AddrParseError, IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use yaml_rust::{Yaml, YamlLoader};
+
+/// The compiled-in configuration, which `ConfigBuilder::new` starts
+/// from.
+const DEFAULT_CONFIG: &str = include_str!("../config.yaml");
+
+/// Names of the consent providers.
+const CONSENT_PROVIDERS: [&str; 2] = ["cookies_policy", "tcf"];
+
+/// Modes of the query param allowlist.
+const QUERYSTRING_ALLOWLIST_MODES: [&str; 3] = ["off", "learn", "enforce"];
+
+/// Most redirects a chain in `special_paths.redirect` can be flattened
+/// from.
+const MAX_REDIRECT_CHAIN: usize = 5;
+
+/// Hardest challenge which can be set: browsers take around a second
+/// to find a SHA-256 with this many leading zero bits.
+const MAX_CHALLENGE_DIFFICULTY: usize = 24;
+
+/// The generation the next parsed configuration gets.
+static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(0);
 
 /// CDN configuration.
 pub struct Config {
+    /// Different for every configuration parsed by an instance, so
+    /// caches can be keyed on it: one reloaded from the config
+    /// dictionary never gets another's cached results.
+    pub generation: usize,
+    /// Environment name (eg, "production" or "staging")
+    pub environment: String,
     /// IPs which may purge the cache (empty = deny all)
-    pub acl_fastlypurge: IpRange<Ipv4Net>,
+    pub acl_fastlypurge: Acl,
+    /// Fastly nodes which may forward requests to this one as a
+    /// shield (empty = deny all)
+    pub acl_shield: Acl,
+    /// Name of the secret used to sign admin requests (eg, purges)
+    pub admin_secret: String,
+    /// How old a signed admin request can be
+    pub admin_max_age: Duration,
+    /// Backend for the Fastly API, used for surrogate key purges
+    pub purge_api_backend: String,
+    /// Name of the secret holding the Fastly API token
+    pub purge_api_token: String,
+    /// Surrogate keys which IPs not on `acl_fastlypurge` may purge
+    pub purge_key_acls: Vec<PurgeKeyAclConfig>,
+    /// IPs the crawler worker runs on (empty = no crawler worker)
+    pub acl_crawler: Acl,
     /// IPs which may make requests (empty = allow all)
-    pub acl_allowlist: IpRange<Ipv4Net>,
+    pub acl_allowlist: Acl,
     /// IPs which may NOT make requests (empty = allow all)
-    pub acl_denylist: IpRange<Ipv4Net>,
+    pub acl_denylist: Acl,
+    /// IPs which may preview A/B test variants without a token (empty
+    /// = deny all)
+    pub acl_abtest_preview: Acl,
+    /// IPs which may see the A/B test report (empty = deny all)
+    pub acl_abtest_report: Acl,
+    /// IPs which may see internal paths without a token (empty = only
+    /// with a token)
+    pub acl_internal: Acl,
+    /// Methods which get a synthetic 405, rather than going to origin
+    pub rejected_methods: Vec<String>,
+    /// Paths where origin handles OPTIONS requests itself, rather than
+    /// getting a synthetic response
+    pub options_pass_paths: Vec<String>,
+    /// Whether plain HTTP requests with methods other than GET and
+    /// HEAD are redirected to HTTPS (with a 308), rather than rejected
+    pub ssl_redirect_unsafe_methods: bool,
     /// HTTP Basic Auth credentials
     pub basic_authorization: Option<String>,
+    /// Fastly logging endpoint (stderr if unset)
+    pub logging_endpoint: Option<String>,
+    /// Heap high-water mark (in bytes) above which a request's memory
+    /// usage is logged
+    pub memory_log_threshold: usize,
+    /// Fastly logging endpoint for access events (`logging.endpoint`
+    /// if unset)
+    pub access_log_endpoint: Option<String>,
+    /// Percentage of requests to log an access event for
+    pub access_log_percentage: usize,
+    /// Fastly logging endpoint for CSP violation reports (stderr if
+    /// unset)
+    pub csp_reports_endpoint: Option<String>,
+    /// Percentage of CSP violation reports which are logged
+    pub csp_reports_percentage: usize,
+    /// Maximum size of a CSP violation report body: bigger ones are
+    /// dropped
+    pub csp_reports_max_size: usize,
+    /// Fastly logging endpoint for analytics beacons (stderr if unset)
+    pub beacons_endpoint: Option<String>,
+    /// Percentage of analytics beacons which are logged
+    pub beacons_percentage: usize,
+    /// Maximum size of an analytics beacon body: bigger ones are
+    /// dropped
+    pub beacons_max_size: usize,
+    /// Whether to ask browsers for network error reports
+    pub nel_enabled: bool,
+    /// URL browsers send network error reports to
+    pub nel_report_url: String,
+    /// How long (in seconds) browsers keep the network error reporting
+    /// policy
+    pub nel_max_age: usize,
+    /// Fraction of successful requests browsers report
+    pub nel_success_fraction: f64,
+    /// Fraction of failed requests browsers report
+    pub nel_failure_fraction: f64,
+    /// Fastly logging endpoint for network error reports (stderr if
+    /// unset)
+    pub nel_endpoint: Option<String>,
+    /// Maximum size of a network error report body: bigger ones are
+    /// dropped
+    pub nel_max_size: usize,
+    /// Fastly logging endpoint for per-request metrics (stderr if unset)
+    pub metrics_endpoint: Option<String>,
+    /// Upper bounds (in milliseconds) of the origin latency histogram
+    /// buckets
+    pub metrics_latency_buckets: Vec<usize>,
+    /// Upper bounds (in bytes) of the response size histogram buckets
+    pub metrics_size_buckets: Vec<usize>,
+    /// Percentage of requests also sent to the legacy VCL service, to
+    /// compare the responses
+    pub compare_percentage: usize,
+    /// Whether to compare response bodies as well as headers
+    pub compare_body_hashes: bool,
+    /// Response headers which are expected to differ
+    pub compare_ignore_headers: Vec<String>,
+    /// Maximum size of the Cookie header: bigger ones are dropped
+    pub cookies_max_size: usize,
+    /// Requests with a bigger Cookie header than this are passed
+    pub pass_cookie_size: usize,
+    /// Requests with a bigger Authorization header than this are passed
+    pub pass_authorization_size: usize,
+    /// Paths (optionally ending in `*`) where repeated query params
+    /// are all passed to the origin, rather than just the last
+    pub querystring_repeated_paths: Vec<String>,
+    /// What to do with query params not in the allowlist: "off",
+    /// "learn" (log them), or "enforce" (strip them)
+    pub querystring_allowlist_mode: String,
+    /// Query params allowed on paths (optionally ending in `*`)
+    pub querystring_allowlist: HashMap<String, Vec<String>>,
+    /// Percentage of requests with query params not in the allowlist
+    /// which are logged, in "learn" mode
+    pub querystring_learn_percentage: usize,
+    /// Backend for the legacy VCL service
+    pub legacy_backend: String,
+    /// Percentage of requests proxied to the legacy VCL service
+    pub legacy_percentage: usize,
+    /// Cookie pinning a client to the legacy VCL service or this one
+    pub legacy_cookie: String,
+    /// Name of the Edge Dictionary holding secrets
+    pub secrets_dictionary: String,
+    /// Form paths which need a CSRF token to POST to
+    pub csrf_paths: Vec<String>,
+    /// Name of the CSRF token signing secret
+    pub csrf_secret: String,
+    /// Form paths whose POSTs are checked for spam
+    pub form_spam_paths: Vec<String>,
+    /// Maximum size of a form submission: bigger ones are spam
+    pub form_spam_max_size: usize,
+    /// Maximum number of fields in a form submission: more are spam
+    pub form_spam_max_fields: usize,
+    /// Hidden form field which only bots fill in
+    pub form_spam_honeypot_field: String,
+    /// Name of the secret used to sign cache bypass tokens
+    pub cachebust_secret: String,
+    /// Name of the secret used to sign A/B test preview tokens
+    pub abtest_preview_secret: String,
+    /// Leading zero bits a challenge's SHA-256 must have (0 = no
+    /// challenges)
+    pub challenge_difficulty: usize,
+    /// How long a solved challenge lasts for
+    pub challenge_ttl: Duration,
+    /// Name of the secret used to sign challenges
+    pub challenge_secret: String,
+    /// Path prefixes only internal clients may see
+    pub internal_paths_prefixes: Vec<String>,
+    /// Name of the secret holding the internal bearer token
+    pub internal_paths_secret: String,
+    /// Path of the search page, which is rate limited
+    pub search_rate_limit_path: String,
+    /// Most tokens a client can have saved up for searches (0 = no
+    /// limit)
+    pub search_rate_limit_capacity: usize,
+    /// Tokens each client gets back per second (at least 1, if there
+    /// is a limit)
+    pub search_rate_limit_refill_per_second: usize,
+    /// Paths (exact, or prefixes ending in `*`) whose pagination
+    /// params are capped
+    pub search_pagination_paths: Vec<String>,
+    /// Pagination params, and the largest value of each passed to
+    /// origin
+    pub search_pagination_max: HashMap<String, usize>,
+    /// Pagination values above this get a 400
+    pub search_pagination_reject_above: usize,
+    /// Each this many characters of search query string cost another
+    /// token (0 = the length is free)
+    pub search_rate_limit_query_length_cost: usize,
     /// Paths to return a 404 for
     pub synthetic_not_found: Vec<String>,
-    /// Paths to return a 302 for (and their destination)
+    /// Paths to return a 302 for (and their destination), with chains
+    /// of redirects flattened into one
     pub synthetic_redirect: HashMap<String, String>,
+    /// Add `src=edge-redirect` to synthetic redirect destinations
+    pub redirect_marker: bool,
+    /// Whether origins can create redirects with `X-Edge-Redirect`
+    pub origin_redirects_enabled: bool,
+    /// How long (in seconds) origin redirects are cached for
+    pub origin_redirects_max_age: usize,
+    /// Paths to redirect to a language-specific version
+    pub locale_redirects: Vec<LocaleRedirectConfig>,
+    /// Path prefixes where an origin 404 gets the synthetic 404 page
+    pub replace_not_found_prefixes: Vec<String>,
+    /// Path prefixes which are fully personalised, and never cached
+    pub personalised_prefixes: Vec<String>,
+    /// Paths to return a 404 for until a publication time
+    pub synthetic_embargo: HashMap<String, SystemTime>,
+    /// Time budget for fetching a response, shared across all the
+    /// origins and mirrors
+    pub request_deadline: Duration,
+    /// If less than this is left of the request deadline when the
+    /// response arrives, the optional transforms are skipped
+    pub degrade_threshold: Duration,
+    /// Paths (exact, or prefixes ending in `*`) which are always
+    /// classed as API requests
+    pub request_priority_api_paths: Vec<String>,
+    /// Path (and querystring) prefixes which search engines must not
+    /// index
+    pub robots_noindex_paths: Vec<String>,
+    /// Whether to serve this host as static assets (no cookies or
+    /// A/B tests, long-lived caching, and CORS)
+    pub assets_profile: bool,
+    /// Cache lifetime (in seconds) of fingerprinted assets
+    pub assets_max_age: usize,
+    /// Value of the `Access-Control-Allow-Origin` header for assets
+    pub assets_cors_origin: String,
+    /// Whether to serve this host as the draft stack (authenticated,
+    /// uncached, and not indexed)
+    pub draft_profile: bool,
+    /// Cookie holding the signon session on the draft stack
+    pub draft_signon_cookie: String,
+    /// Content types for responses without one (or with a generic one),
+    /// by path suffix
+    pub mime_overrides: HashMap<String, String>,
+    /// Origin backends, in failover order
+    pub origins: Vec<OriginConfig>,
+    /// Shared compression dictionaries
+    pub compression_dictionaries: Vec<CompressionDictionaryConfig>,
+    /// Header rules, in the order they are applied
+    pub header_rules: Vec<HeaderRuleConfig>,
+    /// Shield POPs, by origin backend name
+    pub shielding: HashMap<String, ShieldConfig>,
+    /// Name of the secret the edge sends to shields, without which a
+    /// request with `Fastly-FF` is rejected
+    pub shield_secret: String,
     /// Mirror configuration
     pub mirrors: HashMap<String, MirrorConfig>,
+    /// Mirror backends, in the order they are tried when the origins
+    /// fail
+    pub backends_mirrors: Vec<String>,
+    /// Size (in bytes) above which a mirror response cut short is
+    /// completed with range requests to the next mirrors
+    pub mirror_resume_min_bytes: usize,
+    /// Size (in bytes) above which a mirror response isn't buffered
+    /// to be resumed, as it would use too much memory
+    pub mirror_resume_max_bytes: usize,
+    /// Paths whose mirrored copies must match a SHA-256 checksum (hex)
+    pub mirror_checksums: HashMap<String, String>,
+    /// Mirrored copies last modified longer ago than this are marked
+    /// as stale
+    pub mirror_max_age: Duration,
+    /// How long caches may keep a mirrored response
+    pub mirror_cache_max_age: Duration,
+    /// CSS to inline into the `<head>` of HTML pages (unset = off)
+    pub critical_css: Option<String>,
+    /// URL prefix of the stylesheet to load asynchronously when
+    /// `critical_css` is inlined
+    pub critical_css_stylesheet: String,
+    /// Replacements made in the body of HTML pages, in the order they
+    /// are applied
+    pub body_rules: Vec<BodyRuleConfig>,
     /// A/B test configuration
     pub ab_tests: HashMap<String, ABTestConfig>,
+    /// Backend for the experiments service, which has more A/B tests
+    /// (unset = only use `ab_tests`)
+    pub experiments_backend: Option<String>,
+    /// URL of the experiments manifest
+    pub experiments_url: String,
+    /// How long to cache the experiments manifest for
+    pub experiments_ttl: Duration,
+    /// How long to wait for the experiments manifest
+    pub experiments_timeout: Duration,
+    /// Percentage of origin requests for A/B test variants to log, for
+    /// monitoring to disable failing variants
+    pub ab_test_safety_log_percentage: usize,
+    /// Where consent to cookies comes from ("cookies_policy" or "tcf")
+    pub consent_provider: String,
+    /// TCF purpose which grants each consent category, for the "tcf"
+    /// provider
+    pub consent_tcf_purposes: HashMap<String, usize>,
+}
+
+/// Configuration for each host served.
+pub struct HostConfigs {
+    /// Configuration for hosts which don't have their own
+    pub default: Config,
+    /// Configuration by (lowercase) host name
+    pub hosts: HashMap<String, Arc<Config>>,
+}
+
+impl HostConfigs {
+    /// Get the configuration for a Host header (which may include a
+    /// port).
+    pub fn for_host(&self, host: Option<&str>) -> &Config {
+        let name = host
+            .unwrap_or("")
+            .split(':')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        match self.hosts.get(&name) {
+            Some(config) => config,
+            None => &self.default,
+        }
+    }
+}
+
+impl Config {
+    /// Whether plain HTTP requests with methods other than GET and
+    /// HEAD are redirected to HTTPS, rather than rejected.
+    pub fn ssl_redirect_unsafe_methods(&self) -> bool {
+        self.ssl_redirect_unsafe_methods
+    }
+
+    /// HTTP Basic Auth credentials, if they're required.
+    pub fn basic_authorization(&self) -> Option<&str> {
+        self.basic_authorization.as_deref()
+    }
+
+    /// Whether this host is served as static assets.
+    pub fn assets_profile(&self) -> bool {
+        self.assets_profile
+    }
+
+    /// Whether this host is served as the draft stack.
+    pub fn draft_profile(&self) -> bool {
+        self.draft_profile
+    }
+
+    /// Origin backends, in failover order.
+    pub fn origins(&self) -> &[OriginConfig] {
+        &self.origins
+    }
+
+    /// Look up an A/B test in the configuration (not including any
+    /// from the experiments manifest).
+    pub fn ab_test(&self, name: &str) -> Option<&ABTestConfig> {
+        self.ab_tests.get(name)
+    }
+
+    /// Look up the destination of a synthetic redirect.
+    pub fn synthetic_redirect(&self, path: &str) -> Option<&str> {
+        self.synthetic_redirect.get(path).map(String::as_str)
+    }
+}
+
+/// Builds a `Config` programmatically (eg, in tests or the simulator),
+/// by starting from a YAML configuration and changing settings.
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Start from the compiled-in `config.yaml`, which is always
+    /// valid.
+    pub fn new() -> Self {
+        match ConfigBuilder::from_yaml(DEFAULT_CONFIG) {
+            Ok(builder) => builder,
+            Err(error) => panic!("could not parse config.yaml: {}", error),
+        }
+    }
+
+    /// Start from a YAML configuration string.  Only the top-level
+    /// configuration is used: `hosts` overrides are ignored.
+    pub fn from_yaml(config_str: &str) -> Result<Self, ParseError> {
+        let settings = load_settings(config_str)?;
+        Ok(ConfigBuilder {
+            config: parse_settings(&settings)?,
+        })
+    }
+
+    /// Set whether plain HTTP requests with unsafe methods are
+    /// redirected (rather than rejected).
+    pub fn ssl_redirect_unsafe_methods(mut self, redirect: bool) -> Self {
+        self.config.ssl_redirect_unsafe_methods = redirect;
+        self
+    }
+
+    /// Set (or, with `None`, remove) the Basic Auth credentials.
+    pub fn basic_authorization(mut self, credentials: Option<&str>) -> Self {
+        self.config.basic_authorization = credentials.map(|credentials| credentials.to_string());
+        self
+    }
+
+    /// Set whether the host is served as static assets.
+    pub fn assets_profile(mut self, enabled: bool) -> Self {
+        self.config.assets_profile = enabled;
+        self
+    }
+
+    /// Set whether the host is served as the draft stack.
+    pub fn draft_profile(mut self, enabled: bool) -> Self {
+        self.config.draft_profile = enabled;
+        self
+    }
+
+    /// Replace the origins.
+    pub fn origins(mut self, origins: Vec<OriginConfig>) -> Self {
+        self.config.origins = origins;
+        self
+    }
+
+    /// Add (or replace) an A/B test.
+    pub fn ab_test(mut self, name: &str, ab_test: ABTestConfig) -> Self {
+        self.config.ab_tests.insert(name.to_string(), ab_test);
+        self
+    }
+
+    /// Add (or replace) a synthetic redirect.  Unlike redirects in the
+    /// YAML, chains aren't flattened.
+    pub fn synthetic_redirect(mut self, from: &str, to: &str) -> Self {
+        self.config
+            .synthetic_redirect
+            .insert(from.to_string(), to.to_string());
+        self
+    }
+
+    /// Change any other setting.
+    pub fn with<F: FnOnce(&mut Config)>(mut self, f: F) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Finish building.
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder::new()
+    }
+}
+
+/// An IP access control list, of IPv4 and IPv6 networks.
+#[derive(Clone, Default)]
+pub struct Acl {
+    /// IPv4 networks
+    pub ipv4: IpRange<Ipv4Net>,
+    /// IPv6 networks
+    pub ipv6: IpRange<Ipv6Net>,
+}
+
+impl Acl {
+    /// Check if the ACL has no networks at all.
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// Check if an IP is in one of the networks.  IPv4-mapped IPv6
+    /// addresses (`::ffff:a.b.c.d`) are checked as IPv4.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ipv4) => self.ipv4.contains(ipv4),
+            IpAddr::V6(ipv6) => match ipv6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, _, _] => ipv6
+                    .to_ipv4()
+                    .map_or(false, |ipv4| self.ipv4.contains(&ipv4)),
+                _ => self.ipv6.contains(ipv6),
+            },
+        }
+    }
+}
+
+/// Origin configuration.
+pub struct OriginConfig {
+    /// Backend name
+    pub backend: String,
+    /// Continent or country codes which this origin is nearest to
+    pub regions: Vec<String>,
+}
+
+/// Language-specific redirect configuration.
+pub struct LocaleRedirectConfig {
+    /// Path to redirect from
+    pub path: String,
+    /// Language code (eg, "uk") whose speakers are redirected
+    pub language: String,
+    /// Path to redirect to
+    pub destination: String,
+}
+
+/// Shared compression dictionary configuration.
+pub struct CompressionDictionaryConfig {
+    /// Path of the dictionary itself
+    pub path: String,
+    /// Paths which are compressed with the dictionary (a path,
+    /// optionally ending in `*`)
+    pub match_pattern: String,
+}
+
+/// Header rule configuration.
+#[derive(Default)]
+pub struct HeaderRuleConfig {
+    /// Whether the rule applies to the response, rather than the
+    /// request
+    pub response: bool,
+    /// Path to match (a path, optionally ending in `*`)
+    pub path: Option<String>,
+    /// Method to match
+    pub method: Option<String>,
+    /// Header to match (on the response, for response rules), and
+    /// optionally its value
+    pub header: Option<(String, Option<String>)>,
+    /// Cookie to match, and optionally its value
+    pub cookie: Option<(String, Option<String>)>,
+    /// Times to match, checked against the edge clock (empty = any
+    /// time)
+    pub schedule: Vec<ScheduleWindow>,
+    /// Headers to set
+    pub set_headers: HashMap<String, String>,
+    /// Headers to remove
+    pub remove_headers: Vec<String>,
+    /// Redirect destination (request rules only)
+    pub redirect: Option<String>,
+    /// Whether to skip the cache (request rules only)
+    pub pass: bool,
+    /// Whether to challenge the client, if challenges are on (request
+    /// rules only)
+    pub challenge: bool,
+    /// Status to deny the request with (request rules only)
+    pub deny: Option<u16>,
+    /// Origin backend to try first, if it's one of the origins
+    /// (request rules only)
+    pub backend: Option<String>,
+    /// Whether to show the maintenance banner (response rules only)
+    pub maintenance_banner: bool,
+}
+
+/// A time window in which a header rule applies.
+pub enum ScheduleWindow {
+    /// Every week, on each of `days` (empty = every day), from `from`
+    /// until `until` (UTC).  If `until` is earlier than `from`, the
+    /// window carries on past midnight into the next day.
+    Weekly {
+        days: Vec<Weekday>,
+        from: NaiveTime,
+        until: NaiveTime,
+    },
+    /// Once, from `start` until `end`
+    Once { start: SystemTime, end: SystemTime },
+}
+
+/// Surrogate keys which the IPs on an ACL may purge.
+pub struct PurgeKeyAclConfig {
+    /// Surrogate key (a key, optionally ending in `*`)
+    pub key: String,
+    /// IPs which may purge the keys
+    pub acl: Acl,
+}
+
+/// A replacement made in the body of HTML pages, depending on the
+/// request.
+pub struct BodyRuleConfig {
+    /// String to replace
+    pub find: String,
+    /// Path to match (a path, optionally ending in `*`)
+    pub path: Option<String>,
+    /// Request header to match, and optionally its value
+    pub header: Option<(String, Option<String>)>,
+    /// Cookie to match, and optionally its value
+    pub cookie: Option<(String, Option<String>)>,
+    /// Whether the response must (or must not) come from a mirror
+    pub failover: Option<bool>,
+    /// Replacement if the rule matches
+    pub replace: String,
+    /// Replacement if the rule doesn't match (unset = leave `find`
+    /// alone)
+    pub otherwise: Option<String>,
+}
+
+/// Shield configuration.
+pub struct ShieldConfig {
+    /// Shield POP code (eg, "LCY")
+    pub pop: String,
+    /// Backend which sends requests to the shield POP
+    pub backend: String,
 }
 
 /// Mirror configuration.
 pub struct MirrorConfig {
     /// Path prefix
     pub prefix: Option<String>,
+    /// Suffix of the sidecar checksum file stored alongside each
+    /// object (eg, ".sha256"), if the mirror has them
+    pub checksum_suffix: Option<String>,
+    /// Whether directory URLs (`/foo/`) are stored as `foo/index.html`,
+    /// rather than `foo.html`
+    pub directory_index: bool,
 }
 
 /// A/B test configuration.
+#[derive(Clone)]
 pub struct ABTestConfig {
     /// Is it active?
     pub active: bool,
@@ -41,6 +635,20 @@ pub struct ABTestConfig {
     /// `crawler_variant` field is not set and there is an `A`
     /// variant).
     pub crawler_variant: String,
+    /// Request header the variant is sent to origin in, if not
+    /// `GOVUK-ABTest-<Name>`
+    pub header_name: Option<String>,
+}
+
+impl ABTestConfig {
+    /// Get the request header the variant of the test called `name` is
+    /// sent to origin in.
+    pub fn variant_header_name(&self, name: &str) -> String {
+        match &self.header_name {
+            Some(header_name) => header_name.clone(),
+            None => cdn_headers::ab_test_header_name(name),
+        }
+    }
 }
 
 /// An error when parsing configuration.
@@ -48,47 +656,481 @@ pub enum ParseError {
     InvalidKey(String),
     MissingKey(String),
     InvalidYaml,
+    /// Settings which parse, but contradict each other (every problem
+    /// found, not just the first)
+    Conflicts(Vec<String>),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidKey(key) => write!(f, "invalid key: {}", key),
+            ParseError::MissingKey(key) => write!(f, "missing key: {}", key),
+            ParseError::InvalidYaml => write!(f, "invalid YAML"),
+            ParseError::Conflicts(problems) => {
+                write!(f, "conflicting settings: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+/// Parse a YAML configuration string, and the configuration for each
+/// host under `hosts`.
+///
+/// Each host's configuration is the top-level configuration with the
+/// keys in its `overrides` replaced.
+pub fn parse_host_configs(config_str: &str) -> Result<HostConfigs, ParseError> {
+    let settings = load_settings(config_str)?;
+    let default = parse_settings(&settings)?;
+
+    let mut hosts = HashMap::new();
+    for (index, value) in parse_array(&settings, "hosts")?.iter().enumerate() {
+        let key = format!("hosts.{}", index);
+        let table = value
+            .clone()
+            .into_table()
+            .map_err(|_| ParseError::InvalidKey(key.clone()))?;
+
+        let names = match table.get("host") {
+            Some(value) => {
+                let host_key = format!("{}.host", key);
+                let array = value
+                    .clone()
+                    .into_array()
+                    .map_err(|_| ParseError::InvalidKey(host_key.clone()))?;
+                parse_values_to_strings(array, &host_key)
+            }
+            None => Err(ParseError::MissingKey(format!("{}.host", key))),
+        }?;
+
+        let overrides = match table.get("overrides") {
+            Some(value) => value
+                .clone()
+                .into_table()
+                .map_err(|_| ParseError::InvalidKey(format!("{}.overrides", key))),
+            None => Ok(HashMap::new()),
+        }?;
+
+        let mut host_settings = settings.clone();
+        for (okey, ovalue) in overrides.into_iter() {
+            host_settings
+                .set(&okey, ovalue)
+                .map_err(|_| ParseError::InvalidKey(format!("{}.overrides.{}", key, okey)))?;
+        }
+
+        let host_config = Arc::new(parse_settings(&host_settings)?);
+        for name in names {
+            hosts.insert(name.to_lowercase(), host_config.clone());
+        }
+    }
+
+    Ok(HostConfigs {
+        default: default,
+        hosts: hosts,
+    })
 }
 
-/// Parse a YAML configuration string.
-pub fn parse_config(config_str: &str) -> Result<Config, ParseError> {
+/// Load a YAML configuration string.
+fn load_settings(config_str: &str) -> Result<config::Config, ParseError> {
+    for document in YamlLoader::load_from_str(config_str).map_err(|_| ParseError::InvalidYaml)? {
+        check_keys(&document)?;
+    }
+
     let mut settings = config::Config::new();
     settings
         .merge(config::File::from_str(config_str, FileFormat::Yaml))
         .map_err(|_| ParseError::InvalidYaml)?;
+    Ok(settings)
+}
+
+/// Check that no key in the YAML has a subscript: `config` treats
+/// keys like `foo[1000000]` as paths, and would fill out an array of
+/// that size.
+fn check_keys(yaml: &Yaml) -> Result<(), ParseError> {
+    match yaml {
+        Yaml::Hash(hash) => {
+            for (key, value) in hash {
+                if let Some(key) = key.as_str() {
+                    if key.contains('[') {
+                        return Err(ParseError::InvalidKey(key.to_string()));
+                    }
+                }
+                check_keys(value)?;
+            }
+            Ok(())
+        }
+        Yaml::Array(array) => array.iter().try_for_each(check_keys),
+        _ => Ok(()),
+    }
+}
 
+/// Parse loaded configuration.
+fn parse_settings(settings: &config::Config) -> Result<Config, ParseError> {
+    let environment = parse_string(&settings, "environment")?;
     let acl_fastlypurge = parse_acl(&settings, "acl.fastlypurge")?;
+    let acl_shield = parse_acl(&settings, "acl.shield")?;
+    let acl_crawler = parse_acl(&settings, "acl.crawler")?;
+    let admin_secret = parse_string(&settings, "admin.secret")?;
+    let admin_max_age = Duration::from_secs(parse_size(&settings, "admin.max_age_s")? as u64);
+    let purge_api_backend = parse_string(&settings, "purge.api_backend")?;
+    let purge_api_token = parse_string(&settings, "purge.api_token")?;
+    let purge_key_acls = parse_array_of_purge_key_acls(&settings, "purge.key_acls")?;
     let acl_allowlist = parse_acl(&settings, "acl.allowlist")?;
     let acl_denylist = parse_acl(&settings, "acl.denylist")?;
+    let acl_abtest_preview = parse_acl(&settings, "acl.abtest_preview")?;
+    let acl_abtest_report = parse_acl(&settings, "acl.abtest_report")?;
+    let acl_internal = parse_acl(&settings, "acl.internal")?;
+    let rejected_methods = parse_array_of_strings(&settings, "rejected_methods")?;
+    let options_pass_paths = parse_array_of_strings(&settings, "options.pass_paths")?;
+    let ssl_redirect_unsafe_methods = parse_bool(&settings, "ssl.redirect_unsafe_methods")?;
     let basic_authorization = settings.get_str("basic_authorization").ok();
+    let logging_endpoint = settings.get_str("logging.endpoint").ok();
+    let memory_log_threshold = parse_size(&settings, "logging.memory_threshold_bytes")?;
+    let access_log_endpoint = settings.get_str("access_log.endpoint").ok();
+    let access_log_percentage = parse_percentage(&settings, "access_log.percentage")?;
+    let csp_reports_endpoint = settings.get_str("csp_reports.endpoint").ok();
+    let csp_reports_percentage = parse_percentage(&settings, "csp_reports.percentage")?;
+    let csp_reports_max_size = parse_size(&settings, "csp_reports.max_size")?;
+    let beacons_endpoint = settings.get_str("beacons.endpoint").ok();
+    let beacons_percentage = parse_percentage(&settings, "beacons.percentage")?;
+    let beacons_max_size = parse_size(&settings, "beacons.max_size")?;
+    let nel_enabled = parse_bool(&settings, "nel.enabled")?;
+    let nel_report_url = parse_string(&settings, "nel.report_url")?;
+    let nel_max_age = parse_size(&settings, "nel.max_age")?;
+    let nel_success_fraction = parse_fraction(&settings, "nel.success_fraction")?;
+    let nel_failure_fraction = parse_fraction(&settings, "nel.failure_fraction")?;
+    let nel_endpoint = settings.get_str("nel.endpoint").ok();
+    let nel_max_size = parse_size(&settings, "nel.max_size")?;
+    let metrics_endpoint = settings.get_str("metrics.endpoint").ok();
+    let metrics_latency_buckets = parse_array_of_buckets(&settings, "metrics.latency_buckets_ms")?;
+    let metrics_size_buckets = parse_array_of_buckets(&settings, "metrics.size_buckets_bytes")?;
+    let legacy_backend = parse_string(&settings, "legacy.backend")?;
+    let legacy_percentage = parse_percentage(&settings, "legacy.percentage")?;
+    let legacy_cookie = parse_string(&settings, "legacy.cookie")?;
+    let compare_percentage = parse_percentage(&settings, "compare.percentage")?;
+    let compare_body_hashes = parse_bool(&settings, "compare.body_hashes")?;
+    let compare_ignore_headers = parse_array_of_strings(&settings, "compare.ignore_headers")?;
+    let cookies_max_size = parse_size(&settings, "cookies.max_size")?;
+    let pass_cookie_size = parse_size(&settings, "large_header_pass.cookie")?;
+    let pass_authorization_size = parse_size(&settings, "large_header_pass.authorization")?;
+    let querystring_repeated_paths =
+        parse_array_of_strings(&settings, "querystring.repeated_paths")?;
+    let querystring_allowlist_mode = parse_string(&settings, "querystring.allowlist_mode")?;
+    if !QUERYSTRING_ALLOWLIST_MODES.contains(&querystring_allowlist_mode.as_str()) {
+        return Err(ParseError::InvalidKey(
+            "querystring.allowlist_mode".to_string(),
+        ));
+    }
+    let querystring_allowlist = parse_map_of_arrays_of_strings(&settings, "querystring.allowlist")?;
+    let querystring_learn_percentage = parse_percentage(&settings, "querystring.learn_percentage")?;
+    let secrets_dictionary = parse_string(&settings, "secrets_dictionary")?;
+    let csrf_paths = parse_array_of_strings(&settings, "csrf.paths")?;
+    let csrf_secret = parse_string(&settings, "csrf.secret")?;
+    let form_spam_paths = parse_array_of_strings(&settings, "form_spam.paths")?;
+    let form_spam_max_size = parse_size(&settings, "form_spam.max_size")?;
+    let form_spam_max_fields = parse_size(&settings, "form_spam.max_fields")?;
+    let form_spam_honeypot_field = parse_string(&settings, "form_spam.honeypot_field")?;
+    let cachebust_secret = parse_string(&settings, "cachebust.secret")?;
+    let internal_paths_prefixes = parse_array_of_strings(&settings, "internal_paths.prefixes")?;
+    let internal_paths_secret = parse_string(&settings, "internal_paths.secret")?;
+    let abtest_preview_secret = parse_string(&settings, "abtest_preview.secret")?;
+    let challenge_difficulty = parse_size(&settings, "challenge.difficulty")?;
+    if challenge_difficulty > MAX_CHALLENGE_DIFFICULTY {
+        return Err(ParseError::InvalidKey("challenge.difficulty".to_string()));
+    }
+    let challenge_ttl = Duration::from_secs(parse_size(&settings, "challenge.ttl_s")? as u64);
+    let challenge_secret = parse_string(&settings, "challenge.secret")?;
+    let search_rate_limit_path = parse_string(&settings, "search_rate_limit.path")?;
+    let search_rate_limit_capacity = parse_size(&settings, "search_rate_limit.capacity")?;
+    let search_rate_limit_refill_per_second =
+        parse_size(&settings, "search_rate_limit.refill_per_second")?;
+    let search_rate_limit_query_length_cost =
+        parse_size(&settings, "search_rate_limit.query_length_cost")?;
+    if search_rate_limit_capacity > 0 && search_rate_limit_refill_per_second == 0 {
+        return Err(ParseError::InvalidKey(
+            "search_rate_limit.refill_per_second".to_string(),
+        ));
+    }
+    let search_pagination_paths = parse_array_of_strings(&settings, "search_pagination.paths")?;
+    let search_pagination_max = parse_map_of_sizes(&settings, "search_pagination.max")?;
+    let search_pagination_reject_above = parse_size(&settings, "search_pagination.reject_above")?;
     let synthetic_not_found = parse_array_of_strings(&settings, "special_paths.not_found")?;
-    let synthetic_redirect = parse_map_of_strings(&settings, "special_paths.redirect")?;
+    let synthetic_redirect = parse_redirects(&settings, "special_paths.redirect")?;
+    let redirect_marker = parse_bool(&settings, "special_paths.redirect_marker")?;
+    let origin_redirects_enabled = parse_bool(&settings, "origin_redirects.enabled")?;
+    let origin_redirects_max_age = parse_size(&settings, "origin_redirects.max_age_s")?;
+    let locale_redirects =
+        parse_array_of_locale_redirects(&settings, "special_paths.locale_redirect")?;
+    let replace_not_found_prefixes =
+        parse_array_of_strings(&settings, "special_paths.replace_not_found")?;
+    let personalised_prefixes = parse_array_of_strings(&settings, "special_paths.personalised")?;
+    let synthetic_embargo = parse_array_of_embargoes(&settings, "special_paths.embargo")?;
+    let request_deadline =
+        Duration::from_millis(parse_size(&settings, "request_deadline_ms")? as u64);
+    let degrade_threshold =
+        Duration::from_millis(parse_size(&settings, "degrade_threshold_ms")? as u64);
+    let request_priority_api_paths =
+        parse_array_of_strings(&settings, "request_priority.api_paths")?;
+    let robots_noindex_paths = parse_array_of_strings(&settings, "robots.noindex_paths")?;
+    let assets_profile = parse_bool(&settings, "assets.enabled")?;
+    let assets_max_age = parse_size(&settings, "assets.max_age")?;
+    let assets_cors_origin = parse_string(&settings, "assets.cors_origin")?;
+    let draft_profile = parse_bool(&settings, "draft.enabled")?;
+    let draft_signon_cookie = parse_string(&settings, "draft.signon_cookie")?;
+    let mime_overrides = parse_map_of_strings(&settings, "mime_overrides")?;
+    let origins = parse_array_of_origins(&settings, "origins")?;
+    let compression_dictionaries =
+        parse_array_of_compression_dictionaries(&settings, "compression_dictionaries")?;
+    let header_rules = parse_array_of_header_rules(&settings, "header_rules")?;
+    let shielding = parse_map_of_shields(&settings, "shielding")?;
+    let shield_secret = parse_string(&settings, "shield_secret")?;
     let mirrors = parse_map_of_mirrors(&settings, "mirrors")?;
+    let backends_mirrors = parse_array_of_strings(&settings, "backends.mirrors")?;
+    let mirror_resume_min_bytes = parse_size(&settings, "mirror_resume_min_bytes")?;
+    let mirror_resume_max_bytes = parse_size(&settings, "mirror_resume_max_bytes")?;
+    let mirror_checksums = parse_map_of_strings(&settings, "mirror_checksums")?;
+    let mirror_max_age = Duration::from_secs(parse_size(&settings, "mirror_max_age_s")? as u64);
+    let mirror_cache_max_age =
+        Duration::from_secs(parse_size(&settings, "mirror_cache_max_age_s")? as u64);
+    let critical_css = settings.get_str("critical_css.css").ok();
+    let critical_css_stylesheet = parse_string(&settings, "critical_css.stylesheet")?;
+    let body_rules = parse_array_of_body_rules(&settings, "body_rules")?;
     let ab_tests = parse_map_of_ab_tests(&settings, "ab_tests")?;
+    let experiments_backend = settings.get_str("experiments.backend").ok();
+    let consent_provider = parse_string(&settings, "consent.provider")?;
+    if !CONSENT_PROVIDERS.contains(&consent_provider.as_str()) {
+        return Err(ParseError::InvalidKey("consent.provider".to_string()));
+    }
+    let consent_tcf_purposes = parse_map_of_tcf_purposes(&settings, "consent.tcf_purposes")?;
+    let experiments_url = parse_string(&settings, "experiments.url")?;
+    let experiments_ttl = Duration::from_secs(parse_size(&settings, "experiments.ttl_s")? as u64);
+    let experiments_timeout =
+        Duration::from_millis(parse_size(&settings, "experiments.timeout_ms")? as u64);
+    let ab_test_safety_log_percentage =
+        parse_percentage(&settings, "ab_test_safety.log_percentage")?;
 
-    Ok(Config {
+    let config = Config {
+        generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+        environment: environment,
         acl_fastlypurge: acl_fastlypurge,
+        acl_shield: acl_shield,
+        acl_crawler: acl_crawler,
+        admin_secret: admin_secret,
+        admin_max_age: admin_max_age,
+        purge_api_backend: purge_api_backend,
+        purge_api_token: purge_api_token,
+        purge_key_acls: purge_key_acls,
         acl_allowlist: acl_allowlist,
         acl_denylist: acl_denylist,
+        acl_abtest_preview: acl_abtest_preview,
+        acl_abtest_report: acl_abtest_report,
+        acl_internal: acl_internal,
+        rejected_methods: rejected_methods,
+        options_pass_paths: options_pass_paths,
+        ssl_redirect_unsafe_methods: ssl_redirect_unsafe_methods,
         basic_authorization: basic_authorization,
+        logging_endpoint: logging_endpoint,
+        memory_log_threshold: memory_log_threshold,
+        access_log_endpoint: access_log_endpoint,
+        access_log_percentage: access_log_percentage,
+        csp_reports_endpoint: csp_reports_endpoint,
+        csp_reports_percentage: csp_reports_percentage,
+        csp_reports_max_size: csp_reports_max_size,
+        beacons_endpoint: beacons_endpoint,
+        beacons_percentage: beacons_percentage,
+        beacons_max_size: beacons_max_size,
+        nel_enabled: nel_enabled,
+        nel_report_url: nel_report_url,
+        nel_max_age: nel_max_age,
+        nel_success_fraction: nel_success_fraction,
+        nel_failure_fraction: nel_failure_fraction,
+        nel_endpoint: nel_endpoint,
+        nel_max_size: nel_max_size,
+        metrics_endpoint: metrics_endpoint,
+        metrics_latency_buckets: metrics_latency_buckets,
+        metrics_size_buckets: metrics_size_buckets,
+        legacy_backend: legacy_backend,
+        legacy_percentage: legacy_percentage,
+        legacy_cookie: legacy_cookie,
+        compare_percentage: compare_percentage,
+        compare_body_hashes: compare_body_hashes,
+        compare_ignore_headers: compare_ignore_headers,
+        cookies_max_size: cookies_max_size,
+        pass_cookie_size: pass_cookie_size,
+        pass_authorization_size: pass_authorization_size,
+        querystring_repeated_paths: querystring_repeated_paths,
+        querystring_allowlist_mode: querystring_allowlist_mode,
+        querystring_allowlist: querystring_allowlist,
+        querystring_learn_percentage: querystring_learn_percentage,
+        secrets_dictionary: secrets_dictionary,
+        csrf_paths: csrf_paths,
+        csrf_secret: csrf_secret,
+        form_spam_paths: form_spam_paths,
+        form_spam_max_size: form_spam_max_size,
+        form_spam_max_fields: form_spam_max_fields,
+        form_spam_honeypot_field: form_spam_honeypot_field,
+        cachebust_secret: cachebust_secret,
+        abtest_preview_secret: abtest_preview_secret,
+        challenge_difficulty: challenge_difficulty,
+        challenge_ttl: challenge_ttl,
+        challenge_secret: challenge_secret,
+        internal_paths_prefixes: internal_paths_prefixes,
+        internal_paths_secret: internal_paths_secret,
+        search_rate_limit_path: search_rate_limit_path,
+        search_rate_limit_capacity: search_rate_limit_capacity,
+        search_rate_limit_refill_per_second: search_rate_limit_refill_per_second,
+        search_rate_limit_query_length_cost: search_rate_limit_query_length_cost,
+        search_pagination_paths: search_pagination_paths,
+        search_pagination_max: search_pagination_max,
+        search_pagination_reject_above: search_pagination_reject_above,
         synthetic_not_found: synthetic_not_found,
         synthetic_redirect: synthetic_redirect,
+        redirect_marker: redirect_marker,
+        origin_redirects_enabled: origin_redirects_enabled,
+        origin_redirects_max_age: origin_redirects_max_age,
+        locale_redirects: locale_redirects,
+        replace_not_found_prefixes: replace_not_found_prefixes,
+        personalised_prefixes: personalised_prefixes,
+        synthetic_embargo: synthetic_embargo,
+        request_deadline: request_deadline,
+        degrade_threshold: degrade_threshold,
+        request_priority_api_paths: request_priority_api_paths,
+        robots_noindex_paths: robots_noindex_paths,
+        assets_profile: assets_profile,
+        assets_max_age: assets_max_age,
+        assets_cors_origin: assets_cors_origin,
+        draft_profile: draft_profile,
+        draft_signon_cookie: draft_signon_cookie,
+        mime_overrides: mime_overrides,
+        origins: origins,
+        compression_dictionaries: compression_dictionaries,
+        header_rules: header_rules,
+        shielding: shielding,
+        shield_secret: shield_secret,
         mirrors: mirrors,
+        backends_mirrors: backends_mirrors,
+        mirror_resume_min_bytes: mirror_resume_min_bytes,
+        mirror_resume_max_bytes: mirror_resume_max_bytes,
+        mirror_checksums: mirror_checksums,
+        mirror_max_age: mirror_max_age,
+        mirror_cache_max_age: mirror_cache_max_age,
+        critical_css: critical_css,
+        critical_css_stylesheet: critical_css_stylesheet,
+        body_rules: body_rules,
         ab_tests: ab_tests,
-    })
+        experiments_backend: experiments_backend,
+        consent_provider: consent_provider,
+        consent_tcf_purposes: consent_tcf_purposes,
+        experiments_url: experiments_url,
+        experiments_ttl: experiments_ttl,
+        experiments_timeout: experiments_timeout,
+        ab_test_safety_log_percentage: ab_test_safety_log_percentage,
+    };
+
+    lint(&config)?;
+    Ok(config)
+}
+
+/// Check settings which parse on their own, but contradict each
+/// other: a path which is both a synthetic 404 and a redirect, a
+/// redirect to something which isn't a path or an absolute URL, an A/B
+/// variant without a positive weight, or a mirror in `backends.mirrors`
+/// which isn't in `mirrors`.  Every problem is returned, so one deploy
+/// can fix them all.
+fn lint(config: &Config) -> Result<(), ParseError> {
+    let mut problems = Vec::new();
+
+    for (from, to) in config.synthetic_redirect.iter() {
+        let key = format!("special_paths.redirect.{}", from);
+        if config.synthetic_not_found.contains(from) {
+            problems.push(format!("{}: also in special_paths.not_found", key));
+        }
+        if !is_redirect_destination(to) {
+            problems.push(format!("{}: bad destination {}", key, to));
+        }
+    }
+
+    for (index, locale_redirect) in config.locale_redirects.iter().enumerate() {
+        let key = format!("special_paths.locale_redirect.{}", index);
+        if config.synthetic_not_found.contains(&locale_redirect.path) {
+            problems.push(format!("{}: also in special_paths.not_found", key));
+        }
+        if !is_redirect_destination(&locale_redirect.destination) {
+            problems.push(format!(
+                "{}: bad destination {}",
+                key, locale_redirect.destination
+            ));
+        }
+    }
+
+    for (name, ab_test) in config.ab_tests.iter() {
+        for (variant, weight) in ab_test.variants.iter() {
+            if *weight <= 0 {
+                problems.push(format!(
+                    "ab_tests.{}.variants.{}: weight must be positive",
+                    name, variant
+                ));
+            }
+        }
+    }
+
+    for (index, mirror) in config.backends_mirrors.iter().enumerate() {
+        if !config.mirrors.contains_key(mirror) {
+            problems.push(format!("backends.mirrors.{}: no mirror {}", index, mirror));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        problems.sort();
+        Err(ParseError::Conflicts(problems))
+    }
+}
+
+/// Check if a redirect destination is a path on this site, or an
+/// absolute `http` or `https` URL.
+fn is_redirect_destination(destination: &str) -> bool {
+    if destination.starts_with('/') {
+        return !destination.starts_with("//");
+    }
+
+    match Url::parse(destination) {
+        Ok(url) => (url.scheme() == "http" || url.scheme() == "https") && url.has_host(),
+        Err(_) => false,
+    }
 }
 
-/// Get an ACL from the settings.
-fn parse_acl(settings: &config::Config, key: &str) -> Result<IpRange<Ipv4Net>, ParseError> {
+/// Get an ACL, of IPv4 and IPv6 CIDRs, from the settings.
+fn parse_acl(settings: &config::Config, key: &str) -> Result<Acl, ParseError> {
     let values = parse_array_of_strings(settings, key)?;
+    parse_strings_to_acl(&values, key)
+}
 
+/// Turn a list of IPv4 and IPv6 CIDRs into an ACL.
+fn parse_strings_to_acl(values: &[String], key: &str) -> Result<Acl, ParseError> {
     let networks = values
         .iter()
         .map(|s| s.parse())
-        .collect::<Result<Vec<Ipv4Net>, AddrParseError>>()
+        .collect::<Result<Vec<IpNet>, AddrParseError>>()
         .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
 
-    Ok(networks.into_iter().collect())
+    Ok(Acl {
+        ipv4: networks
+            .iter()
+            .filter_map(|network| match network {
+                IpNet::V4(network) => Some(*network),
+                IpNet::V6(_) => None,
+            })
+            .collect(),
+        ipv6: networks
+            .iter()
+            .filter_map(|network| match network {
+                IpNet::V6(network) => Some(*network),
+                IpNet::V4(_) => None,
+            })
+            .collect(),
+    })
 }
 
 /// Get an array of `String`s from the settings.
@@ -97,6 +1139,21 @@ fn parse_array_of_strings(settings: &config::Config, key: &str) -> Result<Vec<St
     parse_values_to_strings(array, key)
 }
 
+/// Get an array of histogram bucket bounds from the settings, which
+/// must be in ascending order.
+fn parse_array_of_buckets(settings: &config::Config, key: &str) -> Result<Vec<usize>, ParseError> {
+    let array = parse_array(settings, key)?;
+    let mut buckets = Vec::with_capacity(array.len());
+    for value in array.iter() {
+        let bound = parse_value_to_int(value, key)?;
+        if bound < 0 || buckets.last().map_or(false, |&last| last >= bound as usize) {
+            return Err(ParseError::InvalidKey(key.to_string()));
+        }
+        buckets.push(bound as usize);
+    }
+    Ok(buckets)
+}
+
 /// Get a map of `String`s from the settings.
 fn parse_map_of_strings(
     settings: &config::Config,
@@ -111,6 +1168,194 @@ fn parse_map_of_strings(
     Ok(new_map)
 }
 
+/// Get a map of arrays of strings from the settings.
+fn parse_map_of_arrays_of_strings(
+    settings: &config::Config,
+    key: &str,
+) -> Result<HashMap<String, Vec<String>>, ParseError> {
+    let map = parse_map(settings, key)?;
+    let mut new_map = HashMap::new();
+    for (mkey, value) in map.iter() {
+        let value_key = format!("{}.{}", key, mkey);
+        let array = value
+            .clone()
+            .into_array()
+            .map_err(|_| ParseError::InvalidKey(value_key.clone()))?;
+        new_map.insert(mkey.clone(), parse_values_to_strings(array, &value_key)?);
+    }
+    Ok(new_map)
+}
+
+/// Get a map of non-negative integers from the settings.
+fn parse_map_of_sizes(
+    settings: &config::Config,
+    key: &str,
+) -> Result<HashMap<String, usize>, ParseError> {
+    let map = parse_map(settings, key)?;
+    let mut new_map = HashMap::new();
+    for (mkey, value) in map.iter() {
+        let value_key = format!("{}.{}", key, mkey);
+        let parsed = parse_value_to_int(value, &value_key)?;
+        if parsed < 0 {
+            return Err(ParseError::InvalidKey(value_key));
+        }
+        new_map.insert(mkey.clone(), parsed as usize);
+    }
+    Ok(new_map)
+}
+
+/// Get a map of redirects from the settings, with chains (A to B and B
+/// to C) flattened into single redirects (A to C), so clients only get
+/// one hop.  A redirect which is part of a cycle, or which starts a
+/// chain of more than `MAX_REDIRECT_CHAIN` redirects, is invalid.
+fn parse_redirects(
+    settings: &config::Config,
+    key: &str,
+) -> Result<HashMap<String, String>, ParseError> {
+    let redirects = parse_map_of_strings(settings, key)?;
+    let mut flattened = HashMap::new();
+    for (from, to) in redirects.iter() {
+        let mut chain = vec![from];
+        let mut destination = to;
+        while let Some(next) = redirects.get(destination) {
+            if chain.contains(&destination) || chain.len() >= MAX_REDIRECT_CHAIN {
+                return Err(ParseError::InvalidKey(format!("{}.{}", key, from)));
+            }
+            chain.push(destination);
+            destination = next;
+        }
+        flattened.insert(from.clone(), destination.clone());
+    }
+    Ok(flattened)
+}
+
+/// Get an array of embargoed paths (and their publication time) from
+/// the settings.
+fn parse_array_of_embargoes(
+    settings: &config::Config,
+    key: &str,
+) -> Result<HashMap<String, SystemTime>, ParseError> {
+    let array = parse_array(settings, key)?;
+    let mut new_map = HashMap::new();
+    for (index, value) in array.iter().enumerate() {
+        let (path, publish_at) = parse_value_to_embargo(value, &format!("{}.{}", key, index))?;
+        new_map.insert(path, publish_at);
+    }
+    Ok(new_map)
+}
+
+/// Get an array of `OriginConfig`s from the settings.
+fn parse_array_of_origins(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<OriginConfig>, ParseError> {
+    let array = parse_array(settings, key)?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(index, value)| parse_value_to_origin(value, &format!("{}.{}", key, index)))
+        .collect()
+}
+
+/// Get an array of `LocaleRedirectConfig`s from the settings.
+fn parse_array_of_locale_redirects(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<LocaleRedirectConfig>, ParseError> {
+    let array = parse_array(settings, key)?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(index, value)| parse_value_to_locale_redirect(value, &format!("{}.{}", key, index)))
+        .collect()
+}
+
+/// Get an array of `CompressionDictionaryConfig`s from the settings.
+fn parse_array_of_compression_dictionaries(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<CompressionDictionaryConfig>, ParseError> {
+    let array = parse_array(settings, key)?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            parse_value_to_compression_dictionary(value, &format!("{}.{}", key, index))
+        })
+        .collect()
+}
+
+/// Get an array of `HeaderRuleConfig`s from the settings.
+fn parse_array_of_header_rules(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<HeaderRuleConfig>, ParseError> {
+    let array = parse_array(settings, key)?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(index, value)| parse_value_to_header_rule(value, &format!("{}.{}", key, index)))
+        .collect()
+}
+
+/// Get an array of `PurgeKeyAclConfig`s from the settings.
+fn parse_array_of_purge_key_acls(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<PurgeKeyAclConfig>, ParseError> {
+    let array = parse_array(settings, key)?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(index, value)| parse_value_to_purge_key_acl(value, &format!("{}.{}", key, index)))
+        .collect()
+}
+
+/// Get an array of `BodyRuleConfig`s from the settings.
+fn parse_array_of_body_rules(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<BodyRuleConfig>, ParseError> {
+    let array = parse_array(settings, key)?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(index, value)| parse_value_to_body_rule(value, &format!("{}.{}", key, index)))
+        .collect()
+}
+
+/// Get a map of TCF purpose numbers (1 to 24) from the settings.
+fn parse_map_of_tcf_purposes(
+    settings: &config::Config,
+    key: &str,
+) -> Result<HashMap<String, usize>, ParseError> {
+    let map = parse_map(settings, key)?;
+    let mut new_map = HashMap::new();
+    for (mkey, value) in map.iter() {
+        let purpose_key = format!("{}.{}", key, mkey);
+        let purpose = parse_value_to_int(value, &purpose_key)?;
+        if !(1..=24).contains(&purpose) {
+            return Err(ParseError::InvalidKey(purpose_key));
+        }
+        new_map.insert(mkey.clone(), purpose as usize);
+    }
+    Ok(new_map)
+}
+
+/// Get a map of `ShieldConfig`s from the settings.
+fn parse_map_of_shields(
+    settings: &config::Config,
+    key: &str,
+) -> Result<HashMap<String, ShieldConfig>, ParseError> {
+    let map = parse_map(settings, key)?;
+    let mut new_map = HashMap::new();
+    for (mkey, value) in map.iter() {
+        let parsed = parse_value_to_shield(value, &format!("{}.{}", key, mkey))?;
+        new_map.insert(mkey.clone(), parsed);
+    }
+    Ok(new_map)
+}
+
 /// Get a map of `MirrorConfig`s from the settings.
 fn parse_map_of_mirrors(
     settings: &config::Config,
@@ -147,6 +1392,506 @@ fn parse_values_to_strings(values: Vec<Value>, key: &str) -> Result<Vec<String>,
         .collect()
 }
 
+/// Turn a `Value` into a `PurgeKeyAclConfig`.
+fn parse_value_to_purge_key_acl(value: &Value, key: &str) -> Result<PurgeKeyAclConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let purge_key = match table.get("key") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.key", key)),
+        None => Err(ParseError::MissingKey(format!("{}.key", key))),
+    }?;
+
+    let acl_key = format!("{}.acl", key);
+    let acl = match table.get("acl") {
+        Some(value) => {
+            let values = value
+                .clone()
+                .into_array()
+                .map_err(|_| ParseError::InvalidKey(acl_key.clone()))?;
+            parse_strings_to_acl(&parse_values_to_strings(values, &acl_key)?, &acl_key)
+        }
+        None => Err(ParseError::MissingKey(acl_key)),
+    }?;
+
+    Ok(PurgeKeyAclConfig {
+        key: purge_key,
+        acl: acl,
+    })
+}
+
+/// Turn a `Value` into an `OriginConfig`.
+fn parse_value_to_origin(value: &Value, key: &str) -> Result<OriginConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let backend = match table.get("backend") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.backend", key)),
+        None => Err(ParseError::MissingKey(format!("{}.backend", key))),
+    }?;
+
+    let regions = match table.get("regions") {
+        Some(value) => {
+            let regions_key = format!("{}.regions", key);
+            let array = value
+                .clone()
+                .into_array()
+                .map_err(|_| ParseError::InvalidKey(regions_key.clone()))?;
+            parse_values_to_strings(array, &regions_key)
+        }
+        None => Ok(Vec::new()),
+    }?;
+
+    Ok(OriginConfig {
+        backend: backend,
+        regions: regions,
+    })
+}
+
+/// Turn a `Value` into a `LocaleRedirectConfig`.
+fn parse_value_to_locale_redirect(
+    value: &Value,
+    key: &str,
+) -> Result<LocaleRedirectConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let path = match table.get("path") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.path", key)),
+        None => Err(ParseError::MissingKey(format!("{}.path", key))),
+    }?;
+
+    let language = match table.get("language") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.language", key)),
+        None => Err(ParseError::MissingKey(format!("{}.language", key))),
+    }?;
+
+    let destination = match table.get("destination") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.destination", key)),
+        None => Err(ParseError::MissingKey(format!("{}.destination", key))),
+    }?;
+
+    Ok(LocaleRedirectConfig {
+        path: path,
+        language: language.to_lowercase(),
+        destination: destination,
+    })
+}
+
+/// Turn a `Value` into a `CompressionDictionaryConfig`.
+fn parse_value_to_compression_dictionary(
+    value: &Value,
+    key: &str,
+) -> Result<CompressionDictionaryConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let path = match table.get("path") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.path", key)),
+        None => Err(ParseError::MissingKey(format!("{}.path", key))),
+    }?;
+
+    let match_pattern = match table.get("match") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.match", key)),
+        None => Err(ParseError::MissingKey(format!("{}.match", key))),
+    }?;
+
+    Ok(CompressionDictionaryConfig {
+        path: path,
+        match_pattern: match_pattern,
+    })
+}
+
+/// Turn a `Value` into a `HeaderRuleConfig`.
+fn parse_value_to_header_rule(value: &Value, key: &str) -> Result<HeaderRuleConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let phase_key = format!("{}.phase", key);
+    let response = match table.get("phase") {
+        Some(value) => match parse_value_to_string(&value, &phase_key)?.as_str() {
+            "request" => Ok(false),
+            "response" => Ok(true),
+            _ => Err(ParseError::InvalidKey(phase_key)),
+        },
+        None => Err(ParseError::MissingKey(phase_key)),
+    }?;
+
+    let match_key = format!("{}.match", key);
+    let match_table = match table.get("match") {
+        Some(value) => value
+            .clone()
+            .into_table()
+            .map_err(|_| ParseError::InvalidKey(match_key.clone())),
+        None => Ok(HashMap::new()),
+    }?;
+
+    let mut path = None;
+    if let Some(value) = match_table.get("path") {
+        path = Some(parse_value_to_string(
+            value,
+            &format!("{}.path", match_key),
+        )?);
+    }
+
+    let mut method = None;
+    if let Some(value) = match_table.get("method") {
+        method = Some(parse_value_to_string(
+            value,
+            &format!("{}.method", match_key),
+        )?);
+    }
+
+    let mut header = None;
+    if let Some(value) = match_table.get("header") {
+        let header_key = format!("{}.header", match_key);
+        let (name, expected) = parse_value_to_name_and_value(value, &header_key)?;
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(ParseError::InvalidKey(format!("{}.name", header_key)));
+        }
+        header = Some((name, expected));
+    }
+
+    let mut cookie = None;
+    if let Some(value) = match_table.get("cookie") {
+        cookie = Some(parse_value_to_name_and_value(
+            value,
+            &format!("{}.cookie", match_key),
+        )?);
+    }
+
+    let schedule = match match_table.get("schedule") {
+        Some(value) => {
+            let schedule_key = format!("{}.schedule", match_key);
+            value
+                .clone()
+                .into_array()
+                .map_err(|_| ParseError::InvalidKey(schedule_key.clone()))?
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    parse_value_to_schedule_window(value, &format!("{}.{}", schedule_key, index))
+                })
+                .collect::<Result<Vec<ScheduleWindow>, ParseError>>()
+        }
+        None => Ok(Vec::new()),
+    }?;
+
+    let set_headers_key = format!("{}.set_headers", key);
+    let set_headers = match table.get("set_headers") {
+        Some(value) => {
+            let mut new_map = HashMap::new();
+            let map = value
+                .clone()
+                .into_table()
+                .map_err(|_| ParseError::InvalidKey(set_headers_key.clone()))?;
+            for (name, value) in map.iter() {
+                let header_key = format!("{}.{}", set_headers_key, name);
+                let header_value = parse_value_to_string(value, &header_key)?;
+                if HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || HeaderValue::from_str(&header_value).is_err()
+                {
+                    return Err(ParseError::InvalidKey(header_key));
+                }
+                new_map.insert(name.to_string(), header_value);
+            }
+            Ok(new_map)
+        }
+        None => Ok(HashMap::new()),
+    }?;
+
+    let remove_headers = match table.get("remove_headers") {
+        Some(value) => {
+            let remove_headers_key = format!("{}.remove_headers", key);
+            let array = value
+                .clone()
+                .into_array()
+                .map_err(|_| ParseError::InvalidKey(remove_headers_key.clone()))?;
+            let names = parse_values_to_strings(array, &remove_headers_key)?;
+            if names
+                .iter()
+                .any(|name| HeaderName::from_bytes(name.as_bytes()).is_err())
+            {
+                return Err(ParseError::InvalidKey(remove_headers_key));
+            }
+            Ok(names)
+        }
+        None => Ok(Vec::new()),
+    }?;
+
+    let mut redirect = None;
+    if let Some(value) = table.get("redirect") {
+        let redirect_key = format!("{}.redirect", key);
+        let destination = parse_value_to_string(value, &redirect_key)?;
+        if HeaderValue::from_str(&destination).is_err() {
+            return Err(ParseError::InvalidKey(redirect_key));
+        }
+        redirect = Some(destination);
+    }
+
+    let mut pass = false;
+    if let Some(value) = table.get("pass") {
+        pass = parse_value_to_bool(value, &format!("{}.pass", key))?;
+    }
+
+    let mut challenge = false;
+    if let Some(value) = table.get("challenge") {
+        challenge = parse_value_to_bool(value, &format!("{}.challenge", key))?;
+    }
+
+    let mut deny = None;
+    if let Some(value) = table.get("deny") {
+        let deny_key = format!("{}.deny", key);
+        let status = parse_value_to_int(value, &deny_key)?;
+        if !(400..600).contains(&status) {
+            return Err(ParseError::InvalidKey(deny_key));
+        }
+        deny = Some(status as u16);
+    }
+
+    let mut backend = None;
+    if let Some(value) = table.get("backend") {
+        backend = Some(parse_value_to_string(value, &format!("{}.backend", key))?);
+    }
+
+    let mut maintenance_banner = false;
+    if let Some(value) = table.get("maintenance_banner") {
+        maintenance_banner = parse_value_to_bool(value, &format!("{}.maintenance_banner", key))?;
+    }
+
+    let phase_only_fields = if response {
+        vec![
+            ("redirect", redirect.is_some()),
+            ("pass", pass),
+            ("challenge", challenge),
+            ("deny", deny.is_some()),
+            ("backend", backend.is_some()),
+        ]
+    } else {
+        vec![("maintenance_banner", maintenance_banner)]
+    };
+    for (field, is_set) in phase_only_fields {
+        if is_set {
+            return Err(ParseError::InvalidKey(format!("{}.{}", key, field)));
+        }
+    }
+
+    Ok(HeaderRuleConfig {
+        response: response,
+        path: path,
+        method: method,
+        header: header,
+        cookie: cookie,
+        schedule: schedule,
+        set_headers: set_headers,
+        remove_headers: remove_headers,
+        redirect: redirect,
+        pass: pass,
+        challenge: challenge,
+        deny: deny,
+        backend: backend,
+        maintenance_banner: maintenance_banner,
+    })
+}
+
+/// Turn a `Value` into a `BodyRuleConfig`.
+fn parse_value_to_body_rule(value: &Value, key: &str) -> Result<BodyRuleConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let find_key = format!("{}.find", key);
+    let find = match table.get("find") {
+        Some(value) => parse_value_to_string(&value, &find_key),
+        None => Err(ParseError::MissingKey(find_key.clone())),
+    }?;
+    if find.is_empty() {
+        return Err(ParseError::InvalidKey(find_key));
+    }
+
+    let match_key = format!("{}.match", key);
+    let match_table = match table.get("match") {
+        Some(value) => value
+            .clone()
+            .into_table()
+            .map_err(|_| ParseError::InvalidKey(match_key.clone())),
+        None => Ok(HashMap::new()),
+    }?;
+
+    let mut path = None;
+    if let Some(value) = match_table.get("path") {
+        path = Some(parse_value_to_string(
+            value,
+            &format!("{}.path", match_key),
+        )?);
+    }
+
+    let mut header = None;
+    if let Some(value) = match_table.get("header") {
+        let header_key = format!("{}.header", match_key);
+        let (name, expected) = parse_value_to_name_and_value(value, &header_key)?;
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(ParseError::InvalidKey(format!("{}.name", header_key)));
+        }
+        header = Some((name, expected));
+    }
+
+    let mut cookie = None;
+    if let Some(value) = match_table.get("cookie") {
+        cookie = Some(parse_value_to_name_and_value(
+            value,
+            &format!("{}.cookie", match_key),
+        )?);
+    }
+
+    let mut failover = None;
+    if let Some(value) = match_table.get("failover") {
+        failover = Some(parse_value_to_bool(
+            value,
+            &format!("{}.failover", match_key),
+        )?);
+    }
+
+    let replace = match table.get("replace") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.replace", key)),
+        None => Err(ParseError::MissingKey(format!("{}.replace", key))),
+    }?;
+
+    let mut otherwise = None;
+    if let Some(value) = table.get("otherwise") {
+        otherwise = Some(parse_value_to_string(value, &format!("{}.otherwise", key))?);
+    }
+
+    Ok(BodyRuleConfig {
+        find: find,
+        path: path,
+        header: header,
+        cookie: cookie,
+        failover: failover,
+        replace: replace,
+        otherwise: otherwise,
+    })
+}
+
+/// Turn a `Value` into a `ScheduleWindow`: either `days` (optional),
+/// `from`, and `until`, or `start` and `end`.
+fn parse_value_to_schedule_window(value: &Value, key: &str) -> Result<ScheduleWindow, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    if table.contains_key("start") || table.contains_key("end") {
+        let start = match table.get("start") {
+            Some(value) => parse_value_to_time(&value, &format!("{}.start", key)),
+            None => Err(ParseError::MissingKey(format!("{}.start", key))),
+        }?;
+        let end = match table.get("end") {
+            Some(value) => parse_value_to_time(&value, &format!("{}.end", key)),
+            None => Err(ParseError::MissingKey(format!("{}.end", key))),
+        }?;
+        if end <= start {
+            return Err(ParseError::InvalidKey(format!("{}.end", key)));
+        }
+        return Ok(ScheduleWindow::Once {
+            start: start,
+            end: end,
+        });
+    }
+
+    let days = match table.get("days") {
+        Some(value) => {
+            let days_key = format!("{}.days", key);
+            let array = value
+                .clone()
+                .into_array()
+                .map_err(|_| ParseError::InvalidKey(days_key.clone()))?;
+            parse_values_to_strings(array, &days_key)?
+                .iter()
+                .map(|day| {
+                    day.parse::<Weekday>()
+                        .map_err(|_| ParseError::InvalidKey(days_key.clone()))
+                })
+                .collect::<Result<Vec<Weekday>, ParseError>>()
+        }
+        None => Ok(Vec::new()),
+    }?;
+    let from = match table.get("from") {
+        Some(value) => parse_value_to_time_of_day(&value, &format!("{}.from", key)),
+        None => Err(ParseError::MissingKey(format!("{}.from", key))),
+    }?;
+    let until = match table.get("until") {
+        Some(value) => parse_value_to_time_of_day(&value, &format!("{}.until", key)),
+        None => Err(ParseError::MissingKey(format!("{}.until", key))),
+    }?;
+    if from == until {
+        return Err(ParseError::InvalidKey(format!("{}.until", key)));
+    }
+
+    Ok(ScheduleWindow::Weekly {
+        days: days,
+        from: from,
+        until: until,
+    })
+}
+
+/// Turn a `Value` with a `name` and optional `value` into a pair.
+fn parse_value_to_name_and_value(
+    value: &Value,
+    key: &str,
+) -> Result<(String, Option<String>), ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let name = match table.get("name") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.name", key)),
+        None => Err(ParseError::MissingKey(format!("{}.name", key))),
+    }?;
+
+    let mut expected = None;
+    if let Some(value) = table.get("value") {
+        expected = Some(parse_value_to_string(value, &format!("{}.value", key))?);
+    }
+
+    Ok((name, expected))
+}
+
+/// Turn a `Value` into a `ShieldConfig`.
+fn parse_value_to_shield(value: &Value, key: &str) -> Result<ShieldConfig, ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let pop = match table.get("pop") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.pop", key)),
+        None => Err(ParseError::MissingKey(format!("{}.pop", key))),
+    }?;
+
+    let backend = match table.get("backend") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.backend", key)),
+        None => Err(ParseError::MissingKey(format!("{}.backend", key))),
+    }?;
+
+    Ok(ShieldConfig {
+        pop: pop,
+        backend: backend,
+    })
+}
+
 /// Turn a `Value` into a `MirrorConfig`.
 fn parse_value_to_mirror(value: &Value, key: &str) -> Result<MirrorConfig, ParseError> {
     let table = value
@@ -160,7 +1905,42 @@ fn parse_value_to_mirror(value: &Value, key: &str) -> Result<MirrorConfig, Parse
         prefix = Some(prefix_string);
     }
 
-    Ok(MirrorConfig { prefix: prefix })
+    let mut checksum_suffix = None;
+    if let Some(value) = table.get("checksum_suffix") {
+        let suffix_string = parse_value_to_string(value, &format!("{}.checksum_suffix", key))?;
+        checksum_suffix = Some(suffix_string);
+    }
+
+    let mut directory_index = false;
+    if let Some(value) = table.get("directory_index") {
+        directory_index = parse_value_to_bool(value, &format!("{}.directory_index", key))?;
+    }
+
+    Ok(MirrorConfig {
+        prefix: prefix,
+        checksum_suffix: checksum_suffix,
+        directory_index: directory_index,
+    })
+}
+
+/// Turn a `Value` into an embargoed path and its publication time.
+fn parse_value_to_embargo(value: &Value, key: &str) -> Result<(String, SystemTime), ParseError> {
+    let table = value
+        .clone()
+        .into_table()
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))?;
+
+    let path = match table.get("path") {
+        Some(value) => parse_value_to_string(&value, &format!("{}.path", key)),
+        None => Err(ParseError::MissingKey(format!("{}.path", key))),
+    }?;
+
+    let publish_at = match table.get("publish_at") {
+        Some(value) => parse_value_to_time(&value, &format!("{}.publish_at", key)),
+        None => Err(ParseError::MissingKey(format!("{}.publish_at", key))),
+    }?;
+
+    Ok((path, publish_at))
 }
 
 /// Turn a `Value` into an `ABTestConfig`.
@@ -202,11 +1982,24 @@ fn parse_value_to_ab_test(value: &Value, key: &str) -> Result<ABTestConfig, Pars
         _ => Err(ParseError::MissingKey(format!("{}.crawler_variant", key))),
     }?;
 
+    let header_name = match table.get("header_name") {
+        Some(value) => {
+            let header_key = format!("{}.header_name", key);
+            let name = parse_value_to_string(&value, &header_key)?;
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(ParseError::InvalidKey(header_key));
+            }
+            Some(name)
+        }
+        None => None,
+    };
+
     Ok(ABTestConfig {
         active: active,
         expires: expires,
         variants: variants,
         crawler_variant: crawler_variant,
+        header_name: header_name,
     })
 }
 
@@ -234,6 +2027,71 @@ fn parse_value_to_int(value: &Value, key: &str) -> Result<i64, ParseError> {
         .map_err(|_| ParseError::InvalidKey(key.to_string()))
 }
 
+/// Turn a `Value` containing an RFC 3339 timestamp into a `SystemTime`.
+fn parse_value_to_time(value: &Value, key: &str) -> Result<SystemTime, ParseError> {
+    let time_string = parse_value_to_string(value, key)?;
+    DateTime::parse_from_rfc3339(&time_string)
+        .map(SystemTime::from)
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))
+}
+
+/// Get a time of day (`HH:MM`) from a `Value`.
+fn parse_value_to_time_of_day(value: &Value, key: &str) -> Result<NaiveTime, ParseError> {
+    let time_string = parse_value_to_string(value, key)?;
+    NaiveTime::parse_from_str(&time_string, "%H:%M")
+        .map_err(|_| ParseError::InvalidKey(key.to_string()))
+}
+
+/// Get a `String` from the settings.
+fn parse_string(settings: &config::Config, key: &str) -> Result<String, ParseError> {
+    settings
+        .get_str(key)
+        .map_err(|_| ParseError::MissingKey(key.to_string()))
+}
+
+/// Get an integer between 0 and 100 from the settings.
+fn parse_percentage(settings: &config::Config, key: &str) -> Result<usize, ParseError> {
+    let value = parse_size(settings, key)?;
+    if value > 100 {
+        Err(ParseError::InvalidKey(key.to_string()))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Get a number between 0 and 1 from the settings.
+fn parse_fraction(settings: &config::Config, key: &str) -> Result<f64, ParseError> {
+    let value = settings
+        .get_float(key)
+        .map_err(|_| ParseError::MissingKey(key.to_string()))?;
+
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(ParseError::InvalidKey(key.to_string()))
+    }
+}
+
+/// Get a `bool` from the settings.
+fn parse_bool(settings: &config::Config, key: &str) -> Result<bool, ParseError> {
+    settings
+        .get_bool(key)
+        .map_err(|_| ParseError::MissingKey(key.to_string()))
+}
+
+/// Get a non-negative integer from the settings.
+fn parse_size(settings: &config::Config, key: &str) -> Result<usize, ParseError> {
+    let value = settings
+        .get_int(key)
+        .map_err(|_| ParseError::MissingKey(key.to_string()))?;
+
+    if value < 0 {
+        Err(ParseError::InvalidKey(key.to_string()))
+    } else {
+        Ok(value as usize)
+    }
+}
+
 /// Get an array from the settings.
 fn parse_array(settings: &config::Config, key: &str) -> Result<Vec<Value>, ParseError> {
     settings
@@ -247,3 +2105,169 @@ fn parse_map(settings: &config::Config, key: &str) -> Result<HashMap<String, Val
         .get_table(key)
         .map_err(|_| ParseError::MissingKey(key.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Path of a file in `tests/fixtures`.
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name)
+    }
+
+    /// Parse a configuration file, relative to the crate root.
+    fn parse_file(path: PathBuf) -> HostConfigs {
+        let config_str = fs::read_to_string(&path)
+            .unwrap_or_else(|error| panic!("could not read {}: {}", path.display(), error));
+        match parse_host_configs(&config_str) {
+            Ok(host_configs) => host_configs,
+            Err(error) => panic!("could not parse {}: {}", path.display(), error),
+        }
+    }
+
+    #[test]
+    fn acl_matches_ipv4_and_ipv6_clients() {
+        let mut settings = config::Config::default();
+        settings
+            .merge(config::File::from_str(
+                r#"acl: ["203.0.113.0/24", "2001:db8::/32"]"#,
+                FileFormat::Yaml,
+            ))
+            .unwrap();
+        let acl = match parse_acl(&settings, "acl") {
+            Ok(acl) => acl,
+            Err(error) => panic!("could not parse acl: {}", error),
+        };
+
+        assert!(acl.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(acl.contains(&"::ffff:203.0.113.7".parse().unwrap()));
+        assert!(acl.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!acl.contains(&"198.51.100.7".parse().unwrap()));
+        assert!(!acl.contains(&"2001:db9::1".parse().unwrap()));
+    }
+
+    /// Describe the parsed hosts, redirects, and A/B tests, one per
+    /// line, in a stable order.
+    fn summarise(host_configs: &HostConfigs) -> String {
+        let mut lines = Vec::new();
+
+        let mut hosts = host_configs.hosts.keys().collect::<Vec<_>>();
+        hosts.sort();
+        for host in hosts {
+            lines.push(format!("host {}", host));
+        }
+
+        let mut redirects = host_configs
+            .default
+            .synthetic_redirect
+            .iter()
+            .collect::<Vec<_>>();
+        redirects.sort();
+        for (from, to) in redirects {
+            lines.push(format!("redirect {} -> {}", from, to));
+        }
+
+        let mut ab_tests = host_configs.default.ab_tests.iter().collect::<Vec<_>>();
+        ab_tests.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, ab_test) in ab_tests {
+            let mut variants = ab_test
+                .variants
+                .iter()
+                .map(|(variant, weight)| format!("{}:{}", variant, weight))
+                .collect::<Vec<_>>();
+            variants.sort();
+            lines.push(format!(
+                "ab_test {} active={} expires={} crawler_variant={} header={} variants={}",
+                name,
+                ab_test.active,
+                ab_test.expires,
+                ab_test.crawler_variant,
+                ab_test.variant_header_name(name),
+                variants.join(",")
+            ));
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Compare a summary against its golden file in `tests/fixtures`.
+    /// Set `UPDATE_GOLDEN` to rewrite the golden file instead, after a
+    /// change which is meant to alter the parsed configuration.
+    fn assert_golden(name: &str, actual: &str) {
+        let path = fixture_path(&format!("{}.golden", name));
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, actual).expect("could not write golden file");
+            return;
+        }
+
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|error| panic!("could not read {}: {}", path.display(), error));
+        assert!(
+            expected == actual,
+            "parsed configuration does not match {} (set UPDATE_GOLDEN=1 to accept the change)",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn lint_reports_every_conflict() {
+        let mut variants = HashMap::new();
+        variants.insert("A".to_string(), 0);
+        let config = ConfigBuilder::new()
+            .synthetic_redirect("/gone", "not a url")
+            .ab_test(
+                "Broken",
+                ABTestConfig {
+                    active: true,
+                    expires: 86400,
+                    variants: variants,
+                    crawler_variant: "A".to_string(),
+                    header_name: None,
+                },
+            )
+            .with(|config| {
+                config.synthetic_not_found.push("/gone".to_string());
+                config.backends_mirrors.push("missing".to_string());
+            })
+            .build();
+
+        match lint(&config) {
+            Err(ParseError::Conflicts(problems)) => assert_eq!(
+                problems,
+                vec![
+                    "ab_tests.Broken.variants.A: weight must be positive",
+                    "backends.mirrors.3: no mirror missing",
+                    "special_paths.redirect./gone: also in special_paths.not_found",
+                    "special_paths.redirect./gone: bad destination not a url",
+                ]
+            ),
+            _ => panic!("expected conflicts"),
+        }
+    }
+
+    #[test]
+    fn default_config_matches_golden() {
+        let host_configs =
+            parse_file(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("config.yaml"));
+        assert_golden("default", &summarise(&host_configs));
+    }
+
+    #[test]
+    fn large_redirects_match_golden() {
+        let host_configs = parse_file(fixture_path("large_redirects.yaml"));
+        assert_golden("large_redirects", &summarise(&host_configs));
+    }
+
+    #[test]
+    fn many_ab_tests_match_golden() {
+        let host_configs = parse_file(fixture_path("many_ab_tests.yaml"));
+        assert_golden("many_ab_tests", &summarise(&host_configs));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::cdn_config::Config;
+use crate::cdn_logging;
+use crate::cdn_rules;
+
+use fastly::{Request, Response};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Response header naming the stage which failed, on the synthetic
+/// error page
+pub const ERROR_HEADER_NAME: &str = "GOVUK-Edge-Error";
+
+/// Why a request couldn't be answered.  Each kind of failure gets its
+/// own status, so they can be told apart.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CdnError {
+    /// The configuration couldn't be parsed, and there was no snapshot
+    /// to fall back to
+    Config,
+    /// The client has no IP address, so the backend request couldn't
+    /// be built
+    NoClientIp,
+    /// Every origin and mirror failed, or the request deadline ran
+    /// out, with the last error from each backend tried
+    AllBackendsFailed {
+        attempts: u32,
+        errors: Vec<(String, String)>,
+    },
+    /// The one backend the request could go to (a forced backend, or
+    /// the legacy service) failed
+    Send { backend: String, error: String },
+}
+
+impl CdnError {
+    /// The stage which failed, as given in the `GOVUK-Edge-Error`
+    /// header and the logs.
+    pub fn stage(&self) -> &'static str {
+        match self {
+            CdnError::Config => "config",
+            CdnError::NoClientIp => "no_client_ip",
+            CdnError::AllBackendsFailed { .. } => "all_backends_failed",
+            CdnError::Send { .. } => "send",
+        }
+    }
+
+    /// The status of the synthetic error page.
+    pub fn status(&self) -> u16 {
+        match self {
+            CdnError::Config => 500,
+            CdnError::NoClientIp => 400,
+            CdnError::AllBackendsFailed { .. } => 503,
+            CdnError::Send { .. } => 502,
+        }
+    }
+
+    /// Generate the synthetic error page for the failure.  The details
+    /// (eg, which backend failed and why) are only logged, not shown
+    /// to the client.
+    pub fn response(&self, req: &Request) -> Response {
+        let mut resp = cdn_rules::synthetic_error_response(req);
+        resp.set_status(self.status());
+        resp.with_header(ERROR_HEADER_NAME, self.stage())
+    }
+
+    /// Log the failure as an `edge_error` event.
+    pub fn log(&self, settings: &Config) {
+        let mut fields = Map::new();
+        fields.insert("stage".to_string(), Value::from(self.stage()));
+        fields.insert("error".to_string(), Value::from(self.to_string()));
+        cdn_logging::log_event(settings, "edge_error", fields);
+    }
+}
+
+impl fmt::Display for CdnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdnError::Config => write!(f, "no usable configuration"),
+            CdnError::NoClientIp => write!(f, "no client IP"),
+            CdnError::AllBackendsFailed { attempts, errors } => {
+                write!(f, "all backends failed after {} attempts", attempts)?;
+                for (index, (backend, error)) in errors.iter().enumerate() {
+                    let separator = if index == 0 { ": " } else { ", " };
+                    write!(f, "{}{} failed: {}", separator, backend, error)?;
+                }
+                Ok(())
+            }
+            CdnError::Send { backend, error } => write!(f, "{} failed: {}", backend, error),
+        }
+    }
+}
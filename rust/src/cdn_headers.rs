@@ -0,0 +1,88 @@
+use fastly::{Request, Response};
+use std::net::IpAddr;
+
+// Headers which are only read or written by one module (eg,
+// `Fastly-Backend-Name` or `GOVUK-Force-Backend`) are defined there.
+// These are the ones shared between modules.
+
+/// Request header holding the request ID, which the random number
+/// generator is seeded from
+pub const REQUEST_ID_HEADER_NAME: &str = "GOVUK-Request-Id";
+
+/// Request header listing the Fastly nodes a request has passed
+/// through, which marks it as coming from the edge
+pub const FASTLY_FF_HEADER_NAME: &str = "Fastly-FF";
+
+/// Request header Fastly sets on requests which came in over TLS
+pub const FASTLY_SSL_HEADER_NAME: &str = "Fastly-SSL";
+
+/// Request header holding the shield secret, which the edge sends to
+/// shields to show that a request with `Fastly-FF` came from this
+/// service, rather than another one on the same Fastly nodes
+pub const SHIELD_AUTH_HEADER_NAME: &str = "GOVUK-Shield-Auth";
+
+/// Backend request header holding the client's IP
+pub const FASTLY_CLIENT_IP_HEADER_NAME: &str = "Fastly-Client-IP";
+
+/// Backend request header holding the client's IP, for origins which
+/// don't read `Fastly-Client-IP`
+pub const TRUE_CLIENT_IP_HEADER_NAME: &str = "True-Client-IP";
+
+/// Response header set if a mirror served the response
+pub const FAILOVER_HEADER_NAME: &str = "Fastly-Failover";
+
+/// Response header counting the backends tried
+pub const BACKEND_ATTEMPTS_HEADER_NAME: &str = "Fastly-Backend-Attempts";
+
+/// Prefix of the request header an A/B test variant is sent to origin
+/// in, unless the test sets `header_name`
+pub const AB_TEST_HEADER_NAME_PREFIX: &str = "GOVUK-ABTest-";
+
+/// Request / response header marking an A/B test preview, as
+/// `<Name>:<Variant>`
+pub const AB_TEST_PREVIEW_HEADER_NAME: &str = "GOVUK-ABTest-Preview";
+
+/// Request / response header for the account session ID
+pub const ACCOUNT_SESSION_HEADER_NAME: &str = "GOVUK-Account-Session";
+
+/// Request header saying whether the user has a session, which
+/// origins can `Vary` on to keep personalised and anonymous responses
+/// apart in the cache
+pub const AUTHENTICATED_HEADER_NAME: &str = "GOVUK-Authenticated";
+
+/// Response header for ending the account session
+pub const ACCOUNT_END_SESSION_HEADER_NAME: &str = "GOVUK-Account-End-Session";
+
+/// Get the default request header for the variant of the A/B test
+/// called `name`.
+pub fn ab_test_header_name(name: &str) -> String {
+    format!("{}{}", AB_TEST_HEADER_NAME_PREFIX, name)
+}
+
+/// Set the headers giving origin the client's IP.
+pub fn set_client_ip(bereq: &mut Request, client_ip: &str) {
+    bereq.set_header(FASTLY_CLIENT_IP_HEADER_NAME, client_ip);
+    bereq.set_header(TRUE_CLIENT_IP_HEADER_NAME, client_ip);
+    bereq.set_header("X-Forwarded-For", client_ip);
+}
+
+/// Get the client's IP from a backend request.
+pub fn client_ip(bereq: &Request) -> Option<IpAddr> {
+    bereq
+        .get_header_str(FASTLY_CLIENT_IP_HEADER_NAME)
+        .and_then(|ip| ip.parse().ok())
+}
+
+/// Set or remove `Fastly-Failover`.
+pub fn set_failover(resp: &mut Response, failover: bool) {
+    if failover {
+        resp.set_header(FAILOVER_HEADER_NAME, "1");
+    } else {
+        resp.remove_header(FAILOVER_HEADER_NAME);
+    }
+}
+
+/// Check if a mirror served a response.
+pub fn is_failover(resp: &Response) -> bool {
+    resp.contains_header(FAILOVER_HEADER_NAME)
+}
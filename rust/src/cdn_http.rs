@@ -0,0 +1,252 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+
+use fastly::http::{header, HeaderName, HeaderValue};
+use fastly::{Request, Response};
+
+/// `Cache-Control` directives which let a response be stored, and so
+/// contradict `no-store`
+const CACHING_DIRECTIVES: [&str; 5] = [
+    "public",
+    "max-age",
+    "s-maxage",
+    "stale-while-revalidate",
+    "stale-if-error",
+];
+
+/// A media range from an Accept header, eg `text/*;q=0.5`.
+pub struct MediaRange {
+    /// The type, or `*`
+    pub type_: String,
+    /// The subtype, or `*`
+    pub subtype: String,
+    /// The quality, from 0 (not acceptable) to 1
+    pub quality: f32,
+}
+
+impl MediaRange {
+    /// Check if the range includes a media type (eg, `text/html`).
+    pub fn matches(&self, media_type: &str) -> bool {
+        let mut parts = media_type.splitn(2, '/');
+        let type_ = parts.next().unwrap_or("");
+        let subtype = parts.next().unwrap_or("");
+        (self.type_ == "*" || self.type_.eq_ignore_ascii_case(type_))
+            && (self.subtype == "*" || self.subtype.eq_ignore_ascii_case(subtype))
+    }
+
+    /// How specific the range is: `*/*` is 0, `text/*` is 1, and
+    /// `text/html` is 2.
+    fn specificity(&self) -> usize {
+        match (self.type_.as_str(), self.subtype.as_str()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Parse a header which is a comma-separated list of values with
+/// optional q-values (eg, Accept-Language: `cy, en-GB;q=0.8`) into the
+/// values (without their parameters) and qualities, in the order
+/// given.  Values with an invalid q-value are skipped.
+pub fn parse_quality_list(header_value: &str) -> Vec<(String, f32)> {
+    header_value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let value = parts.next().unwrap_or("").trim();
+            if value.is_empty() {
+                return None;
+            }
+
+            let mut quality = 1.0;
+            for param in parts {
+                let param = param.trim();
+                if let Some(q) = param
+                    .strip_prefix("q=")
+                    .or_else(|| param.strip_prefix("Q="))
+                {
+                    quality = q.trim().parse::<f32>().ok()?;
+                    if !(0.0..=1.0).contains(&quality) {
+                        return None;
+                    }
+                }
+            }
+            Some((value.to_string(), quality))
+        })
+        .collect()
+}
+
+/// Parse an Accept header into media ranges, in the order given.
+/// Malformed ranges are skipped.
+pub fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    parse_quality_list(accept)
+        .into_iter()
+        .filter_map(|(range, quality)| {
+            let mut parts = range.splitn(2, '/');
+            let type_ = parts.next()?.trim();
+            let subtype = parts.next()?.trim();
+            if type_.is_empty() || subtype.is_empty() || (type_ == "*" && subtype != "*") {
+                return None;
+            }
+            Some(MediaRange {
+                type_: type_.to_string(),
+                subtype: subtype.to_string(),
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// Choose which of the `available` media types (most preferred first)
+/// to serve for an Accept header.  Each type gets the quality of the
+/// most specific range which matches it, and the highest quality wins,
+/// with ties going to the earlier type.  Without an Accept header (or
+/// with one which can't be parsed), the first type is chosen.
+///
+/// Returns `None` if none of them are acceptable.
+pub fn negotiate<'a>(accept: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_accept(accept.unwrap_or(""));
+    if ranges.is_empty() {
+        return available.first().copied();
+    }
+
+    let mut best: Option<(f32, &'a str)> = None;
+    for media_type in available {
+        let quality = ranges
+            .iter()
+            .filter(|range| range.matches(media_type))
+            .max_by_key(|range| range.specificity())
+            .map_or(0.0, |range| range.quality);
+
+        let is_better = match best {
+            Some((best_quality, _)) => quality > best_quality,
+            None => quality > 0.0,
+        };
+        if is_better {
+            best = Some((quality, *media_type));
+        }
+    }
+    best.map(|(_, media_type)| media_type)
+}
+
+/// Choose the media type to serve for a request (see `negotiate`).
+pub fn negotiate_request<'a>(req: &Request, available: &[&'a str]) -> Option<&'a str> {
+    let accept = req
+        .get_header(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    negotiate(accept, available)
+}
+
+/// A synthetic 406, listing the media types which are available.
+pub fn not_acceptable_response(available: &[&str]) -> Response {
+    Response::from_status(406)
+        .with_header(
+            BACKEND_NAME_HEADER_NAME,
+            BackendNameTag::NotAcceptable.as_str(),
+        )
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_header(header::VARY, "Accept")
+        .with_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .with_body(format!(
+            "Not Acceptable: available as {}\n",
+            available.join(", ")
+        ))
+}
+
+/// Normalise the response headers before delivery, so responses from
+/// this service and the VCL service can be compared byte-for-byte:
+/// names in canonical (lowercase) form, sorted, with each name's
+/// values in the order they were set, and `Cache-Control` merged into
+/// one consistent value (see `normalise_cache_control`).
+pub fn normalise_response_headers(mut resp: Response) -> Response {
+    let mut names: Vec<HeaderName> = resp.get_header_names().cloned().collect();
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+
+    let headers: Vec<(HeaderName, Vec<HeaderValue>)> = names
+        .into_iter()
+        .map(|name| {
+            let values = resp.get_header_all(&name).cloned().collect();
+            (name, values)
+        })
+        .collect();
+
+    for (name, _) in headers.iter() {
+        resp.remove_header(name);
+    }
+
+    for (name, values) in headers {
+        if name == "cache-control" {
+            let values = values
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>();
+            if let Some(value) = normalise_cache_control(&values) {
+                resp.set_header(name, value);
+            }
+        } else {
+            for value in values {
+                resp.append_header(&name, value);
+            }
+        }
+    }
+
+    resp
+}
+
+/// Merge `Cache-Control` values into one, with lowercase directive
+/// names, in the order first given, and without contradictions:
+/// repeats of a directive keep the smallest value, `private` beats
+/// `public`, and `no-store` beats everything which would let the
+/// response be stored.
+///
+/// Returns `None` if there are no directives left.
+pub fn normalise_cache_control(values: &[&str]) -> Option<String> {
+    let mut directives: Vec<(String, Option<String>)> = Vec::new();
+    for directive in values.iter().flat_map(|value| value.split(',')) {
+        let mut parts = directive.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        if name.is_empty() {
+            continue;
+        }
+        let argument = parts.next().map(|argument| argument.trim().to_string());
+
+        match directives.iter_mut().find(|(seen, _)| *seen == name) {
+            Some((_, seen_argument)) => {
+                let smaller = match (seen_argument.as_deref(), argument.as_deref()) {
+                    (Some(seen), Some(new)) => match (seen.parse::<u64>(), new.parse::<u64>()) {
+                        (Ok(seen), Ok(new)) => new < seen,
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if smaller {
+                    *seen_argument = argument;
+                }
+            }
+            None => directives.push((name, argument)),
+        }
+    }
+
+    let has = |wanted: &str| directives.iter().any(|(name, _)| name == wanted);
+    let no_store = has("no-store");
+    let private = has("private");
+    directives.retain(|(name, _)| {
+        !(no_store && CACHING_DIRECTIVES.contains(&name.as_str()) || private && name == "public")
+    });
+
+    if directives.is_empty() {
+        return None;
+    }
+
+    Some(
+        directives
+            .iter()
+            .map(|(name, argument)| match argument {
+                Some(argument) => format!("{}={}", name, argument),
+                None => name.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
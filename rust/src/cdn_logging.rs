@@ -0,0 +1,90 @@
+use crate::cdn_config::Config;
+use crate::cdn_memory;
+use crate::cdn_rand;
+
+use fastly::log::Endpoint;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::panic::PanicInfo;
+
+/// Log an event as a line of JSON, with an `event` field giving its
+/// type, and a `request_id` field giving the request it's about.
+///
+/// Events go to the configured Fastly logging endpoint, or to stderr
+/// if there isn't one.
+pub fn log_event(settings: &Config, event: &str, fields: Map<String, Value>) {
+    log_event_to(settings, None, event, fields);
+}
+
+/// Log an event like `log_event`, but to `endpoint` if it's set,
+/// rather than the configured logging endpoint.
+pub fn log_event_to(
+    settings: &Config,
+    endpoint: Option<&str>,
+    event: &str,
+    fields: Map<String, Value>,
+) {
+    let mut object = fields;
+    object.insert("event".to_string(), Value::String(event.to_string()));
+    object.insert(
+        "request_id".to_string(),
+        Value::String(cdn_rand::request_id()),
+    );
+    write_line(
+        endpoint.or_else(|| settings.logging_endpoint.as_deref()),
+        &Value::Object(object).to_string(),
+    );
+}
+
+/// Log an event as a line of JSON to stderr, for when there is no
+/// usable configuration.
+pub fn log_event_to_stderr(event: &str, fields: Map<String, Value>) {
+    let mut object = fields;
+    object.insert("event".to_string(), Value::String(event.to_string()));
+    eprintln!("{}", Value::Object(object));
+}
+
+/// Log a panic, with the memory in use when it happened, to the
+/// logging endpoint if there is a usable configuration and to stderr
+/// if not.
+pub fn log_panic(settings: Option<&Config>, info: &PanicInfo<'_>) {
+    let payload = info.payload();
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => Some(message.to_string()),
+        None => payload.downcast_ref::<String>().cloned(),
+    };
+
+    let mut fields = Map::new();
+    fields.insert(
+        "message".to_string(),
+        message.map_or(Value::Null, Value::from),
+    );
+    fields.insert(
+        "location".to_string(),
+        info.location()
+            .map_or(Value::Null, |location| Value::from(location.to_string())),
+    );
+    fields.insert("peak_bytes".to_string(), Value::from(cdn_memory::peak()));
+    fields.insert(
+        "current_bytes".to_string(),
+        Value::from(cdn_memory::current()),
+    );
+
+    match settings {
+        Some(settings) => log_event(settings, "panic", fields),
+        None => log_event_to_stderr("panic", fields),
+    }
+}
+
+/// Write a line to a Fastly logging endpoint, or to stderr if there
+/// isn't one (or it doesn't exist).
+pub fn write_line(endpoint_name: Option<&str>, line: &str) {
+    let endpoint = endpoint_name.and_then(|name| Endpoint::try_from_name(name).ok());
+
+    match endpoint {
+        Some(mut endpoint) => {
+            let _ = writeln!(endpoint, "{}", line);
+        }
+        None => eprintln!("{}", line),
+    }
+}
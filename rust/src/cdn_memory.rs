@@ -0,0 +1,86 @@
+use crate::cdn_config::Config;
+use crate::cdn_logging;
+
+use serde_json::{Map, Value};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes currently allocated
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Most bytes allocated at once since the last `reset_peak`
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// An allocator which counts the bytes allocated through it, so we
+/// can see how close a request came to running the instance out of
+/// memory.  It only counts if it's installed as the
+/// `#[global_allocator]`, which the Fastly binary does.
+pub struct TrackingAllocator;
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Count an allocation, and raise the high-water mark if need be.
+fn allocated(size: usize) {
+    let current = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(current, Ordering::Relaxed);
+}
+
+/// Bytes currently allocated.
+pub fn current() -> usize {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Most bytes allocated at once since the last `reset_peak`.
+pub fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Start a new high-water mark, at the current usage.
+pub fn reset_peak() {
+    PEAK.store(current(), Ordering::Relaxed);
+}
+
+/// Log the memory used by a request, if its high-water mark is above
+/// the threshold.
+pub fn log_usage(settings: &Config, path: &str) {
+    let peak = peak();
+    if peak <= settings.memory_log_threshold {
+        return;
+    }
+
+    let mut fields = Map::new();
+    fields.insert("path".to_string(), Value::from(path));
+    fields.insert("peak_bytes".to_string(), Value::from(peak));
+    fields.insert("current_bytes".to_string(), Value::from(current()));
+    cdn_logging::log_event(settings, "memory", fields);
+}
@@ -0,0 +1,99 @@
+use crate::cdn_backend_name::BackendNameTag;
+use crate::cdn_config::Config;
+use crate::cdn_logging;
+
+use fastly::http::header;
+use fastly::Response;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Counters for a single request, flushed as one line of JSON once the
+/// response is ready.
+///
+/// Histograms are bucketed counters: observing a value increments the
+/// counter for the smallest bucket it fits in, so summing the lines
+/// for a backend gives its distribution.
+pub struct Metrics {
+    backend: BackendNameTag,
+    counters: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    /// Start collecting metrics for a backend.
+    pub fn new(backend: &BackendNameTag) -> Self {
+        Self {
+            backend: backend.clone(),
+            counters: BTreeMap::new(),
+        }
+    }
+
+    /// Increment a counter.
+    pub fn increment(&mut self, name: &str) {
+        *self.counters.entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    /// Record a value in a histogram, with the given (ascending)
+    /// bucket upper bounds.
+    pub fn observe(&mut self, name: &str, buckets: &[usize], value: usize) {
+        let bucket = match buckets.iter().find(|&&bound| value <= bound) {
+            Some(bound) => format!("{}.le_{}", name, bound),
+            None => format!("{}.inf", name),
+        };
+        self.increment(&bucket);
+    }
+
+    /// Write the metrics to the metrics endpoint.
+    pub fn flush(self, settings: &Config) {
+        let counters = self
+            .counters
+            .into_iter()
+            .map(|(name, count)| (name, Value::from(count)))
+            .collect::<Map<String, Value>>();
+
+        let mut fields = Map::new();
+        fields.insert("backend".to_string(), Value::from(self.backend.as_str()));
+        fields.insert(
+            "backend_kind".to_string(),
+            Value::from(self.backend.kind(settings)),
+        );
+        fields.insert("counters".to_string(), Value::Object(counters));
+
+        cdn_logging::write_line(
+            settings.metrics_endpoint.as_deref(),
+            &Value::Object(fields).to_string(),
+        );
+    }
+}
+
+/// Record the origin latency, response size, and status class of a
+/// response from a backend, and flush them.
+///
+/// Responses without a `Content-Length` (eg, streamed ones) count
+/// towards `response_size_bytes.unknown`.
+pub fn record_response(
+    settings: &Config,
+    backend: &BackendNameTag,
+    latency: Duration,
+    resp: &Response,
+) {
+    let mut metrics = Metrics::new(backend);
+
+    metrics.observe(
+        "origin_latency_ms",
+        &settings.metrics_latency_buckets,
+        latency.as_millis() as usize,
+    );
+
+    match resp
+        .get_header_str(header::CONTENT_LENGTH)
+        .and_then(|length| length.parse::<usize>().ok())
+    {
+        Some(size) => metrics.observe("response_size_bytes", &settings.metrics_size_buckets, size),
+        None => metrics.increment("response_size_bytes.unknown"),
+    }
+
+    metrics.increment(&format!("status.{}xx", resp.get_status().as_u16() / 100));
+
+    metrics.flush(settings);
+}
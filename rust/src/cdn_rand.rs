@@ -0,0 +1,81 @@
+use crate::cdn_headers::REQUEST_ID_HEADER_NAME;
+
+use fastly::Request;
+use rand::distributions::uniform::SampleUniform;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::ops::Range;
+use uuid::Uuid;
+
+thread_local! {
+    /// The ID of the request being handled, and the random number
+    /// generator seeded from it.
+    static STATE: RefCell<Option<(String, StdRng)>> = RefCell::new(None);
+}
+
+/// Give a request its ID, and seed the random number generator from
+/// it.  If `reuse_id` is set (eg, for requests from another Fastly
+/// node) an existing `GOVUK-Request-Id` is kept; otherwise a new one
+/// is generated.
+///
+/// All the random decisions made for a request (A/B test assignment,
+/// sampling, and shadow traffic) come from this generator, so they
+/// can be reproduced from the request ID in the logs.  Values which
+/// must be unguessable (CSRF tokens and edge IDs) are not: they use
+/// the operating system's generator.
+pub fn seed_request(req: &mut Request, reuse_id: bool) {
+    let request_id = match req.get_header_str(REQUEST_ID_HEADER_NAME) {
+        Some(request_id) if reuse_id => request_id.to_string(),
+        _ => new_request_id(),
+    };
+    req.set_header(REQUEST_ID_HEADER_NAME, &request_id);
+    seed(&request_id);
+}
+
+/// Seed the random number generator from a request ID, to replay the
+/// random decisions made for that request.
+pub fn seed(request_id: &str) {
+    let digest = Sha256::digest(request_id.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    let rng = StdRng::seed_from_u64(u64::from_be_bytes(bytes));
+    STATE.with(|state| *state.borrow_mut() = Some((request_id.to_string(), rng)));
+}
+
+/// The ID of the request being handled (generating one, if the
+/// generator hasn't been seeded).
+pub fn request_id() -> String {
+    with_state(|request_id, _| request_id.to_string())
+}
+
+/// Pick a number in `range`.
+pub fn gen_range<T: SampleUniform + PartialOrd>(range: Range<T>) -> T {
+    with_state(|_, rng| rng.gen_range(range))
+}
+
+/// Pick `true` with a `percentage` percent chance.
+pub fn percent_chance(percentage: usize) -> bool {
+    gen_range(0..100) < percentage
+}
+
+/// Use the request ID and generator, seeding it first if need be.
+fn with_state<T, F: FnOnce(&str, &mut StdRng) -> T>(f: F) -> T {
+    if STATE.with(|state| state.borrow().is_none()) {
+        seed(&new_request_id());
+    }
+
+    STATE.with(|state| match &mut *state.borrow_mut() {
+        Some((request_id, rng)) => f(request_id, rng),
+        None => unreachable!("the generator was seeded above"),
+    })
+}
+
+/// Generate a new request ID.
+fn new_request_id() -> String {
+    Uuid::new_v4()
+        .to_hyphenated()
+        .encode_lower(&mut Uuid::encode_buffer())
+        .to_string()
+}
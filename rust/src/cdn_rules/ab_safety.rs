@@ -0,0 +1,18 @@
+use crate::cdn_rules::pinning;
+
+/// Edge Dictionary of disabled A/B test variants, which monitoring
+/// sets when a variant's origin requests fail or slow down too much
+const AB_TEST_SAFETY_DICTIONARY: &str = "ab_test_safety";
+
+/// Check if new users shouldn't be assigned to a variant.
+///
+/// Instances share no state, so they can't count for themselves how a
+/// variant's origin requests are doing.  Instead each instance logs an
+/// `ab_test_origin_response` event for (a sample of) them, and when a
+/// variant's error rate or latency is over its thresholds, monitoring
+/// sets `<name>:<variant>` in the `ab_test_safety` Edge Dictionary to
+/// the Unix timestamp the cool-down ends at.  The variant gets new
+/// users again once that time has passed.
+pub fn is_disabled(name: &str, variant: &str) -> bool {
+    pinning::is_set_until(AB_TEST_SAFETY_DICTIONARY, &format!("{}:{}", name, variant))
+}
@@ -1,11 +1,20 @@
-use crate::cdn_config::Config;
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_clock;
+use crate::cdn_config::{ABTestConfig, Config};
+use crate::cdn_headers::AB_TEST_PREVIEW_HEADER_NAME;
+use crate::cdn_rand;
+use crate::cdn_rules::ab_safety;
+use crate::cdn_rules::consent::{Consent, USAGE_CATEGORY};
+use crate::cdn_rules::edge_id::EDGE_ID_HEADER_NAME;
+use crate::cdn_rules::experiments;
+use crate::cdn_rules::{ip_is_on_acl, signing};
 
+use fastly::http::HeaderValue;
 use fastly::{Request, Response};
-use rand::Rng;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-
-/// User-Agent header of the crawler worker.
-const CRAWLER_WORKER_USER_AGENT: &str = "GOV.UK Crawler Worker";
+use std::net::IpAddr;
 
 /// Name of the example A/B test
 const EXAMPLE_AB_TEST_NAME: &str = "Example";
@@ -14,29 +23,75 @@ const EXAMPLE_AB_TEST_NAME: &str = "Example";
 /// consent cookie
 const EXAMPLE_AB_TEST_PATH: &str = "/help/ab-testing";
 
+/// Query parameter for previewing an A/B test variant, as
+/// `<Name>:<Variant>`
+pub const PREVIEW_PARAM_NAME: &str = "preview-abtest";
+
+/// Query parameter holding the signed preview token
+pub const PREVIEW_TOKEN_PARAM_NAME: &str = "preview-abtest-token";
+
+/// Surrogate key prefix for A/B tests.  Cached responses for a test
+/// are tagged `ab-test:<Name>` and `ab-test:<Name>:<Variant>`, so one
+/// purge evicts every variant.
+const SURROGATE_KEY_PREFIX: &str = "ab-test:";
+
+/// Path of the A/B test report
+pub const REPORT_PATH: &str = "/__edge/abtests";
+
 /// Assign the user to A/B test variants.
 ///
-/// If the user has a cookie, or a ?ABTest-<Name>=<Variant> query
-/// param, they are put in that variant; otherwise one is chosen at
-/// random.
-pub fn transform_bereq(settings: &Config, cookies: &HashMap<String, String>, bereq: &mut Request) {
-    if has_consented_to_ab_tests(cookies) {
-        for (name, ab_test) in settings.ab_tests.iter() {
+/// The crawler worker always gets the crawler variant.  Otherwise, if
+/// the user has a cookie, or a ?ABTest-<Name>=<Variant> query param,
+/// they are put in that variant; otherwise one is chosen at random
+/// (from the edge ID, if there is one, so the choice is stable).
+pub fn transform_bereq(
+    settings: &Config,
+    cookies: &HashMap<String, String>,
+    bereq: &mut Request,
+    crawler: bool,
+) {
+    assign(
+        settings,
+        &experiments::ab_tests(settings),
+        cookies,
+        bereq,
+        crawler,
+        ab_safety::is_disabled,
+    );
+}
+
+/// Assign the user to variants of the given A/B tests, setting the
+/// `GOVUK-ABTest-<Name>` request headers (or the tests' own
+/// `header_name`s).  New users aren't put in variants which
+/// `is_disabled`.
+pub fn assign(
+    settings: &Config,
+    ab_tests: &HashMap<String, ABTestConfig>,
+    cookies: &HashMap<String, String>,
+    bereq: &mut Request,
+    crawler: bool,
+    is_disabled: fn(&str, &str) -> bool,
+) {
+    if crawler {
+        for (name, ab_test) in ab_tests.iter() {
+            if ab_test.active {
+                let header_name = ab_test.variant_header_name(name);
+                bereq.set_header(header_name, ab_test.crawler_variant.clone());
+            }
+        }
+    } else if has_consented_to_ab_tests(settings, cookies) {
+        for (name, ab_test) in ab_tests.iter() {
             if !ab_test.active {
                 continue;
             }
 
-            let header_name: String = format!("GOVUK-ABTest-{}", name);
+            let header_name = ab_test.variant_header_name(name);
             let param_name: String = format!("ABTest-{}", name);
 
-            if bereq.get_header_str("user-agent") == Some(CRAWLER_WORKER_USER_AGENT) {
-                bereq.set_header(header_name, ab_test.crawler_variant.clone());
-                continue;
-            }
-
-            let qs: Vec<(String, String)> = bereq.get_query().unwrap();
-            let qs_map: HashMap<String, String> = qs.into_iter().collect();
-            if let Some(variant) = qs_map.get(&param_name) {
+            // if the param is repeated, the last value wins.
+            let qs: Vec<(String, String)> = bereq.get_query().unwrap_or_default();
+            let param = qs.iter().rev().find(|(name, _)| *name == param_name);
+            if let Some((_, variant)) = param {
                 if ab_test.variants.get(variant).is_some() {
                     bereq.set_header(header_name, variant);
                     continue;
@@ -50,44 +105,251 @@ pub fn transform_bereq(settings: &Config, cookies: &HashMap<String, String>, ber
                 }
             }
 
-            let total_freq = ab_test.variants.values().sum();
-            let mut index = rand::thread_rng().gen_range(0..total_freq);
-            for (variant, freq) in ab_test.variants.iter() {
+            // new users aren't put in variants the safety valve has
+            // disabled.
+            let variants: Vec<(&String, &i64)> = ab_test
+                .variants
+                .iter()
+                .filter(|(variant, _)| !is_disabled(name, variant))
+                .collect();
+            let total_freq = variants.iter().map(|(_, freq)| *freq).sum();
+            if total_freq == 0 {
+                continue;
+            }
+            let mut index = match bereq.get_header_str(EDGE_ID_HEADER_NAME) {
+                Some(edge_id) => bucket(edge_id, name, total_freq),
+                None => cdn_rand::gen_range(0..total_freq),
+            };
+            for (variant, freq) in variants {
                 if index <= *freq {
                     bereq.set_header(header_name, variant);
                     break;
                 } else {
-                    index = index - freq;
+                    index -= freq;
                 }
             }
         }
     }
 }
 
+/// Return a JSON report of the A/B tests, for a GET of `REPORT_PATH`
+/// from an IP on the `abtest_report` ACL (anyone else gets a 403), so
+/// the experiments team can check what's actually live: each test's
+/// header, cookie, and crawler variant, and each variant's weight and
+/// whether the safety valve has disabled it.
+///
+/// How many users are in each variant isn't reported: an instance only
+/// sees a few requests, so the split is for the `access` logs (which
+/// record the variants each response set cookies for) to show.
+pub fn report(settings: &Config, req: &Request) -> Option<Response> {
+    if req.get_path() != REPORT_PATH || req.get_method_str() != "GET" {
+        return None;
+    }
+
+    let allowed = match req.get_client_ip_addr() {
+        Some(client_ip) => ip_is_on_acl(&settings.acl_abtest_report, &client_ip, false),
+        None => false,
+    };
+    if !allowed {
+        return Some(report_response(
+            403,
+            json!({ "status": "error", "error": "not allowed to see the report" }),
+        ));
+    }
+
+    let mut tests = Map::new();
+    for (name, ab_test) in experiments::ab_tests(settings).iter() {
+        let mut variants = Map::new();
+        for (variant, weight) in ab_test.variants.iter() {
+            variants.insert(
+                variant.clone(),
+                json!({
+                    "weight": weight,
+                    "disabled": ab_safety::is_disabled(name, variant),
+                }),
+            );
+        }
+
+        tests.insert(
+            name.clone(),
+            json!({
+                "active": ab_test.active,
+                "header": ab_test.variant_header_name(name),
+                "cookie": { "name": format!("ABTest-{}", name), "max_age": ab_test.expires },
+                "crawler_variant": ab_test.crawler_variant,
+                "variants": variants,
+            }),
+        );
+    }
+
+    Some(report_response(200, json!({ "ab_tests": tests })))
+}
+
+/// Build a report response, which is never cached.
+fn report_response(status: u16, body: Value) -> Response {
+    Response::from_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_header(
+            BACKEND_NAME_HEADER_NAME,
+            BackendNameTag::ABTestReport.as_str(),
+        )
+        .with_body(body.to_string())
+}
+
+/// Get the variants of active A/B tests a backend request has been
+/// assigned to, as `(name, variant)` pairs.  Previews aren't included.
+pub fn assigned_variants(settings: &Config, bereq: &Request) -> Vec<(String, String)> {
+    if is_preview(bereq) {
+        return Vec::new();
+    }
+
+    experiments::ab_tests(settings)
+        .iter()
+        .filter(|(_, ab_test)| ab_test.active)
+        .filter_map(|(name, ab_test)| {
+            bereq
+                .get_header_str(ab_test.variant_header_name(name))
+                .map(|variant| (name.clone(), variant.to_string()))
+        })
+        .collect()
+}
+
+/// Get the A/B test variant a request is previewing, if it has a
+/// `?preview-abtest=<Name>:<Variant>` param for a variant of an active
+/// test, and may preview it.
+///
+/// Previews are allowed from IPs on `acl.abtest_preview`, or with a
+/// `?preview-abtest-token=<expiry>.<signature>` param, where `expiry`
+/// is a Unix timestamp and `signature` is the HMAC-SHA256 of
+/// `<Name>:<Variant>:<expiry>` with the `abtest_preview.secret` key.
+pub fn preview(settings: &Config, req: &Request, client_ip: &IpAddr) -> Option<(String, String)> {
+    let query: Vec<(String, String)> = req.get_query().unwrap_or_default();
+    let param = |wanted: &str| {
+        query
+            .iter()
+            .find(|(name, _)| name == wanted)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let preview = param(PREVIEW_PARAM_NAME)?;
+    let index = preview.find(':')?;
+    let (name, variant) = (&preview[..index], &preview[index + 1..]);
+
+    let ab_tests = experiments::ab_tests(settings);
+    let ab_test = ab_tests.get(name).filter(|ab_test| ab_test.active)?;
+    if !ab_test.variants.contains_key(variant) {
+        return None;
+    }
+
+    let allowed = ip_is_on_acl(&settings.acl_abtest_preview, client_ip, false)
+        || param(PREVIEW_TOKEN_PARAM_NAME).map_or(false, |token| {
+            is_valid_preview_token(settings, preview, token)
+        });
+    if allowed {
+        Some((name.to_string(), variant.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Check a preview token (`<expiry>.<signature>`) for a
+/// `<Name>:<Variant>` preview.
+fn is_valid_preview_token(settings: &Config, preview: &str, token: &str) -> bool {
+    let (expiry, signature) = match token.find('.') {
+        Some(index) => (&token[..index], &token[index + 1..]),
+        None => return false,
+    };
+
+    match expiry.parse::<u64>() {
+        Ok(expires_at) if expires_at >= cdn_clock::unix_time() => (),
+        _ => return false,
+    }
+
+    match signing::get_secret(settings, &settings.abtest_preview_secret) {
+        Some(secret) => signing::verify(&secret, &format!("{}:{}", preview, expiry), signature),
+        None => false,
+    }
+}
+
+/// Put a backend request in the previewed variant, whatever variant
+/// it was assigned.
+pub fn set_preview(settings: &Config, bereq: &mut Request, name: &str, variant: &str) {
+    if let Some(ab_test) = experiments::ab_tests(settings).get(name) {
+        bereq.set_header(ab_test.variant_header_name(name), variant);
+    }
+    bereq.set_header(AB_TEST_PREVIEW_HEADER_NAME, format!("{}:{}", name, variant));
+}
+
+/// Check if a backend request is previewing a variant.
+pub fn is_preview(bereq: &Request) -> bool {
+    bereq.contains_header(AB_TEST_PREVIEW_HEADER_NAME)
+}
+
+/// Tag a cacheable backend request with the surrogate keys of the A/B
+/// test variants it was assigned, which are added to the origin's.
+///
+/// This must not be called on requests to pass: setting surrogate
+/// keys overrides the pass.
+pub fn set_surrogate_keys(settings: &Config, bereq: &mut Request) {
+    let mut keys = Vec::new();
+    for (name, ab_test) in experiments::ab_tests(settings).iter() {
+        if !ab_test.active {
+            continue;
+        }
+        let header_name = ab_test.variant_header_name(name);
+        if let Some(variant) = bereq.get_header_str(header_name) {
+            keys.push(format!("{}{}", SURROGATE_KEY_PREFIX, name));
+            keys.push(format!("{}{}:{}", SURROGATE_KEY_PREFIX, name, variant));
+        }
+    }
+
+    if keys.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&keys.join(" ")) {
+        bereq.set_surrogate_key(value);
+    }
+}
+
 /// Set the response headers / cookies to keep the user in the same
 /// variant when they return.
+///
+/// Nothing is set in fallback mode, as the mirrors don't have the
+/// variants, or for the crawler worker.  Previews set no cookies, but
+/// say which variant is being previewed in the `GOVUK-ABTest-Preview`
+/// header, and aren't cached by the browser.
 pub fn transform_beresp(
     settings: &Config,
     bereq: &Request,
     mut beresp: Response,
     bereq_cookies: &HashMap<String, String>,
+    fallback: bool,
+    crawler: bool,
 ) -> Response {
     let mut resp = beresp.clone_with_body();
 
-    for (name, ab_test) in settings.ab_tests.iter() {
-        if !ab_test.active {
-            continue;
-        }
+    if let Some(preview) = bereq.get_header(AB_TEST_PREVIEW_HEADER_NAME) {
+        resp.set_header(AB_TEST_PREVIEW_HEADER_NAME, preview);
+        resp.set_header("Cache-Control", "no-store");
+        return resp;
+    }
 
-        if bereq.get_header_str("User-Agent") == Some(CRAWLER_WORKER_USER_AGENT) {
+    if fallback || crawler {
+        return resp;
+    }
+
+    let ab_tests = experiments::ab_tests(settings);
+    for (name, ab_test) in ab_tests.iter() {
+        if !ab_test.active {
             continue;
         }
 
-        let header_name: String = format!("GOVUK-ABTest-{}", name);
+        let header_name = ab_test.variant_header_name(name);
         let requested_variant: Option<&str> = bereq.get_header_str(header_name);
         let param_name: String = format!("ABTest-{}", name);
 
-        if has_consented_to_ab_tests(&bereq_cookies)
+        if has_consented_to_ab_tests(settings, &bereq_cookies)
             || (name == EXAMPLE_AB_TEST_NAME && bereq.get_path() == EXAMPLE_AB_TEST_PATH)
         {
             if let Some(variant) = requested_variant {
@@ -105,13 +367,30 @@ pub fn transform_beresp(
     resp
 }
 
-/// Check if the user has consented to A/B tests
-fn has_consented_to_ab_tests(cookies: &HashMap<String, String>) -> bool {
-    if let Some(policy) = cookies.get("cookies_policy") {
-        if policy.contains("%22usage%22:true") {
-            return true;
+/// Pick a number in `0..total` from the edge ID and test name.
+fn bucket(edge_id: &str, test_name: &str, total: i64) -> i64 {
+    let digest = Sha256::digest(format!("{}:{}", edge_id, test_name).as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) % total as u64) as i64
+}
+
+/// Get the A/B test variants a response sets cookies for, as a map of
+/// test name to variant.
+pub fn response_variants(resp: &Response) -> Map<String, Value> {
+    let mut variants = Map::new();
+    for value in resp.get_header_all_str("Set-Cookie") {
+        let mut parts = value.split(';').next().unwrap_or("").splitn(2, '=');
+        if let (Some(name), Some(variant)) = (parts.next(), parts.next()) {
+            if let Some(name) = name.trim().strip_prefix("ABTest-") {
+                variants.insert(name.to_string(), Value::from(variant));
+            }
         }
     }
+    variants
+}
 
-    false
+/// Check if the user has consented to A/B tests
+pub fn has_consented_to_ab_tests(settings: &Config, cookies: &HashMap<String, String>) -> bool {
+    Consent::from_cookies(settings, cookies).has_granted(USAGE_CATEGORY)
 }
@@ -1,30 +1,104 @@
-use crate::cdn_rules::backends;
+use crate::cdn_headers::{
+    ACCOUNT_END_SESSION_HEADER_NAME, ACCOUNT_SESSION_HEADER_NAME, AUTHENTICATED_HEADER_NAME,
+};
+use crate::cdn_rules::ab_tests;
 
+use fastly::http::header;
 use fastly::{Body, Request, Response};
 use std::collections::HashMap;
-use std::io::BufRead;
-use std::io::Write;
+use std::io::{BufRead, Write};
 
-/// Request / response header for the session ID
-const ACCOUNT_SESSION_HEADER_NAME: &str = "GOVUK-Account-Session";
+/// Response header for skipping the body transform
+const NO_TRANSFORM_HEADER_NAME: &str = "GOVUK-Edge-No-Transform";
 
-/// Response header for ending the session
-const ACCOUNT_END_SESSION_HEADER_NAME: &str = "GOVUK-Account-End-Session";
+/// MIME type prefixes of binary bodies, which are never transformed
+const BINARY_MIME_TYPE_PREFIXES: [&str; 4] = ["image/", "audio/", "video/", "font/"];
+
+/// MIME types of binary bodies, which are never transformed
+const BINARY_MIME_TYPES: [&str; 5] = [
+    "application/octet-stream",
+    "application/pdf",
+    "application/zip",
+    "application/gzip",
+    "application/vnd.ms-fontobject",
+];
 
 /// Session cookie for the session ID
-const ACCOUNT_COOKIE_NAME: &str = "govuk_account_session";
+pub const ACCOUNT_COOKIE_NAME: &str = "govuk_account_session";
 
-/// Add the account request header if the cookie is set.
+/// Add the account request header if the cookie is set, and the
+/// authenticated header either way.
+///
+/// Requests with a session always skip the cache: we can't tell
+/// before sending them whether the origin will `Vary` on the session,
+/// and a personalised response cached without it would be served to
+/// everyone.
 pub fn transform_bereq(cookies: &HashMap<String, String>, bereq: &mut Request) {
     if let Some(session_id) = cookies.get(ACCOUNT_COOKIE_NAME) {
         bereq.set_header(ACCOUNT_SESSION_HEADER_NAME, session_id);
+        bereq.set_header(AUTHENTICATED_HEADER_NAME, "1");
+        bereq.set_pass(true);
+    } else {
+        bereq.set_header(AUTHENTICATED_HEADER_NAME, "0");
     }
 }
 
 /// Transform the response: handle the special response headers and
 /// transform the body.
-pub fn transform_beresp(bereq: &Request, beresp: Response) -> Response {
-    transform_css(bereq, transform_header(beresp))
+///
+/// The body is not transformed if the origin sends
+/// `GOVUK-Edge-No-Transform: 1`, if the response is `degraded` (there
+/// is too little time left to do it), or if it is binary (images,
+/// PDFs, zips, etc), which is checked before the body is touched.
+///
+/// In fallback mode the session is left alone, and the body is
+/// transformed for the mirrored version of the page, which may be
+/// `stale`.  An A/B test preview (see `ab_tests::is_preview`) shows
+/// the preview banner, and `maintenance` the maintenance banner.  The
+/// `replacements` (from `body_rules`) are made in the same pass, before
+/// the built-in classes.
+pub fn transform_beresp(
+    bereq: &Request,
+    mut beresp: Response,
+    replacements: &[(&str, &str)],
+    fallback: bool,
+    stale: bool,
+    degraded: bool,
+    maintenance: bool,
+) -> Response {
+    let no_transform = !can_transform(&beresp, degraded);
+    beresp.remove_header(NO_TRANSFORM_HEADER_NAME);
+
+    let resp = if fallback {
+        beresp
+    } else {
+        transform_header(beresp)
+    };
+    if no_transform {
+        resp
+    } else {
+        let preview = ab_tests::is_preview(bereq);
+        transform_css(
+            bereq,
+            resp,
+            replacements,
+            fallback,
+            stale,
+            preview,
+            maintenance,
+        )
+    }
+}
+
+/// Check if a response body may be transformed: it isn't `degraded`,
+/// the origin hasn't sent `GOVUK-Edge-No-Transform: 1`, and it isn't
+/// compressed or binary.
+pub fn can_transform(beresp: &Response, degraded: bool) -> bool {
+    // compressed bodies can't be transformed line-by-line.
+    !degraded
+        && beresp.get_header_str(NO_TRANSFORM_HEADER_NAME) != Some("1")
+        && !beresp.contains_header(header::CONTENT_ENCODING)
+        && !is_binary(beresp)
 }
 
 /// Handle the special account response headers: updating cookies or
@@ -40,7 +114,7 @@ fn transform_header(mut beresp: Response) -> Response {
                 ACCOUNT_COOKIE_NAME
             ),
         );
-    } else if let Some(session_id) = resp.get_header_str("GOVUK-Account-Session") {
+    } else if let Some(session_id) = resp.get_header_str(ACCOUNT_SESSION_HEADER_NAME) {
         let value = format!(
             "{}={}; secure; httponly; samesite=lax; path=/",
             ACCOUNT_COOKIE_NAME, session_id
@@ -49,13 +123,11 @@ fn transform_header(mut beresp: Response) -> Response {
     }
 
     let varies = beresp.get_header_all_str("Vary");
-    let varies_by_account_session = varies
-        .iter()
-        .any(|value| *value == ACCOUNT_SESSION_HEADER_NAME);
+    let varies_by_account_session = varies.iter().any(|value| is_internal_vary(value));
     if varies_by_account_session {
         resp.remove_header("Vary");
         for vary in varies.into_iter() {
-            if vary == ACCOUNT_SESSION_HEADER_NAME {
+            if is_internal_vary(vary) {
                 continue;
             }
             resp.append_header("Vary", vary);
@@ -68,9 +140,15 @@ fn transform_header(mut beresp: Response) -> Response {
     resp
 }
 
+/// Check if a `Vary` value is one of the account headers, which are
+/// only meaningful between the edge and the origin.
+fn is_internal_vary(vary: &str) -> bool {
+    vary == ACCOUNT_SESSION_HEADER_NAME || vary == AUTHENTICATED_HEADER_NAME
+}
+
 /// Transforms the body through simple textual replacement
 ///
-/// There are three special strings, intended to be used as CSS
+/// There are six special strings, intended to be used as CSS
 /// classes, and replaced with the appropriate value:
 ///
 /// - `compute_at_edge--show-if-mirrored` - a CSS class which is
@@ -78,6 +156,18 @@ fn transform_header(mut beresp: Response) -> Response {
 ///    cases.  This is so we can have something which is visible only
 ///    when we fall back to the static mirrors
 ///
+/// - `compute_at_edge--show-if-stale` - a CSS class which is hidden
+///    by default, turned into `compute_at_edge--show` if the page
+///    comes from a mirrored copy older than `mirror_max_age_s`, and
+///    `compute_at_edge--hide` otherwise.  This is so we can warn users
+///    that the page may be out of date.
+///
+/// - `compute_at_edge--show-if-abtest-preview` - a CSS class which is
+///    hidden by default, turned into `compute_at_edge--show` if the
+///    request is previewing an A/B test variant, and
+///    `compute_at_edge--hide` otherwise.  This is so content designers
+///    can see they're looking at a preview.
+///
 /// - `compute_at_edge--show-if-cookie` - a CSS class which is hidden
 ///    by default, turned into `compute_at_edge--show` if the session
 ///    cookie is present, and `compute_at_edge--hide` otherwise.  This
@@ -90,45 +180,75 @@ fn transform_header(mut beresp: Response) -> Response {
 ///    otherwise.  This is so we can have something which is visible
 ///    only when the user has a session cookie.
 ///
+/// - `compute_at_edge--show-if-maintenance` - a CSS class which is
+///    hidden by default, turned into `compute_at_edge--show` if a
+///    response header rule with `maintenance_banner` applies (eg, one
+///    scheduled for a planned maintenance window), and
+///    `compute_at_edge--hide` otherwise.
+///
 /// The classes `compute_at_edge--show` and `compute_at_edge--hide`
 /// control visibility of elements in the way you'd expect.
-fn transform_css(bereq: &Request, mut beresp: Response) -> Response {
-    let mut resp = beresp.clone_with_body();
-
+///
+/// The `replacements` are made first, in the same pass.
+fn transform_css(
+    bereq: &Request,
+    mut resp: Response,
+    replacements: &[(&str, &str)],
+    fallback: bool,
+    stale: bool,
+    preview: bool,
+    maintenance: bool,
+) -> Response {
     if has_mime_type(&resp, "text/html") {
-        let (show_if_mirrored, show_if_cookie, show_if_not_cookie) =
-            if beresp.get_header_str("Fastly-Backend-Name") != Some(backends::ORIGIN) {
-                (
-                    "compute_at_edge--show",
-                    "compute_at_edge--hide",
-                    "compute_at_edge--hide",
-                )
-            } else if bereq.contains_header(ACCOUNT_SESSION_HEADER_NAME) {
-                (
-                    "compute_at_edge--hide",
-                    "compute_at_edge--show",
-                    "compute_at_edge--hide",
-                )
-            } else {
-                (
-                    "compute_at_edge--hide",
-                    "compute_at_edge--hide",
-                    "compute_at_edge--show",
-                )
-            };
+        let (show_if_mirrored, show_if_cookie, show_if_not_cookie) = if fallback {
+            (
+                "compute_at_edge--show",
+                "compute_at_edge--hide",
+                "compute_at_edge--hide",
+            )
+        } else if bereq.contains_header(ACCOUNT_SESSION_HEADER_NAME) {
+            (
+                "compute_at_edge--hide",
+                "compute_at_edge--show",
+                "compute_at_edge--hide",
+            )
+        } else {
+            (
+                "compute_at_edge--hide",
+                "compute_at_edge--hide",
+                "compute_at_edge--show",
+            )
+        };
+
+        let show_if_stale = if stale {
+            "compute_at_edge--show"
+        } else {
+            "compute_at_edge--hide"
+        };
+        let show_if_preview = if preview {
+            "compute_at_edge--show"
+        } else {
+            "compute_at_edge--hide"
+        };
+        let show_if_maintenance = if maintenance {
+            "compute_at_edge--show"
+        } else {
+            "compute_at_edge--hide"
+        };
+
+        let mut classes = replacements.to_vec();
+        classes.extend_from_slice(&[
+            ("compute_at_edge--show-if-mirrored", show_if_mirrored),
+            ("compute_at_edge--show-if-stale", show_if_stale),
+            ("compute_at_edge--show-if-abtest-preview", show_if_preview),
+            ("compute_at_edge--show-if-cookie", show_if_cookie),
+            ("compute_at_edge--show-if-not-cookie", show_if_not_cookie),
+            ("compute_at_edge--show-if-maintenance", show_if_maintenance),
+        ]);
 
+        let body = resp.take_body();
         let mut transformed_body = Body::new();
-        for line in resp.take_body().lines() {
-            write!(
-                &mut transformed_body,
-                "{}\n",
-                line.unwrap()
-                    .replace("compute_at_edge--show-if-mirrored", show_if_mirrored)
-                    .replace("compute_at_edge--show-if-cookie", show_if_cookie)
-                    .replace("compute_at_edge--show-if-not-cookie", show_if_not_cookie),
-            )
-            .unwrap();
-        }
+        rewrite_classes(body, &mut transformed_body, &classes);
 
         resp.with_body(transformed_body)
     } else {
@@ -136,10 +256,86 @@ fn transform_css(bereq: &Request, mut beresp: Response) -> Response {
     }
 }
 
-/// Check if a response has a given MIME type.
-fn has_mime_type(resp: &Response, mimetype: &str) -> bool {
+/// Copy an HTML body, replacing each conditional class in `classes`
+/// (in order) with its value, line by line.
+///
+/// Lines which aren't valid UTF-8 are passed through byte-for-byte.
+pub fn rewrite_classes(input: impl BufRead, output: &mut impl Write, classes: &[(&str, &str)]) {
+    rewrite_lines(input, output, |line| {
+        let mut rewritten = line.to_string();
+        for (class, value) in classes {
+            rewritten = rewritten.replace(class, value);
+        }
+        rewritten
+    });
+}
+
+/// Copy a body line by line (each with its `\n`, if it has one),
+/// passing each through `rewrite`.
+///
+/// Lines which aren't valid UTF-8 are passed through byte-for-byte.
+pub fn rewrite_lines(
+    mut input: impl BufRead,
+    output: &mut impl Write,
+    mut rewrite: impl FnMut(&str) -> String,
+) {
+    let mut line = Vec::new();
+    while let Ok(length) = input.read_until(b'\n', &mut line) {
+        if length == 0 {
+            break;
+        }
+        let _ = match std::str::from_utf8(&line) {
+            Ok(line_str) => output.write_all(rewrite(line_str).as_bytes()),
+            Err(_) => output.write_all(&line),
+        };
+        line.clear();
+    }
+}
+
+/// Check if a response has a binary MIME type.
+fn is_binary(resp: &Response) -> bool {
     match resp.get_content_type() {
-        Some(mime) if mime.essence_str() == mimetype => true,
-        _ => false,
+        Some(mime) => {
+            let essence = mime.essence_str();
+            BINARY_MIME_TYPES.contains(&essence)
+                || BINARY_MIME_TYPE_PREFIXES
+                    .iter()
+                    .any(|prefix| essence.starts_with(prefix))
+        }
+        None => false,
+    }
+}
+
+/// Check if a response has a given MIME type.
+pub fn has_mime_type(resp: &Response, mimetype: &str) -> bool {
+    matches!(resp.get_content_type(), Some(mime) if mime.essence_str() == mimetype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASSES: [(&str, &str); 1] =
+        [("compute_at_edge--show-if-mirrored", "compute_at_edge--hide")];
+
+    fn rewrite_bytes(input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        rewrite_classes(input, &mut output, &CLASSES);
+        output
+    }
+
+    #[test]
+    fn passes_invalid_utf8_lines_through() {
+        let input = b"<p class=\"compute_at_edge--show-if-mirrored\">\n\xff\xfe compute_at_edge--show-if-mirrored\n<p class=\"compute_at_edge--show-if-mirrored\">";
+        assert_eq!(
+            rewrite_bytes(input),
+            b"<p class=\"compute_at_edge--hide\">\n\xff\xfe compute_at_edge--show-if-mirrored\n<p class=\"compute_at_edge--hide\">".to_vec()
+        );
+    }
+
+    #[test]
+    fn passes_binary_bodies_through() {
+        let input: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        assert_eq!(rewrite_bytes(&input), input);
     }
 }
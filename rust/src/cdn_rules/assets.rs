@@ -0,0 +1,47 @@
+use crate::cdn_config::Config;
+
+use fastly::http::header;
+use fastly::{Request, Response};
+
+/// Shortest hex digest which marks a file name as fingerprinted
+const MIN_FINGERPRINT_LENGTH: usize = 8;
+
+/// Drop the cookies: assets are the same for everyone, so nothing
+/// about the user should reach the origin.
+pub fn transform_bereq(bereq: &mut Request) {
+    bereq.remove_header(header::COOKIE);
+}
+
+/// Allow cross-origin use of assets, never set cookies, and cache
+/// fingerprinted assets forever (as a new version gets a new path).
+pub fn transform_beresp(settings: &Config, bereq: &Request, mut resp: Response) -> Response {
+    resp.remove_header(header::SET_COOKIE);
+    resp.set_header(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        &settings.assets_cors_origin,
+    );
+
+    if resp.get_status().is_success() && is_fingerprinted(bereq.get_path()) {
+        resp.set_header(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}, immutable", settings.assets_max_age),
+        );
+    }
+
+    resp
+}
+
+/// Check if a path is a fingerprinted asset, like
+/// `/assets/application-3f2a9c1b7d0e.css`: the file name has a hex
+/// digest separated from the rest by a `-` or `.`.
+fn is_fingerprinted(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or("");
+    let stem = match file_name.rfind('.') {
+        Some(index) => &file_name[..index],
+        None => return false,
+    };
+
+    stem.split(|c| c == '-' || c == '.').skip(1).any(|part| {
+        part.len() >= MIN_FINGERPRINT_LENGTH && part.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
@@ -1,7 +1,50 @@
-pub const ORIGIN: &str = "origin";
+use crate::cdn_backend_name::BackendNameTag;
+use crate::cdn_headers::{self, BACKEND_ATTEMPTS_HEADER_NAME};
 
-pub const FALLBACK1: &str = "mirrorS3";
+use fastly::Response;
+use std::time::Duration;
 
-pub const FALLBACK2: &str = "mirrorS3Replica";
+/// Which backend served a response, and how we got there.
+pub struct ServedBy {
+    /// Backend name
+    pub backend: BackendNameTag,
+    /// Whether the response came from a mirror
+    pub failover: bool,
+    /// Number of backends tried, including this one
+    pub attempts: u32,
+    /// How much of the request deadline was left when the response
+    /// arrived
+    pub remaining: Duration,
+}
 
-pub const FALLBACK3: &str = "mirrorGCS";
+impl ServedBy {
+    /// Set the `Fastly-Backend-Name`, `Fastly-Failover`, and
+    /// `Fastly-Backend-Attempts` response headers.
+    pub fn set_headers(&self, resp: &mut Response) {
+        self.backend.set_header(resp);
+        resp.set_header(BACKEND_ATTEMPTS_HEADER_NAME, self.attempts.to_string());
+        cdn_headers::set_failover(resp, self.failover);
+    }
+
+    /// Get the `ServedBy` from a response which has come from a
+    /// shield, adding the attempts it took to reach the shield.
+    pub fn from_shield_response(
+        resp: &Response,
+        shield_backend: &str,
+        attempts: u32,
+        remaining: Duration,
+    ) -> Self {
+        let shield_attempts = resp
+            .get_header_str(BACKEND_ATTEMPTS_HEADER_NAME)
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(1);
+
+        ServedBy {
+            backend: BackendNameTag::from_response(resp)
+                .unwrap_or_else(|| BackendNameTag::Backend(shield_backend.to_string())),
+            failover: cdn_headers::is_failover(resp),
+            attempts: attempts - 1 + shield_attempts,
+            remaining,
+        }
+    }
+}
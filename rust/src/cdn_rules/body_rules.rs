@@ -0,0 +1,73 @@
+use crate::cdn_config::{BodyRuleConfig, Config};
+use crate::cdn_rules::{header_rules, matches_path_pattern};
+
+use fastly::Request;
+use std::collections::HashMap;
+
+/// Get the `(find, replacement)` pairs of the `body_rules` for a
+/// request, in order: each rule's `find` string is replaced with its
+/// `replace` string if the rule matches the request, or with its
+/// `otherwise` string (if it has one) if not.
+///
+/// These are applied in the same pass as the built-in
+/// `compute_at_edge--*` classes, and before them (see
+/// `accounts::transform_beresp`), so a rule can turn its own class into
+/// one of those.
+pub fn replacements<'a>(
+    settings: &'a Config,
+    bereq: &Request,
+    cookies: &HashMap<String, String>,
+    fallback: bool,
+) -> Vec<(&'a str, &'a str)> {
+    settings
+        .body_rules
+        .iter()
+        .filter_map(|rule| {
+            let replacement = if matches(rule, bereq, cookies, fallback) {
+                Some(rule.replace.as_str())
+            } else {
+                rule.otherwise.as_deref()
+            };
+            replacement.map(|replacement| (rule.find.as_str(), replacement))
+        })
+        .collect()
+}
+
+/// Check if a body rule matches a request.  All of its conditions must
+/// hold.
+fn matches(
+    rule: &BodyRuleConfig,
+    bereq: &Request,
+    cookies: &HashMap<String, String>,
+    fallback: bool,
+) -> bool {
+    if let Some(pattern) = &rule.path {
+        if !matches_path_pattern(pattern, bereq.get_path()) {
+            return false;
+        }
+    }
+
+    if let Some((name, expected)) = &rule.header {
+        let actual = bereq
+            .get_header(name.as_str())
+            .and_then(|value| value.to_str().ok());
+        if !header_rules::value_matches(actual, expected) {
+            return false;
+        }
+    }
+
+    if let Some((name, expected)) = &rule.cookie {
+        let actual = cookies.get(name).map(|value| value.as_str());
+        if !header_rules::value_matches(actual, expected) {
+            return false;
+        }
+    }
+
+    if let Some(failover) = rule.failover {
+        if failover != fallback {
+            return false;
+        }
+    }
+
+    true
+}
@@ -0,0 +1,80 @@
+use crate::cdn_clock;
+use crate::cdn_config::Config;
+use crate::cdn_rules::signing;
+
+use fastly::Request;
+
+/// Query parameter holding the signed cache bypass token
+pub const CACHEBUST_PARAM_NAME: &str = "cachebust";
+
+/// Check if the request has a valid cache bypass token, which lets
+/// publishers preview freshly published content without waiting for
+/// the cache.
+///
+/// Tokens look like `<expiry>.<signature>`, where `expiry` is a Unix
+/// timestamp and `signature` is the HMAC-SHA256 of `<path>:<expiry>`
+/// with the `cachebust.secret` key.
+pub fn is_valid(settings: &Config, req: &Request) -> bool {
+    let query: Vec<(String, String)> = req.get_query().unwrap_or_default();
+    let token = match query.iter().find(|(key, _)| key == CACHEBUST_PARAM_NAME) {
+        Some((_, token)) => token,
+        None => return false,
+    };
+
+    match signing::get_secret(settings, &settings.cachebust_secret) {
+        Some(secret) => is_signed(&secret, req.get_path(), token, cdn_clock::unix_time()),
+        None => false,
+    }
+}
+
+/// Check if a token for a path is unexpired and validly signed.
+fn is_signed(secret: &str, path: &str, token: &str, now: u64) -> bool {
+    let (expiry, signature) = match token.find('.') {
+        Some(index) => (&token[..index], &token[index + 1..]),
+        None => return false,
+    };
+
+    let expires_at = match expiry.parse::<u64>() {
+        Ok(expires_at) => expires_at,
+        Err(_) => return false,
+    };
+    if expires_at < now {
+        return false;
+    }
+
+    signing::verify(secret, &format!("{}:{}", path, expiry), signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(path: &str, expiry: u64) -> String {
+        let signature = signing::sign("secret", &format!("{}:{}", path, expiry));
+        format!("{}.{}", expiry, signature)
+    }
+
+    #[test]
+    fn token_is_valid_until_expiry() {
+        let token = token("/government", 1000);
+        assert!(is_signed("secret", "/government", &token, 999));
+        assert!(is_signed("secret", "/government", &token, 1000));
+        assert!(!is_signed("secret", "/government", &token, 1001));
+    }
+
+    #[test]
+    fn token_is_only_valid_for_its_path() {
+        let token = token("/government", 1000);
+        assert!(!is_signed("secret", "/government/news", &token, 0));
+    }
+
+    #[test]
+    fn tampered_tokens_are_invalid() {
+        let token = token("/government", 1000);
+        let later = token.replacen("1000", "2000", 1);
+        assert!(!is_signed("secret", "/government", &later, 0));
+        assert!(!is_signed("other secret", "/government", &token, 0));
+        assert!(!is_signed("secret", "/government", "1000", 0));
+        assert!(!is_signed("secret", "/government", "soon.abcd", 0));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_clock;
+use crate::cdn_config::Config;
+use crate::cdn_rules::{pages, signing};
+
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Cookie holding a solved challenge, as
+/// `<expiry>.<signature>.<nonce>`
+pub const CHALLENGE_COOKIE_NAME: &str = "govuk_edge_challenge";
+
+/// The challenge page.  `{{challenge}}`, `{{difficulty}}`, and
+/// `{{max_age}}` are filled in by the edge.
+const SYNTHETIC_CHALLENGE_RESPONSE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Welcome to GOV.UK</title>
+    <style>
+      body { font-family: Arial, sans-serif; margin: 0; }
+      header { background: black; }
+      h1 { color: white; font-size: 29px; margin: 0 auto; padding: 10px; max-width: 990px; }
+      p { color: black; margin: 30px auto; max-width: 990px; }
+    </style>
+  </head>
+  <body>
+    <header><h1>GOV.UK</h1></header>
+    <p>Checking your browser. This page will reload in a few seconds.</p>
+    <noscript><p>You need to turn on JavaScript to continue.</p></noscript>
+    <script>
+      (async function () {
+        var challenge = "{{challenge}}";
+        var difficulty = {{difficulty}};
+        var encoder = new TextEncoder();
+        function zeroBits(bytes) {
+          var bits = 0;
+          for (var i = 0; i < bytes.length; i++) {
+            if (bytes[i] === 0) { bits += 8; continue; }
+            bits += Math.clz32(bytes[i]) - 24;
+            break;
+          }
+          return bits;
+        }
+        for (var nonce = 0; ; nonce++) {
+          var digest = await crypto.subtle.digest("SHA-256", encoder.encode(challenge + "." + nonce));
+          if (zeroBits(new Uint8Array(digest)) >= difficulty) {
+            document.cookie = "govuk_edge_challenge=" + challenge + "." + nonce + "; max-age={{max_age}}; path=/; secure; samesite=lax";
+            window.location.reload();
+            return;
+          }
+        }
+      })();
+    </script>
+  </body>
+</html>
+"#;
+
+/// Check if challenges are turned on.
+pub fn is_enabled(settings: &Config) -> bool {
+    settings.challenge_difficulty > 0
+}
+
+/// Check if the client has solved a challenge: it has a challenge
+/// cookie which hasn't expired, whose signature is for its IP, and
+/// whose SHA-256 starts with `challenge.difficulty` zero bits.
+pub fn is_solved(settings: &Config, cookies: &HashMap<String, String>, req: &Request) -> bool {
+    if !is_enabled(settings) {
+        return false;
+    }
+
+    let cookie = match cookies.get(CHALLENGE_COOKIE_NAME) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+    let client_ip = match req.get_client_ip_addr() {
+        Some(client_ip) => client_ip.to_string(),
+        None => return false,
+    };
+
+    let mut parts = cookie.splitn(3, '.');
+    let (expiry, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(expiry), Some(signature), Some(_)) => (expiry, signature),
+        _ => return false,
+    };
+    match expiry.parse::<u64>() {
+        Ok(expiry) if expiry > cdn_clock::unix_time() => (),
+        _ => return false,
+    }
+
+    let secret = match signing::get_secret(settings, &settings.challenge_secret) {
+        Some(secret) => secret,
+        None => return false,
+    };
+    let expected = signing::sign(&secret, &format!("{}:{}", client_ip, expiry));
+    if !signing::secrets_match(&expected, signature) {
+        return false;
+    }
+
+    leading_zero_bits(&Sha256::digest(cookie.as_bytes())) >= settings.challenge_difficulty
+}
+
+/// Generate the challenge page, with a new challenge for the client:
+/// an expiry `challenge.ttl_s` from now, signed with the client's IP.
+/// The page is never cached.
+pub fn response(settings: &Config, req: &Request, status: u16) -> Response {
+    let expiry = cdn_clock::unix_time() + settings.challenge_ttl.as_secs();
+    let client_ip = req
+        .get_client_ip_addr()
+        .map_or(String::new(), |client_ip| client_ip.to_string());
+    let signature = signing::get_secret(settings, &settings.challenge_secret)
+        .map_or(String::new(), |secret| {
+            signing::sign(&secret, &format!("{}:{}", client_ip, expiry))
+        });
+
+    let body = pages::body("challenge", SYNTHETIC_CHALLENGE_RESPONSE)
+        .replace("{{challenge}}", &format!("{}.{}", expiry, signature))
+        .replace("{{difficulty}}", &settings.challenge_difficulty.to_string())
+        .replace("{{max_age}}", &settings.challenge_ttl.as_secs().to_string());
+
+    Response::from_status(status)
+        .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Challenge.as_str())
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_header("Cache-Control", "no-store")
+        .with_body(body)
+}
+
+/// Count the zero bits at the start of a digest.
+fn leading_zero_bits(digest: &[u8]) -> usize {
+    let mut bits = 0;
+    for byte in digest {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros() as usize;
+            break;
+        }
+    }
+    bits
+}
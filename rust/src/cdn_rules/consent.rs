@@ -0,0 +1,159 @@
+use crate::cdn_config::Config;
+
+use percent_encoding::percent_decode_str;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Cookie recording which categories of cookie the user has consented
+/// to, as percent-encoded JSON (eg, `{"essential":true,"usage":false}`)
+const CONSENT_COOKIE_NAME: &str = "cookies_policy";
+
+/// Cookie holding an IAB TCF v2 consent string
+const TCF_COOKIE_NAME: &str = "euconsent-v2";
+
+/// Offset (in bits) of the purpose consents in a TCF v2 core string
+const TCF_PURPOSES_OFFSET: usize = 152;
+
+/// Consent category covering A/B tests and the edge ID
+pub const USAGE_CATEGORY: &str = "usage";
+
+/// What the user has said about cookies.
+pub enum Consent {
+    /// There is no consent cookie
+    Missing,
+    /// The consent cookie can't be parsed
+    Invalid,
+    /// The consent categories the user has granted
+    Granted(Vec<String>),
+}
+
+/// A source of the user's consent: something which turns their
+/// cookies into a `Consent`.
+pub trait ConsentProvider {
+    /// Get the user's consent from their cookies.
+    fn consent(&self, cookies: &HashMap<String, String>) -> Consent;
+}
+
+/// Consent from the GOV.UK cookie banner's `cookies_policy` cookie.
+pub struct CookiesPolicy;
+
+impl ConsentProvider for CookiesPolicy {
+    fn consent(&self, cookies: &HashMap<String, String>) -> Consent {
+        let cookie = match cookies.get(CONSENT_COOKIE_NAME) {
+            Some(cookie) => cookie,
+            None => return Consent::Missing,
+        };
+
+        let policy = percent_decode_str(cookie)
+            .decode_utf8()
+            .ok()
+            .and_then(|json| serde_json::from_str::<Value>(&json).ok());
+
+        match policy {
+            Some(Value::Object(categories)) => {
+                let mut granted: Vec<String> = categories
+                    .into_iter()
+                    .filter(|(_, value)| value == &Value::Bool(true))
+                    .map(|(category, _)| category)
+                    .collect();
+                granted.sort();
+                Consent::Granted(granted)
+            }
+            _ => Consent::Invalid,
+        }
+    }
+}
+
+/// Consent from an IAB TCF v2 consent string, as set by a consent
+/// management platform, with each category granted if the TCF purpose
+/// it maps to is.
+pub struct Tcf<'a> {
+    /// TCF purpose (1 to 24) for each category
+    pub purposes: &'a HashMap<String, usize>,
+}
+
+impl<'a> ConsentProvider for Tcf<'a> {
+    fn consent(&self, cookies: &HashMap<String, String>) -> Consent {
+        let cookie = match cookies.get(TCF_COOKIE_NAME) {
+            Some(cookie) => cookie,
+            None => return Consent::Missing,
+        };
+
+        // the core string is the first `.`-separated segment, and is
+        // base64url, so each character is six bits
+        let core = cookie.split('.').next().unwrap_or("");
+        let bits: Option<Vec<u8>> = core.bytes().map(base64url_value).collect();
+        let bits = match bits {
+            Some(bits) if bits.len() * 6 >= TCF_PURPOSES_OFFSET + 24 => bits,
+            _ => return Consent::Invalid,
+        };
+        let bit = |index: usize| bits[index / 6] & (0b100000 >> (index % 6)) != 0;
+
+        // the version is the first six bits
+        if bits[0] != 2 {
+            return Consent::Invalid;
+        }
+
+        let mut granted: Vec<String> = self
+            .purposes
+            .iter()
+            .filter(|(_, purpose)| bit(TCF_PURPOSES_OFFSET + *purpose - 1))
+            .map(|(category, _)| category.clone())
+            .collect();
+        granted.sort();
+        Consent::Granted(granted)
+    }
+}
+
+/// The value of a base64url character.
+fn base64url_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    }
+}
+
+/// The configured consent provider.
+pub fn provider(settings: &Config) -> Box<dyn ConsentProvider + '_> {
+    match settings.consent_provider.as_str() {
+        "tcf" => Box::new(Tcf {
+            purposes: &settings.consent_tcf_purposes,
+        }),
+        _ => Box::new(CookiesPolicy),
+    }
+}
+
+impl Consent {
+    /// Get the user's consent from their cookies, with the configured
+    /// provider.
+    pub fn from_cookies(settings: &Config, cookies: &HashMap<String, String>) -> Self {
+        provider(settings).consent(cookies)
+    }
+
+    /// Name of the state, for logging.
+    pub fn status(&self) -> &'static str {
+        match self {
+            Consent::Missing => "missing",
+            Consent::Invalid => "invalid",
+            Consent::Granted(_) => "set",
+        }
+    }
+
+    /// The granted categories (none, if the cookie is missing or
+    /// can't be parsed).
+    pub fn categories(&self) -> &[String] {
+        match self {
+            Consent::Granted(categories) => categories,
+            _ => &[],
+        }
+    }
+
+    /// Check if the user has granted a category.
+    pub fn has_granted(&self, category: &str) -> bool {
+        self.categories().iter().any(|granted| granted == category)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::cdn_config::{Acl, Config};
+use crate::cdn_headers;
+use crate::cdn_rules::ip_is_on_acl;
+
+use fastly::Request;
+
+/// User-Agent header of the crawler worker.
+const CRAWLER_WORKER_USER_AGENT: &str = "GOV.UK Crawler Worker";
+
+/// Check if a backend request is from the crawler worker, which makes
+/// the snapshots the mirrors serve: it has the crawler User-Agent, and
+/// comes from an IP on the crawler ACL.  Anyone can send the
+/// User-Agent, so with an empty ACL nothing is the crawler worker.
+///
+/// Crawler requests get no session, no A/B test cookies, and no
+/// cookies at all, so the snapshots are the same for everyone.
+pub fn is_crawler_worker(settings: &Config, bereq: &Request) -> bool {
+    is_on_crawler_acl(&settings.acl_crawler, bereq)
+}
+
+/// Check if a backend request has the crawler User-Agent and comes
+/// from an IP on an ACL.
+fn is_on_crawler_acl(acl: &Acl, bereq: &Request) -> bool {
+    if bereq.get_header_str("User-Agent") != Some(CRAWLER_WORKER_USER_AGENT) {
+        return false;
+    }
+
+    match cdn_headers::client_ip(bereq) {
+        Some(client_ip) => ip_is_on_acl(acl, &client_ip, false),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crawler_bereq(client_ip: &str) -> Request {
+        Request::get("https://www.gov.uk/")
+            .with_header("User-Agent", CRAWLER_WORKER_USER_AGENT)
+            .with_header("Fastly-Client-IP", client_ip)
+    }
+
+    fn acl(cidr: &str) -> Acl {
+        let mut acl = Acl::default();
+        acl.ipv4.add(cidr.parse().unwrap());
+        acl
+    }
+
+    #[test]
+    fn crawler_needs_user_agent_and_acl() {
+        let acl = acl("203.0.113.0/24");
+        assert!(is_on_crawler_acl(&acl, &crawler_bereq("203.0.113.7")));
+        assert!(!is_on_crawler_acl(&acl, &crawler_bereq("198.51.100.7")));
+
+        let bereq = crawler_bereq("203.0.113.7").with_header("User-Agent", "Mozilla/5.0");
+        assert!(!is_on_crawler_acl(&acl, &bereq));
+    }
+
+    #[test]
+    fn empty_acl_has_no_crawler() {
+        assert!(!is_on_crawler_acl(
+            &Acl::default(),
+            &crawler_bereq("203.0.113.7")
+        ));
+    }
+
+    #[test]
+    fn missing_client_ip_is_not_crawler() {
+        let bereq = Request::get("https://www.gov.uk/")
+            .with_header("User-Agent", CRAWLER_WORKER_USER_AGENT);
+        assert!(!is_on_crawler_acl(&acl("203.0.113.0/24"), &bereq));
+    }
+}
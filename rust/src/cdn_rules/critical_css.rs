@@ -0,0 +1,123 @@
+use crate::cdn_config::Config;
+use crate::cdn_rules::accounts;
+
+use fastly::{Body, Response};
+use std::io::{BufRead, Write};
+
+/// The `rel` of a stylesheet link
+const STYLESHEET_REL: &str = r#"rel="stylesheet""#;
+
+/// The `rel` (and handler) which preloads a stylesheet, and applies it
+/// once it has loaded
+const ASYNC_STYLESHEET_REL: &str =
+    r#"rel="preload" as="style" onload="this.onload=null;this.rel='stylesheet'""#;
+
+/// Inline the `critical_css.css` snippet into the `<head>` of an HTML
+/// page, and load the stylesheet whose URL starts with
+/// `critical_css.stylesheet` asynchronously, so the page can be drawn
+/// before the full stylesheet arrives.  Browsers without JavaScript
+/// still load the stylesheet normally, from a `<noscript>` copy of the
+/// link.
+///
+/// Bodies which can't be transformed (see `accounts::can_transform`)
+/// are left alone.
+pub fn transform_beresp(settings: &Config, mut beresp: Response, degraded: bool) -> Response {
+    let css = match &settings.critical_css {
+        Some(css) => css,
+        None => return beresp,
+    };
+    if !accounts::has_mime_type(&beresp, "text/html") || !accounts::can_transform(&beresp, degraded)
+    {
+        return beresp;
+    }
+
+    let body = beresp.take_body();
+    let mut transformed_body = Body::new();
+    rewrite(
+        body,
+        &mut transformed_body,
+        css,
+        &settings.critical_css_stylesheet,
+    );
+    beresp.with_body(transformed_body)
+}
+
+/// Copy an HTML body, adding a `<style>` holding `css` before the first
+/// `</head>`, and making the `<link>`s to `stylesheet` asynchronous,
+/// line by line.
+///
+/// Lines which aren't valid UTF-8 are passed through byte-for-byte.
+pub fn rewrite(input: impl BufRead, output: &mut impl Write, css: &str, stylesheet: &str) {
+    let mut inlined = false;
+    accounts::rewrite_lines(input, output, |line| {
+        let rewritten = rewrite_stylesheet_links(line, stylesheet);
+        if !inlined && rewritten.contains("</head>") {
+            inlined = true;
+            rewritten.replacen("</head>", &format!("<style>{}</style></head>", css), 1)
+        } else {
+            rewritten
+        }
+    });
+}
+
+/// Replace each `<link rel="stylesheet">` to `stylesheet` in a line
+/// with one which preloads it, followed by the original in a
+/// `<noscript>`.
+fn rewrite_stylesheet_links(line: &str, stylesheet: &str) -> String {
+    let href = format!(r#"href="{}"#, stylesheet);
+    let mut rewritten = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("<link") {
+        let end = match rest[start..].find('>') {
+            Some(offset) => start + offset + 1,
+            None => break,
+        };
+        let tag = &rest[start..end];
+        rewritten.push_str(&rest[..start]);
+        if tag.contains(STYLESHEET_REL) && tag.contains(&href) {
+            rewritten.push_str(&tag.replacen(STYLESHEET_REL, ASYNC_STYLESHEET_REL, 1));
+            rewritten.push_str("<noscript>");
+            rewritten.push_str(tag);
+            rewritten.push_str("</noscript>");
+        } else {
+            rewritten.push_str(tag);
+        }
+        rest = &rest[end..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite_str(input: &str) -> String {
+        let mut output = Vec::new();
+        rewrite(
+            input.as_bytes(),
+            &mut output,
+            "body{margin:0}",
+            "/assets/application-",
+        );
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn inlines_css_before_the_end_of_the_head() {
+        assert_eq!(
+            rewrite_str("<head>\n<title>GOV.UK</title>\n</head>\n<body></body>\n"),
+            "<head>\n<title>GOV.UK</title>\n<style>body{margin:0}</style></head>\n<body></body>\n"
+        );
+    }
+
+    #[test]
+    fn only_rewrites_the_main_stylesheet() {
+        assert_eq!(
+            rewrite_str(
+                r#"<link rel="stylesheet" href="/assets/application-123.css"><link rel="stylesheet" href="/assets/print.css">"#
+            ),
+            r#"<link rel="preload" as="style" onload="this.onload=null;this.rel='stylesheet'" href="/assets/application-123.css"><noscript><link rel="stylesheet" href="/assets/application-123.css"></noscript><link rel="stylesheet" href="/assets/print.css">"#
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_rules::signing;
+
+use fastly::{Request, Response};
+use rand::Rng;
+use std::collections::HashMap;
+
+/// Cookie holding the signed CSRF token.  This is not `httponly`, as
+/// the page needs to copy it into the form.
+const CSRF_COOKIE_NAME: &str = "govuk_csrf_token";
+
+/// Request header the token can be submitted in
+const CSRF_HEADER_NAME: &str = "GOVUK-CSRF-Token";
+
+/// Form field the token can be submitted in
+const CSRF_FIELD_NAME: &str = "csrf_token";
+
+/// Reject a POST to a protected path unless it submits the same
+/// (validly signed) token as is in the CSRF cookie.
+pub fn verify(
+    settings: &Config,
+    cookies: &HashMap<String, String>,
+    req: &mut Request,
+) -> Option<Response> {
+    if req.get_method_str() != "POST" || !is_protected(settings, req.get_path()) {
+        return None;
+    }
+
+    let secret = signing::get_secret(settings, &settings.csrf_secret);
+    let cookie_token = cookies.get(CSRF_COOKIE_NAME);
+    let is_valid = match (&secret, cookie_token) {
+        (Some(secret), Some(cookie_token)) if is_signed(secret, cookie_token) => {
+            submitted_token(req).as_ref() == Some(cookie_token)
+        }
+        _ => false,
+    };
+
+    if is_valid {
+        None
+    } else {
+        Some(
+            Response::from_status(403)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Csrf.as_str())
+                .with_header("Cache-Control", "no-store"),
+        )
+    }
+}
+
+/// Issue a new token when a protected form page is fetched without a
+/// valid one.
+pub fn transform_beresp(
+    settings: &Config,
+    bereq: &Request,
+    cookies: &HashMap<String, String>,
+    mut resp: Response,
+) -> Response {
+    if bereq.get_method_str() != "GET" || !is_protected(settings, bereq.get_path()) {
+        return resp;
+    }
+
+    if let Some(secret) = signing::get_secret(settings, &settings.csrf_secret) {
+        let has_valid_token = cookies
+            .get(CSRF_COOKIE_NAME)
+            .map_or(false, |token| is_signed(&secret, token));
+        if !has_valid_token {
+            resp.append_header(
+                "Set-Cookie",
+                format!(
+                    "{}={}; secure; samesite=strict; path=/",
+                    CSRF_COOKIE_NAME,
+                    new_token(&secret)
+                ),
+            );
+        }
+    }
+
+    resp
+}
+
+/// Check if a path is a protected form.
+fn is_protected(settings: &Config, path: &str) -> bool {
+    settings
+        .csrf_paths
+        .iter()
+        .any(|protected| protected == path)
+}
+
+/// Generate a new signed token, of the form `<nonce>.<signature>`.
+fn new_token(secret: &str) -> String {
+    let nonce: [u8; 16] = rand::thread_rng().gen();
+    let nonce = signing::to_hex(&nonce);
+    let signature = signing::sign(secret, &nonce);
+    format!("{}.{}", nonce, signature)
+}
+
+/// Check if a token has a valid signature.
+fn is_signed(secret: &str, token: &str) -> bool {
+    match token.find('.') {
+        Some(index) => signing::verify(secret, &token[..index], &token[index + 1..]),
+        None => false,
+    }
+}
+
+/// Get the token submitted with a request, from the header or (for
+/// urlencoded forms) the form field.
+fn submitted_token(req: &mut Request) -> Option<String> {
+    if let Some(token) = req.get_header_str(CSRF_HEADER_NAME) {
+        return Some(token.to_string());
+    }
+
+    let is_form = match req.get_content_type() {
+        Some(mime) => mime.essence_str() == "application/x-www-form-urlencoded",
+        None => false,
+    };
+    if !is_form {
+        return None;
+    }
+
+    let body = req.take_body_bytes();
+    let token = form_urlencoded::parse(&body)
+        .find(|(name, _)| name == CSRF_FIELD_NAME)
+        .map(|(_, value)| value.into_owned());
+    req.set_body(body);
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_tokens_are_signed() {
+        let token = new_token("secret");
+        assert!(is_signed("secret", &token));
+        assert!(!is_signed("other secret", &token));
+        assert_ne!(new_token("secret"), token);
+    }
+
+    #[test]
+    fn tampered_tokens_are_not_signed() {
+        let token = new_token("secret");
+        let index = token.find('.').unwrap();
+        let nonce = signing::to_hex(&[0; 16]);
+        assert!(!is_signed(
+            "secret",
+            &format!("{}{}", nonce, &token[index..])
+        ));
+        assert!(!is_signed("secret", &token[..index]));
+        assert!(!is_signed("secret", ""));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::cdn_config::{CompressionDictionaryConfig, Config};
+
+use fastly::http::header;
+use fastly::{Request, Response};
+
+/// Request header naming the SHA-256 of the dictionary the client has
+const AVAILABLE_DICTIONARY_HEADER_NAME: &str = "Available-Dictionary";
+
+/// Response header telling the client to keep a response as a
+/// dictionary
+const USE_AS_DICTIONARY_HEADER_NAME: &str = "Use-As-Dictionary";
+
+/// Content encodings which use a shared dictionary (brotli and zstd)
+const DICTIONARY_ENCODINGS: &[&str] = &["dcb", "dcz"];
+
+/// If the client has a dictionary for this path and accepts a
+/// dictionary-compressed response, ask the origin for one.
+///
+/// Otherwise, don't tell the origin about the dictionary.
+pub fn transform_bereq(settings: &Config, bereq: &mut Request) {
+    negotiate(&settings.compression_dictionaries, bereq);
+}
+
+/// Negotiate a dictionary-compressed response against some
+/// dictionaries.
+fn negotiate(dictionaries: &[CompressionDictionaryConfig], bereq: &mut Request) {
+    let accepted = bereq
+        .get_header_str(header::ACCEPT_ENCODING)
+        .unwrap_or("")
+        .split(',')
+        .map(|encoding| encoding.split(';').next().unwrap_or("").trim())
+        .filter(|encoding| DICTIONARY_ENCODINGS.contains(encoding))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let negotiated = !accepted.is_empty()
+        && bereq.contains_header(AVAILABLE_DICTIONARY_HEADER_NAME)
+        && dictionary_for_path(dictionaries, bereq.get_path()).is_some();
+
+    if negotiated {
+        bereq.set_header(header::ACCEPT_ENCODING, accepted);
+    } else {
+        bereq.remove_header(AVAILABLE_DICTIONARY_HEADER_NAME);
+    }
+}
+
+/// Check if a dictionary-compressed response has been asked for.
+pub fn is_negotiated(bereq: &Request) -> bool {
+    bereq.contains_header(AVAILABLE_DICTIONARY_HEADER_NAME)
+}
+
+/// Tell the client to keep dictionary resources, and that responses
+/// on dictionary-compressed paths vary by the dictionary.
+pub fn transform_beresp(settings: &Config, bereq: &Request, mut resp: Response) -> Response {
+    let path = bereq.get_path();
+
+    if let Some(dictionary) = settings
+        .compression_dictionaries
+        .iter()
+        .find(|dictionary| dictionary.path == path)
+    {
+        resp.set_header(
+            USE_AS_DICTIONARY_HEADER_NAME,
+            format!("match=\"{}\"", dictionary.match_pattern),
+        );
+    }
+
+    if dictionary_for_path(&settings.compression_dictionaries, path).is_some() {
+        resp.append_header(header::VARY, AVAILABLE_DICTIONARY_HEADER_NAME);
+        resp.append_header(header::VARY, "Accept-Encoding");
+    }
+
+    resp
+}
+
+/// Find the dictionary which applies to a path, if there is one.
+///
+/// Match patterns are a path, optionally ending in `*`.
+fn dictionary_for_path<'a>(
+    dictionaries: &'a [CompressionDictionaryConfig],
+    path: &str,
+) -> Option<&'a CompressionDictionaryConfig> {
+    dictionaries.iter().find(|dictionary| {
+        let pattern = &dictionary.match_pattern;
+        if pattern.ends_with('*') {
+            path.starts_with(&pattern[..pattern.len() - 1])
+        } else {
+            path == pattern
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionaries() -> Vec<CompressionDictionaryConfig> {
+        vec![CompressionDictionaryConfig {
+            path: "/assets/dictionary.dat".to_string(),
+            match_pattern: "/assets/*".to_string(),
+        }]
+    }
+
+    fn bereq(path: &str, accept_encoding: &str) -> Request {
+        Request::get(format!("https://www.gov.uk{}", path))
+            .with_header(header::ACCEPT_ENCODING, accept_encoding)
+            .with_header(AVAILABLE_DICTIONARY_HEADER_NAME, ":hash:")
+    }
+
+    #[test]
+    fn negotiates_dictionary_encodings_only() {
+        let mut bereq = bereq("/assets/app.js", "gzip, dcb;q=0.9, br, dcz");
+        negotiate(&dictionaries(), &mut bereq);
+        assert!(is_negotiated(&bereq));
+        assert_eq!(
+            bereq.get_header_str(header::ACCEPT_ENCODING),
+            Some("dcb, dcz")
+        );
+    }
+
+    #[test]
+    fn does_not_negotiate_outside_dictionary_paths() {
+        let mut bereq = bereq("/government/app.js", "gzip, dcb");
+        negotiate(&dictionaries(), &mut bereq);
+        assert!(!is_negotiated(&bereq));
+        assert_eq!(
+            bereq.get_header_str(header::ACCEPT_ENCODING),
+            Some("gzip, dcb")
+        );
+    }
+
+    #[test]
+    fn does_not_negotiate_without_dictionary_encoding() {
+        let mut bereq = bereq("/assets/app.js", "gzip, br");
+        negotiate(&dictionaries(), &mut bereq);
+        assert!(!is_negotiated(&bereq));
+        assert_eq!(
+            bereq.get_header_str(header::ACCEPT_ENCODING),
+            Some("gzip, br")
+        );
+    }
+
+    #[test]
+    fn does_not_negotiate_without_available_dictionary() {
+        let mut bereq = Request::get("https://www.gov.uk/assets/app.js")
+            .with_header(header::ACCEPT_ENCODING, "dcb");
+        negotiate(&dictionaries(), &mut bereq);
+        assert!(!is_negotiated(&bereq));
+    }
+}
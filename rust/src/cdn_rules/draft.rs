@@ -0,0 +1,17 @@
+use crate::cdn_config::Config;
+
+use fastly::Response;
+use std::collections::HashMap;
+
+/// Check if the user has a signon session, which the draft origin
+/// checks for itself.
+pub fn has_signon_session(settings: &Config, cookies: &HashMap<String, String>) -> bool {
+    cookies.contains_key(&settings.draft_signon_cookie)
+}
+
+/// Draft content must never be mistaken for published content, so
+/// keep it out of search engines.
+pub fn transform_beresp(mut resp: Response) -> Response {
+    resp.set_header("X-Robots-Tag", "noindex");
+    resp
+}
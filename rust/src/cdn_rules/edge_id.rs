@@ -0,0 +1,61 @@
+use crate::cdn_config::Config;
+use crate::cdn_rules::ab_tests::has_consented_to_ab_tests;
+
+use fastly::{Request, Response};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Cookie holding the edge ID
+pub const EDGE_ID_COOKIE_NAME: &str = "govuk_edge_id";
+
+/// Request header carrying the edge ID to the origin
+pub const EDGE_ID_HEADER_NAME: &str = "GOVUK-Edge-Id";
+
+/// How long (in seconds) the cookie lasts
+const EDGE_ID_COOKIE_MAX_AGE: u32 = 60 * 60 * 24 * 365;
+
+/// Give users who have consented to usage cookies a persistent random
+/// ID, which A/B test assignment is based on, so the same user always
+/// gets the same variants.
+///
+/// The ID from the cookie is reused if it's valid.
+pub fn transform_bereq(settings: &Config, cookies: &HashMap<String, String>, bereq: &mut Request) {
+    if !has_consented_to_ab_tests(settings, cookies) {
+        bereq.remove_header(EDGE_ID_HEADER_NAME);
+        return;
+    }
+
+    let edge_id = cookies
+        .get(EDGE_ID_COOKIE_NAME)
+        .and_then(|value| Uuid::parse_str(value).ok())
+        .unwrap_or_else(Uuid::new_v4);
+
+    bereq.set_header(
+        EDGE_ID_HEADER_NAME,
+        edge_id
+            .to_hyphenated()
+            .encode_lower(&mut Uuid::encode_buffer())
+            .to_string(),
+    );
+}
+
+/// Set the cookie if the edge ID is new.
+pub fn transform_beresp(
+    bereq: &Request,
+    bereq_cookies: &HashMap<String, String>,
+    mut resp: Response,
+) -> Response {
+    if let Some(edge_id) = bereq.get_header_str(EDGE_ID_HEADER_NAME) {
+        if bereq_cookies.get(EDGE_ID_COOKIE_NAME).map(|v| v.as_str()) != Some(edge_id) {
+            resp.append_header(
+                "Set-Cookie",
+                format!(
+                    "{}={}; secure; samesite=lax; max-age={}; path=/",
+                    EDGE_ID_COOKIE_NAME, edge_id, EDGE_ID_COOKIE_MAX_AGE
+                ),
+            );
+        }
+    }
+
+    resp
+}
@@ -0,0 +1,159 @@
+use crate::cdn_config::{ABTestConfig, Config};
+use crate::cdn_rules::{log_invalid_experiment, send_before};
+
+use fastly::http::HeaderName;
+use fastly::Request;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// A fetched manifest, and when to fetch it again.
+type CachedManifest = (Instant, Rc<HashMap<String, ABTestConfig>>);
+
+thread_local! {
+    /// Manifests fetched by this instance, by the generation of the
+    /// configuration they were fetched for.
+    static MANIFESTS: RefCell<HashMap<usize, CachedManifest>> = RefCell::new(HashMap::new());
+}
+
+/// Get the A/B tests: those in `ab_tests`, added to and overridden by
+/// those in the experiments manifest, if `experiments.backend` is set.
+///
+/// This lets the experiments team launch tests without a CDN
+/// configuration deploy.  The manifest is cached (both by Fastly and
+/// by this instance) for `experiments.ttl_s`.  If it can't be fetched
+/// within `experiments.timeout_ms` or parsed, the last good copy is
+/// used, or failing that just `ab_tests`.  Invalid tests in the
+/// manifest are skipped.  Other configurations' copies are dropped
+/// once they're due a refresh, so those for configurations replaced by
+/// a reload don't pile up.
+pub fn ab_tests(settings: &Config) -> Cow<HashMap<String, ABTestConfig>> {
+    let backend = match &settings.experiments_backend {
+        Some(backend) => backend,
+        None => return Cow::Borrowed(&settings.ab_tests),
+    };
+
+    let key = settings.generation;
+    let now = Instant::now();
+    let manifest = MANIFESTS.with(|manifests| {
+        let mut manifests = manifests.borrow_mut();
+        match manifests.get(&key) {
+            Some((refresh_at, manifest)) if now < *refresh_at => Rc::clone(manifest),
+            cached => {
+                let manifest = match fetch_manifest(settings, backend) {
+                    Some(manifest) => Rc::new(manifest),
+                    None => cached.map_or_else(|| Rc::new(HashMap::new()), |(_, m)| Rc::clone(m)),
+                };
+                manifests.retain(|_, (refresh_at, _)| now < *refresh_at);
+                manifests.insert(key, (now + settings.experiments_ttl, Rc::clone(&manifest)));
+                manifest
+            }
+        }
+    });
+
+    if manifest.is_empty() {
+        return Cow::Borrowed(&settings.ab_tests);
+    }
+
+    let mut ab_tests = settings.ab_tests.clone();
+    for (name, ab_test) in manifest.iter() {
+        ab_tests.insert(name.clone(), ab_test.clone());
+    }
+    Cow::Owned(ab_tests)
+}
+
+/// Fetch and parse the experiments manifest: a JSON object of A/B
+/// tests, in the same format as `ab_tests`.  Invalid tests are logged
+/// and left out.
+fn fetch_manifest(settings: &Config, backend: &str) -> Option<HashMap<String, ABTestConfig>> {
+    let mut resp = send_before(
+        Request::get(&settings.experiments_url).with_ttl(settings.experiments_ttl.as_secs() as u32),
+        backend,
+        Instant::now() + settings.experiments_timeout,
+    )
+    .ok()?;
+    if !resp.get_status().is_success() {
+        return None;
+    }
+
+    let tests = match serde_json::from_str(&resp.take_body_str()).ok()? {
+        Value::Object(tests) => tests,
+        _ => return None,
+    };
+
+    let mut manifest = HashMap::new();
+    for (name, test) in tests.iter() {
+        match parse_ab_test(test) {
+            Some(ab_test) => {
+                manifest.insert(name.clone(), ab_test);
+            }
+            None => log_invalid_experiment(settings, name),
+        }
+    }
+    Some(manifest)
+}
+
+/// Parse an A/B test from the manifest.
+fn parse_ab_test(test: &Value) -> Option<ABTestConfig> {
+    let active = match test.get("active") {
+        Some(active) => active.as_bool()?,
+        None => false,
+    };
+    let expires = test.get("expires")?.as_i64()?;
+
+    let mut variants = HashMap::new();
+    for (name, weight) in test.get("variants")?.as_object()?.iter() {
+        variants.insert(name.clone(), weight.as_i64()?);
+    }
+
+    let crawler_variant = match test.get("crawler_variant") {
+        Some(variant) => variant.as_str()?.to_string(),
+        None => "A".to_string(),
+    };
+    if !variants.contains_key(&crawler_variant) {
+        return None;
+    }
+
+    let header_name = match test.get("header_name") {
+        Some(header_name) => {
+            let header_name = header_name.as_str()?;
+            HeaderName::from_bytes(header_name.as_bytes()).ok()?;
+            Some(header_name.to_string())
+        }
+        None => None,
+    };
+
+    Some(ABTestConfig {
+        active,
+        expires,
+        variants,
+        crawler_variant,
+        header_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn crawler_variant_defaults_to_a() {
+        let test = json!({ "expires": 86400, "variants": { "A": 50, "B": 50 } });
+        let ab_test = parse_ab_test(&test).expect("test should parse");
+        assert_eq!(ab_test.crawler_variant, "A");
+    }
+
+    #[test]
+    fn crawler_variant_must_be_a_variant() {
+        let test =
+            json!({ "expires": 86400, "variants": { "A": 50, "B": 50 }, "crawler_variant": "C" });
+        assert!(parse_ab_test(&test).is_none());
+
+        let test = json!({ "expires": 86400, "variants": { "X": 50, "Y": 50 } });
+        assert!(parse_ab_test(&test).is_none());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_rules::replay;
+
+use fastly::{Request, Response};
+use serde_json::json;
+
+/// Request header naming the backend (an origin or a mirror) to send
+/// the request to, skipping the failover ladder
+pub const FORCE_BACKEND_HEADER_NAME: &str = "GOVUK-Force-Backend";
+
+/// Check a `GOVUK-Force-Backend` header, which lets smoke tests probe
+/// each backend's health through the production edge.  It must name
+/// an origin or a mirror, and the request must be signed like an
+/// admin request (see `replay::verify`), with the backend name as the
+/// signed value.
+///
+/// Returns a 403 if the header is there but not valid.  Otherwise the
+/// header is left on the request, for `forced_backend`.
+pub fn recv(settings: &Config, req: &Request) -> Option<Response> {
+    let backend = req.get_header_str(FORCE_BACKEND_HEADER_NAME)?;
+
+    let error = if !is_known_backend(settings, backend) {
+        "unknown backend"
+    } else {
+        match replay::verify(settings, req, backend) {
+            Ok(()) => return None,
+            Err(error) => error,
+        }
+    };
+
+    Some(
+        Response::from_status(403)
+            .with_header("Content-Type", "application/json")
+            .with_header("Cache-Control", "no-store")
+            .with_header(
+                BACKEND_NAME_HEADER_NAME,
+                BackendNameTag::ForcedBackend.as_str(),
+            )
+            .with_body(json!({ "status": "error", "error": error }).to_string()),
+    )
+}
+
+/// Get the backend a request has been forced to, if any.  `recv` has
+/// already checked the header by the time the backend request is
+/// built.
+pub fn forced_backend(bereq: &Request) -> Option<String> {
+    bereq
+        .get_header_str(FORCE_BACKEND_HEADER_NAME)
+        .map(|backend| backend.to_string())
+}
+
+/// Check if a backend is one of the origins.
+pub fn is_origin(settings: &Config, backend: &str) -> bool {
+    settings
+        .origins
+        .iter()
+        .any(|origin| origin.backend == backend)
+}
+
+/// Check if a backend is one of the origins or the mirrors.
+fn is_known_backend(settings: &Config, backend: &str) -> bool {
+    is_origin(settings, backend)
+        || settings
+            .backends_mirrors
+            .iter()
+            .any(|mirror| mirror == backend)
+}
@@ -0,0 +1,225 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_clock;
+use crate::cdn_config::{Config, HeaderRuleConfig, ScheduleWindow};
+use crate::cdn_rules::{challenge, matches_path_pattern, paths};
+use chrono::{DateTime, Datelike, Utc, Weekday};
+use fastly::{Request, Response};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Apply the request header rules to a client request, returning a
+/// response if one of them redirects, denies, or challenges it.
+pub fn recv(
+    settings: &Config,
+    cookies: &HashMap<String, String>,
+    req: &mut Request,
+) -> Option<Response> {
+    for rule in settings.header_rules.iter().filter(|rule| !rule.response) {
+        if !matches(rule, req, cookies, None) {
+            continue;
+        }
+
+        if let Some(status) = rule.deny {
+            return Some(
+                Response::from_status(status)
+                    .with_header(
+                        BACKEND_NAME_HEADER_NAME,
+                        BackendNameTag::HeaderRule.as_str(),
+                    )
+                    .with_header("Cache-Control", "no-store"),
+            );
+        }
+
+        if rule.challenge
+            && challenge::is_enabled(settings)
+            && !challenge::is_solved(settings, cookies, req)
+        {
+            return Some(challenge::response(settings, req, 403));
+        }
+
+        if let Some(destination) = &rule.redirect {
+            return Some(Response::from_status(302).with_header("Location", destination));
+        }
+
+        for name in &rule.remove_headers {
+            req.remove_header(name);
+        }
+        for (name, value) in &rule.set_headers {
+            req.set_header(name, value);
+        }
+    }
+
+    None
+}
+
+/// Check if a request header rule says to pass the request.  This is
+/// checked when the backend request is built, so against the request
+/// as the other rules left it.
+pub fn passes(settings: &Config, cookies: &HashMap<String, String>, req: &Request) -> bool {
+    settings
+        .header_rules
+        .iter()
+        .any(|rule| !rule.response && rule.pass && matches(rule, req, cookies, None))
+}
+
+/// Get the origin backend a request header rule says to try first, if
+/// there is one.  Like `passes`, this is checked against the backend
+/// request.
+pub fn preferred_backend<'a>(
+    settings: &'a Config,
+    cookies: &HashMap<String, String>,
+    bereq: &Request,
+) -> Option<&'a str> {
+    settings
+        .header_rules
+        .iter()
+        .filter(|rule| !rule.response && matches(rule, bereq, cookies, None))
+        .find_map(|rule| rule.backend.as_deref())
+}
+
+/// Check if a response header rule says to show the maintenance
+/// banner.  This is checked against the backend response, before the
+/// body is transformed.
+pub fn shows_maintenance_banner(
+    settings: &Config,
+    bereq: &Request,
+    cookies: &HashMap<String, String>,
+    beresp: &Response,
+) -> bool {
+    settings.header_rules.iter().any(|rule| {
+        rule.response && rule.maintenance_banner && matches(rule, bereq, cookies, Some(beresp))
+    })
+}
+
+/// Apply the response header rules to a backend response.
+pub fn transform_beresp(
+    settings: &Config,
+    bereq: &Request,
+    cookies: &HashMap<String, String>,
+    mut beresp: Response,
+) -> Response {
+    for rule in settings.header_rules.iter().filter(|rule| rule.response) {
+        if !matches(rule, bereq, cookies, Some(&beresp)) {
+            continue;
+        }
+
+        for name in &rule.remove_headers {
+            beresp.remove_header(name);
+        }
+        for (name, value) in &rule.set_headers {
+            beresp.set_header(name, value);
+        }
+    }
+
+    beresp
+}
+
+/// Check if a rule applies to a request, with the rule's header
+/// condition checked against the response if there is one.
+fn matches(
+    rule: &HeaderRuleConfig,
+    req: &Request,
+    cookies: &HashMap<String, String>,
+    resp: Option<&Response>,
+) -> bool {
+    if let Some(pattern) = &rule.path {
+        if !matches_path_pattern(pattern, &paths::canonical(req.get_path())) {
+            return false;
+        }
+    }
+
+    if let Some(method) = &rule.method {
+        if !req.get_method_str().eq_ignore_ascii_case(method) {
+            return false;
+        }
+    }
+
+    if let Some((name, expected)) = &rule.header {
+        let value = match resp {
+            Some(resp) => resp.get_header(name.as_str()),
+            None => req.get_header(name.as_str()),
+        };
+        let actual = value.and_then(|value| value.to_str().ok());
+        if !value_matches(actual, expected) {
+            return false;
+        }
+    }
+
+    if let Some((name, expected)) = &rule.cookie {
+        if !value_matches(cookies.get(name).map(|value| value.as_str()), expected) {
+            return false;
+        }
+    }
+
+    if !rule.schedule.is_empty() {
+        let now = cdn_clock::now();
+        if !rule.schedule.iter().any(|window| in_window(window, now)) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Check if a time is in a schedule window.
+fn in_window(window: &ScheduleWindow, now: SystemTime) -> bool {
+    match window {
+        ScheduleWindow::Once { start, end } => *start <= now && now < *end,
+        ScheduleWindow::Weekly { days, from, until } => {
+            let now = DateTime::<Utc>::from(now);
+            let time = now.time();
+            let today = now.weekday();
+            let on = |day: Weekday| days.is_empty() || days.contains(&day);
+            if from < until {
+                on(today) && *from <= time && time < *until
+            } else {
+                (on(today) && *from <= time) || (on(today.pred()) && time < *until)
+            }
+        }
+    }
+}
+
+/// Check a header or cookie value against the expected one, if there
+/// is one.
+pub fn value_matches(actual: Option<&str>, expected: &Option<String>) -> bool {
+    match (actual, expected) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(actual), Some(expected)) => actual == expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str) -> HeaderRuleConfig {
+        HeaderRuleConfig {
+            path: Some(path.to_string()),
+            deny: Some(403),
+            ..HeaderRuleConfig::default()
+        }
+    }
+
+    #[test]
+    fn matches_path_patterns() {
+        let cookies = HashMap::new();
+        let req = Request::get("https://www.gov.uk/admin/users");
+        assert!(matches(&rule("/admin*"), &req, &cookies, None));
+        assert!(!matches(&rule("/admin"), &req, &cookies, None));
+        assert!(!matches(&rule("/other*"), &req, &cookies, None));
+    }
+
+    #[test]
+    fn matches_canonical_path() {
+        let cookies = HashMap::new();
+        for url in &[
+            "https://www.gov.uk//admin/users",
+            "https://www.gov.uk/%61dmin/users",
+            "https://www.gov.uk/admin//users",
+        ] {
+            let req = Request::get(*url);
+            assert!(matches(&rule("/admin/*"), &req, &cookies, None), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::cdn_config::Config;
+use crate::cdn_rules::{ip_is_on_acl, paths, signing};
+
+use fastly::Request;
+
+/// Check if a path is only for internal clients, by being under one of
+/// `internal_paths.prefixes`.  The path is canonicalised first (see
+/// `paths::canonical`), so `/api//internal/` or `/api/%69nternal/`,
+/// which origins route the same, don't get around the check.
+pub fn is_internal_path(settings: &Config, path: &str) -> bool {
+    let path = paths::canonical(path);
+    settings
+        .internal_paths_prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+/// Check if a request is for an internal path, and from a client which
+/// may not see it: one neither on `acl.internal` nor with an
+/// `Authorization: Bearer <token>` header matching the
+/// `internal_paths.secret` secret.  These get the usual synthetic 404,
+/// so outsiders can't tell the path exists.
+pub fn is_hidden(settings: &Config, req: &Request) -> bool {
+    if !is_internal_path(settings, req.get_path()) {
+        return false;
+    }
+
+    let on_acl = req.get_client_ip_addr().map_or(false, |client_ip| {
+        ip_is_on_acl(&settings.acl_internal, &client_ip, false)
+    });
+
+    !on_acl && !has_valid_token(settings, req)
+}
+
+/// Check if a request has the internal bearer token.
+pub fn has_valid_token(settings: &Config, req: &Request) -> bool {
+    let token = match req
+        .get_header_str("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) => token,
+        None => return false,
+    };
+
+    signing::get_secret(settings, &settings.internal_paths_secret)
+        .map_or(false, |secret| signing::secrets_match(&secret, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdn_config::ConfigBuilder;
+
+    #[test]
+    fn repeated_slashes_are_internal() {
+        let settings = ConfigBuilder::new().build();
+        assert!(is_internal_path(&settings, "/api/internal/x"));
+        assert!(is_internal_path(&settings, "/api//internal/x"));
+        assert!(is_internal_path(&settings, "//api///internal//x"));
+    }
+
+    #[test]
+    fn percent_encoded_letters_are_internal() {
+        let settings = ConfigBuilder::new().build();
+        assert!(is_internal_path(&settings, "/api/%69nternal/x"));
+        assert!(is_internal_path(
+            &settings,
+            "/%61%70%69/%69%6E%74%65%72%6E%61%6C/x"
+        ));
+        assert!(!is_internal_path(&settings, "/api/internals"));
+    }
+}
@@ -0,0 +1,18 @@
+use crate::cdn_config::Config;
+use crate::cdn_rand;
+
+use std::collections::HashMap;
+
+/// Decide whether a request should be handled by the legacy VCL
+/// service instead.
+///
+/// The rollout cookie pins a client to one implementation: "1" for
+/// the VCL service and "0" for this one.  Otherwise,
+/// `legacy.percentage` percent of requests go to the VCL service.
+pub fn is_legacy_request(settings: &Config, cookies: &HashMap<String, String>) -> bool {
+    match cookies.get(&settings.legacy_cookie).map(|v| v.as_str()) {
+        Some("1") => true,
+        Some("0") => false,
+        _ => cdn_rand::percent_chance(settings.legacy_percentage),
+    }
+}
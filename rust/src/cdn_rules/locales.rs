@@ -0,0 +1,75 @@
+use crate::cdn_config::Config;
+use crate::cdn_http;
+
+use fastly::http::header;
+use fastly::{Request, Response};
+use std::collections::HashMap;
+
+/// Cookie which turns off language-specific redirects
+const LOCALE_REDIRECT_COOKIE_NAME: &str = "govuk_locale_redirect";
+
+/// How long (in seconds) the cookie lasts
+const LOCALE_REDIRECT_COOKIE_MAX_AGE: u32 = 60 * 60 * 24 * 30;
+
+/// Redirect a campaign path to its language-specific version, if the
+/// client's most preferred language has one.
+///
+/// The redirect sets a cookie turning further redirects off, so that
+/// clients can follow a link back to the original page without being
+/// sent round in a loop.
+pub fn redirect(
+    settings: &Config,
+    cookies: &HashMap<String, String>,
+    req: &Request,
+) -> Option<Response> {
+    if cookies.get(LOCALE_REDIRECT_COOKIE_NAME).map(|v| v.as_str()) == Some("0") {
+        return None;
+    }
+
+    let path = req.get_path();
+    let rules = settings
+        .locale_redirects
+        .iter()
+        .filter(|rule| rule.path == path)
+        .collect::<Vec<_>>();
+    if rules.is_empty() {
+        return None;
+    }
+
+    let language = preferred_language(req.get_header_str(header::ACCEPT_LANGUAGE)?)?;
+    rules
+        .iter()
+        .find(|rule| rule.language == language)
+        .map(|rule| {
+            Response::from_status(302)
+                .with_header(header::LOCATION, &rule.destination)
+                .with_header(
+                    header::SET_COOKIE,
+                    format!(
+                        "{}=0; Path=/; Max-Age={}",
+                        LOCALE_REDIRECT_COOKIE_NAME, LOCALE_REDIRECT_COOKIE_MAX_AGE
+                    ),
+                )
+        })
+}
+
+/// Find the primary language subtag (eg, "uk" for "uk-UA") of the
+/// most preferred language in an Accept-Language header.
+pub fn preferred_language(accept_language: &str) -> Option<String> {
+    let mut best: Option<(f32, String)> = None;
+    for (tag, quality) in cdn_http::parse_quality_list(accept_language) {
+        if tag == "*" || quality <= 0.0 {
+            continue;
+        }
+
+        let is_better = match &best {
+            Some((best_quality, _)) => quality > *best_quality,
+            None => true,
+        };
+        if is_better {
+            let language = tag.split('-').next().unwrap_or(&tag).to_lowercase();
+            best = Some((quality, language));
+        }
+    }
+    best.map(|(_, language)| language)
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A small least-recently-used cache, for remembering per-client
+/// state across the requests an instance handles.
+///
+/// Eviction scans every entry, so this is only suitable for small
+/// capacities.
+pub struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    /// Create an empty cache holding up to `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Look up a key, computing and caching its value if it's not
+    /// there.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: &K, f: F) -> V {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.0 = tick;
+            return entry.1.clone();
+        }
+
+        let value = f();
+        self.insert(key, value.clone());
+        value
+    }
+
+    /// Cache a value, replacing the key's current value if it has one
+    /// and evicting the least recently used entry if the cache is
+    /// full.
+    pub fn insert(&mut self, key: &K, value: V) {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if !self.entries.contains_key(key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(key.clone(), (tick, value));
+    }
+
+    /// Replace the value of a key which is already cached.  Keys which
+    /// aren't cached are ignored.
+    pub fn replace(&mut self, key: &K, value: V) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.1 = value;
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::cdn_config::Config;
+
+use fastly::http::header;
+use fastly::Response;
+
+/// Content types which don't say what the body is, and so are
+/// overridden just like a missing one
+const GENERIC_CONTENT_TYPES: [&str; 2] = ["application/octet-stream", "binary/octet-stream"];
+
+/// Set the `Content-Type` of a response from the `mime_overrides`
+/// config, if the backend didn't send one (or sent a generic one).
+///
+/// `path` is the path the object was fetched from, which for the
+/// mirrors includes the `.html` suffix.  The longest matching suffix
+/// wins.
+pub fn set_default_content_type(settings: &Config, path: &str, beresp: &mut Response) {
+    let is_generic = match beresp.get_content_type() {
+        Some(mime) => GENERIC_CONTENT_TYPES.contains(&mime.essence_str()),
+        None => !beresp.contains_header(header::CONTENT_TYPE),
+    };
+    if !is_generic {
+        return;
+    }
+
+    let content_type = settings
+        .mime_overrides
+        .iter()
+        .filter(|(suffix, _)| path.ends_with(suffix.as_str()))
+        .max_by_key(|(suffix, _)| suffix.len())
+        .map(|(_, content_type)| content_type);
+
+    if let Some(content_type) = content_type {
+        beresp.set_header(header::CONTENT_TYPE, content_type);
+    }
+}
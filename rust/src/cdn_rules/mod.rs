@@ -1,19 +1,73 @@
+mod ab_safety;
 mod ab_tests;
-mod accounts;
+pub mod accounts;
+mod assets;
 mod backends;
+mod body_rules;
+mod cachebust;
+mod challenge;
+mod consent;
+mod crawler;
+mod critical_css;
+mod csrf;
+mod dictionaries;
+mod draft;
+mod edge_id;
+mod experiments;
+mod force_backend;
+mod header_rules;
+mod internal;
+mod legacy;
+mod locales;
+mod memo;
+mod mime;
+mod options;
+mod origin_redirects;
+mod pages;
+mod pagination;
+mod paths;
+mod pinning;
+mod priority;
+mod purge;
+mod query_allowlist;
+mod redirects;
+mod replay;
+mod reports;
+mod robots;
+mod search_limit;
+mod signing;
+pub mod simulate;
+mod spam;
+mod ssl;
 
-use crate::cdn_config::Config;
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_clock;
+use crate::cdn_config::{Acl, Config, MirrorConfig, OriginConfig};
+use crate::cdn_error::CdnError;
+use crate::cdn_headers::{
+    self, AB_TEST_PREVIEW_HEADER_NAME, FASTLY_FF_HEADER_NAME, REQUEST_ID_HEADER_NAME,
+    SHIELD_AUTH_HEADER_NAME,
+};
+use crate::cdn_logging;
+use crate::cdn_rand;
+use crate::cdn_rules::backends::ServedBy;
+use crate::cdn_rules::consent::Consent;
+use crate::cdn_rules::query_allowlist::UnknownParams;
 
+use fastly::geo::geo_lookup;
 use fastly::http::header;
-use fastly::http::request::SendError;
+use fastly::http::request::{PollResult, SendError};
+use fastly::http::{HeaderName, Method, StatusCode};
 use fastly::{Request, Response};
-use httpdate::fmt_http_date;
-use ipnet::Ipv4Net;
-use iprange::IpRange;
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::io::Read;
 use std::net::IpAddr;
-use std::time::SystemTime;
-use uuid::Uuid;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// HTML for a synthetic 404 response
 const SYNTHETIC_NOT_FOUND_RESPONSE: &str = r#"<!DOCTYPE html>
@@ -34,6 +88,25 @@ const SYNTHETIC_NOT_FOUND_RESPONSE: &str = r#"<!DOCTYPE html>
 </html>
 "#;
 
+/// HTML for a synthetic 404 response, in Welsh
+const SYNTHETIC_NOT_FOUND_RESPONSE_CY: &str = r#"<!DOCTYPE html>
+<html lang="cy">
+  <head>
+    <title>Croeso i GOV.UK</title>
+    <style>
+      body { font-family: Arial, sans-serif; margin: 0; }
+      header { background: black; }
+      h1 { color: white; font-size: 29px; margin: 0 auto; padding: 10px; max-width: 990px; }
+      p { color: black; margin: 30px auto; max-width: 990px; }
+    </style>
+  </head>
+  <body>
+    <header><h1>GOV.UK</h1></header>
+    <p>Ni allwn ddod o hyd i'r dudalen rydych chi'n chwilio amdani. Rhowch gynnig ar chwilio ar <a href="https://www.gov.uk/">GOV.UK</a>.</p>
+  </body>
+</html>
+"#;
+
 /// HTML for a synthetic 503 response
 const SYNTHETIC_SERVER_ERROR_RESPONSE: &str = r#"
 <!DOCTYPE html>
@@ -55,6 +128,52 @@ const SYNTHETIC_SERVER_ERROR_RESPONSE: &str = r#"
 </html>
 "#;
 
+/// HTML for a synthetic 503 response, in Welsh
+const SYNTHETIC_SERVER_ERROR_RESPONSE_CY: &str = r#"
+<!DOCTYPE html>
+<html lang="cy">
+  <head>
+    <title>Croeso i GOV.UK</title>
+    <style>
+      body { font-family: Arial, sans-serif; margin: 0; }
+      header { background: black; }
+      h1 { color: white; font-size: 29px; margin: 0 auto; padding: 10px; max-width: 990px; }
+      p { color: black; margin: 30px auto; max-width: 990px; }
+    </style>
+  </head>
+  <body>
+    <header><h1>GOV.UK</h1></header>
+    <p>Rydym yn cael anawsterau technegol. Rhowch gynnig arall arni yn nes ymlaen.</p>
+    <p>Gallwch <a href="/coronavirus">ddod o hyd i wybodaeth am y coronafeirws</a> ar GOV.UK.</p>
+  </body>
+</html>
+"#;
+
+/// HTML for a synthetic 429 response
+const SYNTHETIC_TOO_MANY_REQUESTS_RESPONSE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Welcome to GOV.UK</title>
+    <style>
+      body { font-family: Arial, sans-serif; margin: 0; }
+      header { background: black; }
+      h1 { color: white; font-size: 29px; margin: 0 auto; padding: 10px; max-width: 990px; }
+      p { color: black; margin: 30px auto; max-width: 990px; }
+    </style>
+  </head>
+  <body>
+    <header><h1>GOV.UK</h1></header>
+    <p>You've made a lot of searches in a short time. Please slow down, and try again in a minute.</p>
+  </body>
+</html>
+"#;
+
+/// Query parameter added to synthetic redirects, if enabled
+const REDIRECT_MARKER: &str = "src=edge-redirect";
+
+/// How often to check if a backend has responded
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
 /// When falling back to the mirrors, if a path doesn't have one of
 /// these suffixes, add ".html"
 const SUFFIXES: &[&str] = &[
@@ -64,9 +183,125 @@ const SUFFIXES: &[&str] = &[
     "xml", "xsd", "xslt", "zip",
 ];
 
+/// The root element of an S3 / GCS bucket listing
+const BUCKET_LISTING_MARKER: &[u8] = b"<ListBucketResult";
+
+/// The root element of an S3 / GCS error (eg, `NoSuchKey`, or
+/// `AccessDenied`, which S3 returns for missing keys if the bucket
+/// isn't listable)
+const BUCKET_ERROR_MARKER: &[u8] = b"<Error>";
+
+/// Prefixes of the response headers S3 and GCS add, which say nothing
+/// useful to clients
+const MIRROR_HEADER_PREFIXES: [&str; 3] = ["x-amz-", "x-goog-", "x-guploader-"];
+
+/// Proxy the request, untouched, to the legacy VCL service, if it
+/// has been picked for the rollout.
+///
+/// The response is returned untouched too, so the two implementations
+/// can be compared.
+pub fn legacy_response(settings: &Config, req: &mut Request) -> Option<Response> {
+    let cookies = get_cookies(
+        req.get_header(header::COOKIE)
+            .and_then(|value| value.to_str().ok()),
+    );
+
+    if legacy::is_legacy_request(settings, &cookies) {
+        match req.clone_with_body().send(&settings.legacy_backend) {
+            Ok(resp) => Some(resp),
+            Err(error) => {
+                let error = CdnError::Send {
+                    backend: settings.legacy_backend.clone(),
+                    error: error.to_string(),
+                };
+                error.log(settings);
+                Some(error.response(req))
+            }
+        }
+    } else {
+        None
+    }
+}
+
+/// Normalise the request path (see `paths::normalise`), returning a
+/// synthetic 400 if it looks like a traversal attempt.
+pub fn normalise_path(req: &mut Request) -> Option<Response> {
+    match paths::normalise(req.get_path()) {
+        Some(path) => {
+            if path != req.get_path() {
+                req.set_path(&path);
+            }
+            None
+        }
+        None => Some(
+            Response::from_status(400)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::BadPath.as_str())
+                .with_header("Cache-Control", "no-store"),
+        ),
+    }
+}
+
+/// Drop cookies which contain control characters, and drop the
+/// Cookie header entirely if it is bigger than `cookies.max_size`, so
+/// malformed cookies never reach the origin.
+///
+/// A malformed session cookie is logged, as it signs the user out.
+pub fn sanitise_cookies(settings: &Config, req: &mut Request) {
+    let header_bytes = match req.get_header(header::COOKIE) {
+        Some(value) => value.as_bytes().to_vec(),
+        None => return,
+    };
+
+    if header_bytes.len() > settings.cookies_max_size {
+        log_cookie_problem(settings, "oversized", None);
+        req.remove_header(header::COOKIE);
+        return;
+    }
+
+    let header_str = String::from_utf8_lossy(&header_bytes);
+    let (valid, session_malformed) = split_valid_cookies(&header_str);
+    if session_malformed {
+        log_cookie_problem(settings, "malformed", Some(accounts::ACCOUNT_COOKIE_NAME));
+    }
+
+    if valid.is_empty() {
+        req.remove_header(header::COOKIE);
+    } else {
+        req.set_header(header::COOKIE, valid.join("; "));
+    }
+}
+
+/// Split a Cookie header into its well-formed cookies, and whether
+/// the session cookie was malformed.
+fn split_valid_cookies(header_str: &str) -> (Vec<&str>, bool) {
+    let mut valid = Vec::new();
+    let mut session_malformed = false;
+    for kv in header_str.split(';') {
+        if kv
+            .chars()
+            .any(|c| c.is_control() || c == std::char::REPLACEMENT_CHARACTER)
+        {
+            let name = kv.split('=').next().unwrap_or("").trim();
+            session_malformed |= name == accounts::ACCOUNT_COOKIE_NAME;
+        } else if !kv.trim().is_empty() {
+            valid.push(kv.trim());
+        }
+    }
+    (valid, session_malformed)
+}
+
+/// Decisions about a path which only depend on the configuration.
+#[derive(Clone, Copy)]
+struct PathClass {
+    /// Whether the path gets a synthetic 404
+    not_found: bool,
+    /// Whether the path is fully personalised
+    personalised: bool,
+}
+
 /// Produce a synthetic response to this request, if appropriate.
 pub fn synthetic_response(settings: &Config, req: &Request) -> Option<Response> {
-    if let Some(client_ip) = req.get_client_ip_addr().clone() {
+    if let Some(client_ip) = req.get_client_ip_addr() {
         if !ip_is_on_acl(&settings.acl_allowlist, &client_ip, true) {
             return Some(Response::from_status(403));
         }
@@ -76,30 +311,163 @@ pub fn synthetic_response(settings: &Config, req: &Request) -> Option<Response>
         }
     }
 
+    if is_rejected_method(&settings, req.get_method_str()) {
+        log_rejected_method(&settings, req.get_method_str(), req.get_path());
+        return Some(
+            Response::from_status(405)
+                .with_header("Allow", options::allowed_methods(&settings))
+                .with_header(
+                    BACKEND_NAME_HEADER_NAME,
+                    BackendNameTag::MethodNotAllowed.as_str(),
+                ),
+        );
+    }
+
+    if let Some(response) = purge::purge(&settings, &req) {
+        log_purge_audit(&settings, &req, &response);
+        return Some(response);
+    }
+
+    if internal::is_hidden(&settings, &req) {
+        return Some(
+            synthetic_not_found_response(req)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::NotFound.as_str()),
+        );
+    }
+
     if !authorized(&settings, &req) {
         return Some(Response::from_status(401).with_header("WWW-Authenticate", "Basic"));
     }
 
-    if !req.contains_header("fastly-ssl") {
-        let mut url = req.get_url().clone();
-        url.set_scheme("https");
+    if let Some(response) = ssl::redirect(&settings, &req) {
+        return Some(response);
+    }
+
+    if let Some(response) = ab_tests::report(&settings, &req) {
+        return Some(response);
+    }
+
+    if let Some(response) = options::recv(&settings, &req) {
+        return Some(response);
+    }
+
+    if let Some(response) = force_backend::recv(&settings, &req) {
+        return Some(response);
+    }
+
+    if let Some(response) = pagination::recv(&settings, &req) {
+        return Some(response);
+    }
+
+    let cookies = get_cookies(req.get_header_str("cookie"));
+    if let Some(retry_after) = search_limit::check(&settings, &req)
+        .filter(|_| !challenge::is_solved(&settings, &cookies, &req))
+    {
+        log_search_rate_limited(&settings, req.get_query_str().unwrap_or(""), retry_after);
+        if challenge::is_enabled(&settings) {
+            return Some(challenge::response(&settings, &req, 429));
+        }
         return Some(
-            Response::from_status(301)
-                .with_header("Location", url.to_string())
-                .with_header("Fastly-Backend-Name", "force_ssl"),
+            Response::from_status(429)
+                .with_header(
+                    BACKEND_NAME_HEADER_NAME,
+                    BackendNameTag::SearchRateLimit.as_str(),
+                )
+                .with_header("Retry-After", retry_after.as_secs().to_string())
+                .with_header("Cache-Control", "no-store")
+                .with_body(pages::body(
+                    "too_many_requests",
+                    SYNTHETIC_TOO_MANY_REQUESTS_RESPONSE,
+                )),
+        );
+    }
+
+    if classify_path(&settings, req.get_url().path()).not_found {
+        return Some(
+            synthetic_not_found_response(req)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::NotFound.as_str()),
         );
     }
 
-    if is_special_not_found(&settings, req.get_url().path()) {
+    if is_embargoed(&settings, req.get_url().path()) {
         return Some(
-            Response::from_status(404)
-                .with_header("Fastly-Backend-Name", "force_not_found")
-                .with_body(SYNTHETIC_NOT_FOUND_RESPONSE),
+            synthetic_not_found_response(req)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Embargo.as_str())
+                .with_header("Cache-Control", "no-store"),
         );
     }
 
     if let Some(destination) = is_special_redirect(&settings, req.get_url().path()) {
-        return Some(Response::from_status(302).with_header("Location", destination));
+        log_redirect(
+            &settings,
+            "special_paths",
+            req.get_url().path(),
+            destination,
+        );
+        return Some(
+            Response::from_status(302)
+                .with_header("Location", mark_redirect(&settings, destination)),
+        );
+    }
+
+    if let Some(response) = locales::redirect(&settings, &cookies, &req) {
+        return Some(response);
+    }
+
+    if let Some(destination) = redirects::lookup(req.get_url().path()) {
+        log_redirect(&settings, "bulk", req.get_url().path(), destination);
+        return Some(
+            Response::from_status(301)
+                .with_header("Location", mark_redirect(&settings, destination)),
+        );
+    }
+
+    None
+}
+
+/// Collect reports (eg, CSP violations, network errors, or analytics
+/// beacons) sent by browsers.  These skip the authorization and SSL checks, as
+/// browsers send them without credentials.
+pub fn collect_report(settings: &Config, req: &mut Request) -> Option<Response> {
+    if let Some(response) = reports::csp(settings, req) {
+        return Some(response);
+    }
+
+    if let Some(response) = reports::nel(settings, req) {
+        return Some(response);
+    }
+
+    if let Some(response) = reports::beacon(settings, req) {
+        return Some(response);
+    }
+
+    None
+}
+
+/// Apply the request header rules, producing a synthetic response if
+/// one of them redirects or denies the request.
+pub fn apply_header_rules(settings: &Config, req: &mut Request) -> Option<Response> {
+    let cookies = get_cookies(req.get_header_str("cookie"));
+    header_rules::recv(settings, &cookies, req)
+}
+
+/// Produce a synthetic response to this request after inspecting its
+/// body, if appropriate.
+pub fn check_request_body(settings: &Config, req: &mut Request) -> Option<Response> {
+    let cookies = get_cookies(req.get_header_str("cookie"));
+
+    if let Some(response) = csrf::verify(settings, &cookies, req) {
+        return Some(response);
+    }
+
+    if let Some(reason) = spam::check(settings, req) {
+        log_form_spam(settings, req.get_path(), reason);
+        let status = if reason == spam::TOO_BIG { 413 } else { 422 };
+        return Some(
+            Response::from_status(status)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::FormSpam.as_str())
+                .with_header("Cache-Control", "no-store"),
+        );
     }
 
     None
@@ -107,159 +475,742 @@ pub fn synthetic_response(settings: &Config, req: &Request) -> Option<Response>
 
 /// Build the backend request.
 ///
-/// Returns `None` if the `Request` parameter is not a client request.
-pub fn build_bereq(settings: &Config, req: &mut Request) -> Option<Request> {
+/// Returns `CdnError::NoClientIp` if the `Request` parameter is not a
+/// client request.
+pub fn build_bereq(settings: &Config, req: &mut Request) -> Result<Request, CdnError> {
     if let Some(client_ip) = req.get_client_ip_addr() {
         let ip = client_ip.to_string();
         let method: String = req.get_method_str().to_string();
         let cookies: HashMap<String, String> = get_cookies(req.get_header_str("cookie"));
         let mut bereq = req.clone_with_body();
 
+        bereq.remove_header(FASTLY_FF_HEADER_NAME);
+        bereq.remove_header(SHIELD_AUTH_HEADER_NAME);
+        bereq.remove_header(AB_TEST_PREVIEW_HEADER_NAME);
         bereq.remove_header("Client-IP");
-        bereq.set_header("Fastly-Client-IP", ip.clone());
-        bereq.set_header("True-Client-IP", ip.clone());
-        bereq.set_header("X-Forwarded-For", ip.clone());
+        cdn_headers::set_client_ip(&mut bereq, &ip);
 
-        if method == "PURGE" && !ip_is_on_acl(&settings.acl_fastlypurge, &client_ip, false) {
-            bereq.set_header("Fastly-Purge-Requires-Auth", "1");
+        if settings.assets_profile {
+            assets::transform_bereq(&mut bereq);
         }
 
-        bereq.set_query(&normalise_querystring(&req));
+        let qs = normalise_querystring(&settings, &req);
+        if let Some(unknown) = query_allowlist::learn(&settings, req.get_path(), &qs) {
+            log_unknown_query_params(&settings, &unknown);
+        }
+        // Serialising a list of string pairs into a query string can't
+        // fail, so there's no error to handle here.
+        let _ = bereq.set_query(&qs);
 
         // https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L246
         // not sure how to do this - is this `req.set_stale_while_revalidate()` ?
 
-        bereq.set_header("Govuk-Use-Recommended-Related-Links", "true");
+        bereq.set_header(REQUEST_ID_HEADER_NAME, cdn_rand::request_id());
+        priority::set_headers(&settings, &mut bereq);
 
-        bereq.set_header(
-            "GOVUK-Request-Id",
-            Uuid::new_v4()
-                .to_hyphenated()
-                .encode_lower(&mut Uuid::encode_buffer())
-                .to_string(),
-        );
+        // todo https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L354
+
+        // setting surrogate keys on the backend request overrides a
+        // pass, so only decide whether to pass once everything else
+        // is done.
+        let mut pass = header_rules::passes(&settings, &cookies, req);
 
-        if let Some(expected) = &settings.basic_authorization {
-            bereq.set_header("Authorization", format!("Basic {}", expected));
+        if method != "HEAD" && method != "GET" && method != "PURGE" {
+            pass = true;
         }
 
-        // todo https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L354
+        if classify_path(&settings, req.get_path()).personalised {
+            pass = true;
+        }
 
-        if method != "HEAD" && method != "GET" && method != "PURGE" {
+        if cachebust::is_valid(&settings, &req) {
+            pass = true;
+        }
+
+        // a forced backend is being probed, so the cache must not
+        // answer for it.
+        if force_backend::forced_backend(&bereq).is_some() {
+            pass = true;
+        }
+
+        // previews aren't cached, so they can't leak to other users.
+        let preview = if settings.assets_profile {
+            None
+        } else {
+            ab_tests::preview(&settings, &req, &client_ip)
+        };
+        if preview.is_some() {
+            pass = true;
+        }
+
+        // never cache draft content: it changes constantly, and the
+        // origin decides who can see it.
+        if settings.draft_profile {
+            pass = true;
+        }
+
+        for (name, threshold) in &[
+            (header::COOKIE, settings.pass_cookie_size),
+            (header::AUTHORIZATION, settings.pass_authorization_size),
+        ] {
+            let size = bereq.get_header(name).map_or(0, |value| value.len());
+            if size > *threshold {
+                log_large_header(&settings, name.as_str(), size);
+                pass = true;
+            }
+        }
+
+        if !settings.assets_profile {
+            let crawler = crawler::is_crawler_worker(&settings, &bereq);
+            if crawler {
+                bereq.remove_header(header::COOKIE);
+            } else {
+                accounts::transform_bereq(&cookies, &mut bereq);
+            }
+            if !crawler {
+                edge_id::transform_bereq(&settings, &cookies, &mut bereq);
+            }
+            ab_tests::transform_bereq(&settings, &cookies, &mut bereq, crawler);
+            if let Some((name, variant)) = &preview {
+                ab_tests::set_preview(&settings, &mut bereq, name, variant);
+            }
+            if !pass {
+                ab_tests::set_surrogate_keys(&settings, &mut bereq);
+            }
+        }
+
+        if pass {
             bereq.set_pass(true);
         }
 
-        ab_tests::transform_bereq(&settings, &cookies, &mut bereq);
-        accounts::transform_bereq(&cookies, &mut bereq);
+        dictionaries::transform_bereq(&settings, &mut bereq);
 
-        Some(bereq)
+        Ok(bereq)
     } else {
-        None
+        Err(CdnError::NoClientIp)
+    }
+}
+
+/// Build the backend request on the shield tier.
+///
+/// The edge has already set the client headers and done the
+/// client-specific transforms, so this only needs to make sure
+/// uncacheable requests are passed.
+pub fn build_shield_bereq(req: &mut Request) -> Request {
+    let method: String = req.get_method_str().to_string();
+    let mut bereq = req.clone_with_body();
+
+    // the edge never sends requests for a forced backend via a shield.
+    bereq.remove_header(force_backend::FORCE_BACKEND_HEADER_NAME);
+
+    // the shield secret is only for this tier.
+    bereq.remove_header(SHIELD_AUTH_HEADER_NAME);
+
+    if !bereq.contains_header(REQUEST_ID_HEADER_NAME) {
+        bereq.set_header(REQUEST_ID_HEADER_NAME, cdn_rand::request_id());
+    }
+
+    if method != "HEAD" && method != "GET" && method != "PURGE" {
+        bereq.set_pass(true);
     }
+
+    bereq
 }
 
-/// Fetch the backend response, falling back to the mirrors if the
-/// origin is unavailable.
+/// Fetch the backend response, falling back to the other origins and
+/// then the mirrors if the nearest origin is unavailable.
 ///
-/// Returns `None` if the origin and all the mirrors fail.
-pub fn fetch_beresp(settings: &Config, mut bereq: Request) -> Option<Response> {
-    // fetch an uncompressed response, so that `transform_beresp` can handle it.
-    bereq.remove_header(header::ACCEPT_ENCODING);
+/// All the attempts share the `request_deadline` budget.
+///
+/// While monitoring has pinned requests to the mirrors, the origins are
+/// skipped (see `pinning`).
+///
+/// Returns `CdnError::AllBackendsFailed` if all the origins and all
+/// the mirrors fail, or the budget is exhausted.
+///
+/// A request with a (checked) `GOVUK-Force-Backend` header only goes
+/// to that backend, and returns `CdnError::Send` if it fails.
+pub fn fetch_beresp(settings: &Config, bereq: Request) -> Result<(Response, ServedBy), CdnError> {
+    if let Some(backend) = force_backend::forced_backend(&bereq) {
+        return fetch_beresp_forced(settings, bereq, &backend);
+    }
+
+    let pinned = pinning::is_pinned();
+
+    let variants = ab_tests::assigned_variants(settings, &bereq);
+    let started = Instant::now();
+    let result = fetch_beresp_from(settings, bereq, pinned);
+
+    if !variants.is_empty() {
+        let latency = started.elapsed();
+        let error = result.as_ref().map_or(true, |(beresp, served_by)| {
+            served_by.failover || beresp.get_status().is_server_error()
+        });
+        if cdn_rand::percent_chance(settings.ab_test_safety_log_percentage) {
+            for (name, variant) in variants {
+                log_ab_test_origin_response(settings, &name, &variant, latency, error);
+            }
+        }
+    }
+
+    result
+}
+
+/// Fetch the backend response from the origins (unless `pinned`) and
+/// then the mirrors.
+fn fetch_beresp_from(
+    settings: &Config,
+    mut bereq: Request,
+    pinned: bool,
+) -> Result<(Response, ServedBy), CdnError> {
+    // fetch an uncompressed response, so that `transform_beresp` can
+    // handle it, unless the client can use a dictionary-compressed one.
+    if !dictionaries::is_negotiated(&bereq) {
+        bereq.remove_header(header::ACCEPT_ENCODING);
+    }
 
     let original_bereq = bereq.clone_without_body();
+    let deadline = Instant::now() + settings.request_deadline;
+    let mut attempts = 0;
+    let mut errors = Vec::new();
+    let (fallback_path, index_path) = fallback_paths(bereq.get_path());
+
+    let origins = if pinned {
+        Vec::new()
+    } else {
+        origins_by_distance(settings, &bereq)
+    };
+
+    // without the secret the shield would reject the request, so go
+    // straight to the origins instead.
+    let shield_secret = if settings.shielding.is_empty() {
+        None
+    } else {
+        signing::get_secret(settings, &settings.shield_secret)
+    };
+
+    for origin in origins {
+        let shield = shield_for_origin(settings, &bereq, origin).zip(shield_secret.as_ref());
+        if let Some((shield_backend, shield_secret)) = shield {
+            let mut shield_bereq = bereq.clone_with_body();
+            append_fastly_ff(&mut shield_bereq);
+            shield_bereq.set_header(SHIELD_AUTH_HEADER_NAME, shield_secret);
+            attempts += 1;
+            match send_before(shield_bereq, shield_backend, deadline) {
+                Ok(mut beresp) if !beresp.get_status().is_server_error() => {
+                    let served_by = ServedBy::from_shield_response(
+                        &beresp,
+                        shield_backend,
+                        attempts,
+                        remaining_before(deadline),
+                    );
+                    served_by.set_headers(&mut beresp);
+                    return Ok((beresp, served_by));
+                }
+                Ok(beresp) => record_error(
+                    &mut errors,
+                    shield_backend,
+                    BackendError::ServerError(beresp.get_status()),
+                ),
+                Err(error) => record_error(&mut errors, shield_backend, error),
+            }
+        }
+
+        let mut origin_bereq = bereq.clone_with_body();
+        add_origin_headers(settings, &mut origin_bereq);
+        attempts += 1;
+        match send_before(origin_bereq, origin, deadline) {
+            Ok(mut beresp) if !beresp.get_status().is_server_error() => {
+                mime::set_default_content_type(settings, bereq.get_path(), &mut beresp);
+                let served_by = ServedBy {
+                    backend: BackendNameTag::Backend(origin.to_string()),
+                    failover: false,
+                    attempts,
+                    remaining: remaining_before(deadline),
+                };
+                served_by.set_headers(&mut beresp);
+                return Ok((beresp, served_by));
+            }
+            Ok(beresp) => record_error(
+                &mut errors,
+                origin,
+                BackendError::ServerError(beresp.get_status()),
+            ),
+            Err(error) => record_error(&mut errors, origin, error),
+        }
+    }
+
+    // todo https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L604
+    let mut bucket_errors = 0;
+    let mut mirror_failed = false;
+    for (index, backend_name) in settings.backends_mirrors.iter().enumerate() {
+        attempts += 1;
+        match fetch_beresp_fallback(
+            settings,
+            &original_bereq,
+            &fallback_path,
+            index_path.as_deref(),
+            backend_name,
+            &settings.backends_mirrors[index + 1..],
+            deadline,
+        ) {
+            Ok(mut beresp_fallback) if !beresp_fallback.get_status().is_server_error() => {
+                let served_by = ServedBy {
+                    backend: BackendNameTag::Backend(backend_name.to_string()),
+                    failover: true,
+                    attempts,
+                    remaining: remaining_before(deadline),
+                };
+                served_by.set_headers(&mut beresp_fallback);
+                return Ok((beresp_fallback, served_by));
+            }
+            Ok(beresp_fallback) => {
+                mirror_failed = true;
+                record_error(
+                    &mut errors,
+                    backend_name,
+                    BackendError::ServerError(beresp_fallback.get_status()),
+                );
+            }
+            Err(BackendError::BucketError) => {
+                bucket_errors += 1;
+                record_error(&mut errors, backend_name, BackendError::BucketError);
+            }
+            Err(error) => {
+                mirror_failed = true;
+                record_error(&mut errors, backend_name, error);
+            }
+        }
+    }
+
+    // if every mirror said the object isn't there, the page doesn't
+    // exist; but if any failed some other way, the page may only be
+    // missing because that mirror is down.
+    if bucket_errors > 0 && !mirror_failed {
+        let mut resp = synthetic_not_found_response(&original_bereq);
+        let served_by = ServedBy {
+            backend: BackendNameTag::NotFound,
+            failover: true,
+            attempts,
+            remaining: remaining_before(deadline),
+        };
+        served_by.set_headers(&mut resp);
+        return Ok((resp, served_by));
+    }
 
-    let mut fallback_path = bereq
-        .get_path()
-        .split("/")
+    Err(CdnError::AllBackendsFailed { attempts, errors })
+}
+
+/// Fetch the backend response from the one backend a request has been
+/// forced to, with no failover.
+fn fetch_beresp_forced(
+    settings: &Config,
+    mut bereq: Request,
+    backend: &str,
+) -> Result<(Response, ServedBy), CdnError> {
+    bereq.remove_header(force_backend::FORCE_BACKEND_HEADER_NAME);
+    if !dictionaries::is_negotiated(&bereq) {
+        bereq.remove_header(header::ACCEPT_ENCODING);
+    }
+
+    let deadline = Instant::now() + settings.request_deadline;
+    let failover = !force_backend::is_origin(settings, backend);
+    let result = if failover {
+        let (fallback_path, index_path) = fallback_paths(bereq.get_path());
+        fetch_beresp_fallback(
+            settings,
+            &bereq,
+            &fallback_path,
+            index_path.as_deref(),
+            backend,
+            &[],
+            deadline,
+        )
+    } else {
+        let path = bereq.get_path().to_string();
+        add_origin_headers(settings, &mut bereq);
+        send_before(bereq, backend, deadline).map(|mut beresp| {
+            mime::set_default_content_type(settings, &path, &mut beresp);
+            beresp
+        })
+    };
+
+    let mut beresp = result.map_err(|error| CdnError::Send {
+        backend: backend.to_string(),
+        error: error.to_string(),
+    })?;
+    let served_by = ServedBy {
+        backend: BackendNameTag::Backend(backend.to_string()),
+        failover,
+        attempts: 1,
+        remaining: remaining_before(deadline),
+    };
+    served_by.set_headers(&mut beresp);
+    Ok((beresp, served_by))
+}
+
+/// Get the path of a request's object on the mirrors, and (for
+/// directory URLs) the path for mirrors with `directory_index` set,
+/// which store `/foo/` as `foo/index.html`, rather than `foo.html`.
+fn fallback_paths(path: &str) -> (String, Option<String>) {
+    let mut fallback_path = path
+        .split('/')
         .filter(|s| !s.is_empty())
         .collect::<Vec<_>>()
         .join("/");
-    if fallback_path == "" || fallback_path == "/" {
+    if fallback_path.is_empty() || fallback_path == "/" {
         fallback_path = "/index.html".to_string();
     }
 
-    match bereq.send(backends::ORIGIN) {
-        Ok(beresp) if !beresp.get_status().is_server_error() => {
-            Some(beresp.with_header("Fastly-Backend-Name", backends::ORIGIN))
-        }
-        _ => {
-            if !SUFFIXES.iter().any(|suff| fallback_path.ends_with(suff)) {
-                fallback_path = format!("{}.html", fallback_path);
-            }
+    let index_path = if path.ends_with('/') && fallback_path != "/index.html" {
+        Some(format!("{}/index.html", fallback_path))
+    } else {
+        None
+    };
 
-            // todo https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L604
-            match fetch_beresp_fallback(
-                settings,
-                &original_bereq,
-                &fallback_path,
-                backends::FALLBACK1,
-            ) {
-                Ok(beresp_fallback) if !beresp_fallback.get_status().is_server_error() => {
-                    Some(beresp_fallback)
-                }
-                _ => match fetch_beresp_fallback(
-                    settings,
-                    &original_bereq,
-                    &fallback_path,
-                    backends::FALLBACK2,
-                ) {
-                    Ok(beresp_fallback) if !beresp_fallback.get_status().is_server_error() => {
-                        Some(beresp_fallback)
-                    }
-                    _ => match fetch_beresp_fallback(
-                        settings,
-                        &original_bereq,
-                        &fallback_path,
-                        backends::FALLBACK3,
-                    ) {
-                        Ok(beresp_fallback) if !beresp_fallback.get_status().is_server_error() => {
-                            Some(beresp_fallback)
-                        }
-                        _ => None,
-                    },
-                },
-            }
-        }
+    if !SUFFIXES.iter().any(|suff| fallback_path.ends_with(suff)) {
+        fallback_path = format!("{}.html", fallback_path);
     }
+
+    (fallback_path, index_path)
+}
+
+/// Generate a redirect an origin has created with `X-Edge-Redirect`.
+fn origin_redirect_response(settings: &Config, destination: &str) -> Response {
+    Response::from_status(301)
+        .with_header("Location", mark_redirect(settings, destination))
+        .with_header(
+            "Cache-Control",
+            format!("max-age={}", settings.origin_redirects_max_age),
+        )
+        .with_header(
+            BACKEND_NAME_HEADER_NAME,
+            BackendNameTag::OriginRedirect.as_str(),
+        )
 }
 
-/// Generate a synthetic 503 response.  Used if all else fails.
-pub fn synthetic_error_response() -> Response {
-    Response::from_status(503)
-        .with_header("Fastly-Backend-Name", "error")
-        .with_body(SYNTHETIC_SERVER_ERROR_RESPONSE)
+/// Generate a synthetic 503 response, in Welsh or English.  Used if
+/// all else fails.
+pub fn synthetic_error_response(req: &Request) -> Response {
+    synthetic_page(
+        req,
+        503,
+        "server_error",
+        SYNTHETIC_SERVER_ERROR_RESPONSE,
+        SYNTHETIC_SERVER_ERROR_RESPONSE_CY,
+    )
+    .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Error.as_str())
+}
+
+/// Generate a synthetic 404 response, in Welsh or English.
+fn synthetic_not_found_response(req: &Request) -> Response {
+    synthetic_page(
+        req,
+        404,
+        "not_found",
+        SYNTHETIC_NOT_FOUND_RESPONSE,
+        SYNTHETIC_NOT_FOUND_RESPONSE_CY,
+    )
+}
+
+/// Generate a synthetic page in the language the client wants (see
+/// `pages::language`), from the `synthetic_pages` dictionary or the
+/// compiled-in English or Welsh fallback.
+fn synthetic_page(req: &Request, status: u16, name: &str, english: &str, welsh: &str) -> Response {
+    let language = pages::language(req);
+    let fallback = if language == pages::WELSH {
+        welsh
+    } else {
+        english
+    };
+
+    Response::from_status(status)
+        .with_header(header::CONTENT_LANGUAGE, language)
+        .with_header(header::VARY, "Accept-Language")
+        .with_body(pages::localised_body(name, language, fallback))
 }
 
 /// Transform the response body.
-pub fn transform_beresp(settings: &Config, bereq: &Request, beresp: Response) -> Response {
-    let bereq_cookies = get_cookies(bereq.get_header_str("cookie"));
-    accounts::transform_beresp(
+///
+/// If the response came from a mirror, the session-dependent
+/// transforms are skipped: the mirrors don't know about sessions or
+/// A/B tests, and only serve the logged-out version of each page.
+pub fn transform_beresp(
+    settings: &Config,
+    bereq: &Request,
+    beresp: Response,
+    served_by: &ServedBy,
+) -> Response {
+    if let Some(destination) = origin_redirects::from_beresp(settings, &beresp) {
+        log_redirect(settings, "origin", bereq.get_path(), &destination);
+        return origin_redirect_response(settings, &destination);
+    }
+
+    let degraded = served_by.remaining < settings.degrade_threshold;
+    if degraded {
+        log_degraded(settings, bereq.get_path(), served_by.remaining);
+    }
+
+    let beresp = replace_origin_not_found(settings, bereq, beresp, served_by);
+
+    let resp = if settings.assets_profile {
+        assets::transform_beresp(settings, bereq, beresp)
+    } else {
+        let bereq_cookies = get_cookies(bereq.get_header_str("cookie"));
+        let fallback = served_by.failover;
+        let stale = fallback && is_stale_mirror_copy(settings, &beresp);
+        let crawler = crawler::is_crawler_worker(settings, bereq);
+        let maintenance =
+            header_rules::shows_maintenance_banner(settings, bereq, &bereq_cookies, &beresp);
+        let resp =
+            ab_tests::transform_beresp(settings, bereq, beresp, &bereq_cookies, fallback, crawler);
+        let replacements = body_rules::replacements(settings, bereq, &bereq_cookies, fallback);
+        let mut resp = accounts::transform_beresp(
+            bereq,
+            critical_css::transform_beresp(settings, resp, degraded),
+            &replacements,
+            fallback,
+            stale,
+            degraded,
+            maintenance,
+        );
+        if stale {
+            resp.set_header(header::WARNING, "110 - \"Response is Stale\"");
+        }
+        let resp = csrf::transform_beresp(settings, bereq, &bereq_cookies, resp);
+        let mut resp = edge_id::transform_beresp(bereq, &bereq_cookies, resp);
+        if crawler {
+            resp.remove_header(header::SET_COOKIE);
+        }
+        resp
+    };
+
+    let resp = if settings.draft_profile {
+        draft::transform_beresp(resp)
+    } else {
+        resp
+    };
+    let resp = robots::transform_beresp(settings, bereq, resp);
+    let resp = reports::transform_beresp(settings, resp);
+    let resp = purge::transform_beresp(settings, bereq, resp);
+    let resp = header_rules::transform_beresp(
+        settings,
         bereq,
-        ab_tests::transform_beresp(settings, bereq, beresp, &bereq_cookies),
+        &get_cookies(bereq.get_header_str("cookie")),
+        resp,
+    );
+
+    dictionaries::transform_beresp(settings, bereq, resp)
+}
+
+/// Replace the body of a 404 from origin with the synthetic 404 page,
+/// for paths under `special_paths.replace_not_found`.  The headers
+/// saying which backend served it, and origin's `Cache-Control`, are
+/// kept.
+fn replace_origin_not_found(
+    settings: &Config,
+    bereq: &Request,
+    beresp: Response,
+    served_by: &ServedBy,
+) -> Response {
+    let replace = beresp.get_status() == StatusCode::NOT_FOUND
+        && !served_by.failover
+        && settings
+            .replace_not_found_prefixes
+            .iter()
+            .any(|prefix| bereq.get_path().starts_with(prefix));
+    if !replace {
+        return beresp;
+    }
+
+    let mut resp = synthetic_not_found_response(bereq);
+    served_by.set_headers(&mut resp);
+    for value in beresp.get_header_all(header::CACHE_CONTROL) {
+        resp.append_header(header::CACHE_CONTROL, value);
+    }
+    resp
+}
+
+/// Check if a mirrored copy was last modified longer ago than
+/// `mirror_max_age_s`.  Copies without a (valid) `Last-Modified`
+/// header aren't counted as stale, as there's no telling.
+fn is_stale_mirror_copy(settings: &Config, beresp: &Response) -> bool {
+    let last_modified = match beresp
+        .get_header(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+    {
+        Some(last_modified) => last_modified,
+        None => return false,
+    };
+
+    cdn_clock::now()
+        .duration_since(last_modified)
+        .map_or(false, |age| age > settings.mirror_max_age)
+}
+
+/// Add the headers which only the origin needs to see.
+fn add_origin_headers(settings: &Config, bereq: &mut Request) {
+    bereq.set_header("Govuk-Use-Recommended-Related-Links", "true");
+
+    // the internal bearer token is only for the edge.
+    if internal::has_valid_token(settings, bereq) {
+        bereq.remove_header("Authorization");
+    }
+
+    if let Some(expected) = &settings.basic_authorization {
+        bereq.set_header("Authorization", format!("Basic {}", expected));
+    }
+}
+
+/// Check if this request has come from another node of this service,
+/// meaning that this instance is running on the shield tier.
+///
+/// `Fastly-FF` is only trusted from IPs on the shield ACL, and with the
+/// shield secret: any Fastly service can send requests from the
+/// shield ACL's IPs.
+pub fn is_shield_request(settings: &Config, req: &Request) -> bool {
+    let from_fastly = match req.get_client_ip_addr() {
+        Some(client_ip) if req.contains_header(FASTLY_FF_HEADER_NAME) => {
+            ip_is_on_acl(&settings.acl_shield, &client_ip, false)
+        }
+        _ => false,
+    };
+    from_fastly && has_shield_secret(settings, req)
+}
+
+/// Check if a request has the shield secret.
+fn has_shield_secret(settings: &Config, req: &Request) -> bool {
+    match (
+        signing::get_secret(settings, &settings.shield_secret),
+        req.get_header_str(SHIELD_AUTH_HEADER_NAME),
+    ) {
+        (Some(secret), Some(candidate)) => signing::secrets_match(&secret, candidate),
+        _ => false,
+    }
+}
+
+/// Reject a request with `Fastly-FF` which isn't a shield request (see
+/// `is_shield_request`), rather than handle it as a client request
+/// with a header it has no business sending.
+pub fn reject_forged_shield_request(req: &Request) -> Option<Response> {
+    if !req.contains_header(FASTLY_FF_HEADER_NAME) {
+        return None;
+    }
+
+    Some(
+        Response::from_status(403)
+            .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Shield.as_str()),
     )
 }
 
-/// Check if an IP is on an ACL.
-fn ip_is_on_acl(acl: &IpRange<Ipv4Net>, client_ip: &IpAddr, on_empty_acl: bool) -> bool {
+/// Get the shield backend to use for an origin, if the origin is
+/// shielded and this instance is not running on its shield POP (or
+/// on the shield tier at all).
+fn shield_for_origin<'a>(settings: &'a Config, bereq: &Request, origin: &str) -> Option<&'a str> {
+    if bereq.contains_header(FASTLY_FF_HEADER_NAME) {
+        return None;
+    }
+
+    match settings.shielding.get(origin) {
+        Some(shield) if env::var("FASTLY_POP").ok().as_ref() != Some(&shield.pop) => {
+            Some(&shield.backend)
+        }
+        _ => None,
+    }
+}
+
+/// Add this node to the `Fastly-FF` header, so the shield knows the
+/// request has come from the edge.
+fn append_fastly_ff(bereq: &mut Request) {
+    let node = format!(
+        "{}-{}",
+        env::var("FASTLY_HOSTNAME").unwrap_or_else(|_| "unknown".to_string()),
+        env::var("FASTLY_POP").unwrap_or_else(|_| "unknown".to_string()),
+    );
+    let fastly_ff = match bereq.get_header_str(FASTLY_FF_HEADER_NAME) {
+        Some(existing) => format!("{}, {}", existing, node),
+        None => node,
+    };
+    bereq.set_header(FASTLY_FF_HEADER_NAME, fastly_ff);
+}
+
+/// Order the origins by distance from the client: those tagged with
+/// the client's continent or country first, then the rest, each in
+/// config order.  An origin a request header rule names as its
+/// `backend` goes first of all.
+fn origins_by_distance<'a>(settings: &'a Config, bereq: &Request) -> Vec<&'a str> {
+    let geo = cdn_headers::client_ip(bereq).and_then(geo_lookup);
+
+    let (mut near, far): (Vec<&OriginConfig>, Vec<&OriginConfig>) =
+        settings.origins.iter().partition(|origin| match &geo {
+            Some(geo) => origin
+                .regions
+                .iter()
+                .any(|region| region == geo.continent().as_code() || region == geo.country_code()),
+            None => false,
+        });
+    near.extend(far);
+
+    let mut backends: Vec<&str> = near
+        .into_iter()
+        .map(|origin| origin.backend.as_str())
+        .collect();
+
+    let cookies = get_cookies(bereq.get_header_str("cookie"));
+    if let Some(preferred) = header_rules::preferred_backend(settings, &cookies, bereq) {
+        if let Some(index) = backends.iter().position(|backend| *backend == preferred) {
+            let backend = backends.remove(index);
+            backends.insert(0, backend);
+        }
+    }
+
+    backends
+}
+
+/// Check if an IP (v4 or v6) is on an ACL.
+fn ip_is_on_acl(acl: &Acl, client_ip: &IpAddr, on_empty_acl: bool) -> bool {
     if acl.is_empty() {
         on_empty_acl
-    } else if let IpAddr::V4(client_ipv4) = client_ip {
-        acl.contains(client_ipv4)
     } else {
-        false
+        acl.contains(client_ip)
     }
 }
 
 /// Check if the correct Authorization header has been supplied (if
-/// needed).
+/// needed).  On the draft stack, it's always needed unless there is a
+/// signon session.  Internal paths are checked by `internal::is_hidden`
+/// instead, as their clients use the header for a bearer token.
 fn authorized(settings: &Config, request: &Request) -> bool {
+    if internal::is_internal_path(settings, request.get_path()) {
+        return true;
+    }
+
+    if settings.draft_profile {
+        let cookies = get_cookies(request.get_header_str("cookie"));
+        if draft::has_signon_session(settings, &cookies) {
+            return true;
+        }
+    }
+
     match (
         &settings.basic_authorization,
         request.get_header_str("authorization"),
     ) {
         (Some(expected), Some(actual)) if actual == format!("Basic {}", expected) => true,
         (Some(_), _) => false,
-        (None, _) => true,
+        (None, _) => !settings.draft_profile,
+    }
+}
+
+/// Classify a path.
+///
+/// This isn't memoised: looking the path up in a cache costs more than
+/// checking it against the configuration.
+fn classify_path(settings: &Config, path: &str) -> PathClass {
+    PathClass {
+        not_found: is_special_not_found(settings, path),
+        personalised: is_personalised(settings, path),
     }
 }
 
@@ -268,15 +1219,259 @@ fn is_special_not_found(settings: &Config, path: &str) -> bool {
     settings.synthetic_not_found.contains(&path.to_string())
 }
 
+/// Check if a path is embargoed (has a publication time in the future)
+fn is_embargoed(settings: &Config, path: &str) -> bool {
+    match settings.synthetic_embargo.get(path) {
+        Some(publish_at) => cdn_clock::now() < *publish_at,
+        None => false,
+    }
+}
+
+/// Check if a method (eg, TRACE) is never passed to origin
+fn is_rejected_method(settings: &Config, method: &str) -> bool {
+    settings
+        .rejected_methods
+        .iter()
+        .any(|rejected| rejected.eq_ignore_ascii_case(method))
+}
+
+/// Check if a path is fully personalised, and so never cached
+fn is_personalised(settings: &Config, path: &str) -> bool {
+    settings
+        .personalised_prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
 /// Check if a path is a special-cased redirect and return the redirect if so.
 fn is_special_redirect<'a>(settings: &'a Config, path: &'a str) -> Option<&'a String> {
     settings.synthetic_redirect.get(&path.to_string())
 }
 
-/// Sort the querystring, remove UTM params, and drop some params on
-/// certain pages.
-fn normalise_querystring(req: &Request) -> Vec<(String, String)> {
-    let mut qs: Vec<(String, String)> = req.get_query().unwrap();
+/// Log that a cookie (or the whole Cookie header) has been dropped.
+fn log_cookie_problem(settings: &Config, problem: &str, name: Option<&str>) {
+    let mut fields = Map::new();
+    fields.insert("problem".to_string(), Value::from(problem));
+    if let Some(name) = name {
+        fields.insert("cookie".to_string(), Value::from(name));
+    }
+    cdn_logging::log_event(settings, "cookie_dropped", fields);
+}
+
+/// Log that a request has been passed because of a large header.
+fn log_large_header(settings: &Config, name: &str, size: usize) {
+    let mut fields = Map::new();
+    fields.insert("header".to_string(), Value::from(name));
+    fields.insert("size".to_string(), Value::from(size));
+    cdn_logging::log_event(settings, "large_header_pass", fields);
+}
+
+/// Log that a response was delivered without the optional transforms,
+/// because the request deadline was nearly up.
+fn log_degraded(settings: &Config, path: &str, remaining: Duration) {
+    let mut fields = Map::new();
+    fields.insert("path".to_string(), Value::from(path));
+    fields.insert(
+        "remaining_ms".to_string(),
+        Value::from(remaining.as_millis() as u64),
+    );
+    cdn_logging::log_event(settings, "degraded", fields);
+}
+
+/// Log a request and its response (for `access_log.percentage`
+/// percent of requests), with what served it, the A/B test variants
+/// it set, and the user's consent state, so experiment exposure can be
+/// joined against consent rates.
+pub fn log_access(settings: &Config, req: &Request, resp: &Response) {
+    if !cdn_rand::percent_chance(settings.access_log_percentage) {
+        return;
+    }
+
+    let cookies = get_cookies(req.get_header_str("cookie"));
+    let consent = Consent::from_cookies(settings, &cookies);
+
+    let mut fields = Map::new();
+    fields.insert("method".to_string(), Value::from(req.get_method_str()));
+    fields.insert("path".to_string(), Value::from(req.get_path()));
+    fields.insert(
+        "status".to_string(),
+        Value::from(resp.get_status().as_u16()),
+    );
+    let backend = BackendNameTag::from_response(resp);
+    fields.insert(
+        "backend".to_string(),
+        backend
+            .as_ref()
+            .map_or(Value::Null, |backend| Value::from(backend.as_str())),
+    );
+    fields.insert(
+        "backend_kind".to_string(),
+        backend
+            .as_ref()
+            .map_or(Value::Null, |backend| Value::from(backend.kind(settings))),
+    );
+    fields.insert(
+        "failover".to_string(),
+        Value::from(cdn_headers::is_failover(resp)),
+    );
+    fields.insert(
+        "ab_tests".to_string(),
+        Value::Object(ab_tests::response_variants(resp)),
+    );
+    fields.insert("consent".to_string(), Value::from(consent.status()));
+    fields.insert(
+        "consent_categories".to_string(),
+        Value::from(consent.categories().to_vec()),
+    );
+    cdn_logging::log_event_to(
+        settings,
+        settings.access_log_endpoint.as_deref(),
+        "access",
+        fields,
+    );
+}
+
+/// Log that a request has been rejected because of its method.
+fn log_rejected_method(settings: &Config, method: &str, path: &str) {
+    let mut fields = Map::new();
+    fields.insert("method".to_string(), Value::from(method));
+    fields.insert("path".to_string(), Value::from(path));
+    cdn_logging::log_event(settings, "method_rejected", fields);
+}
+
+/// Log an A/B test in the experiments manifest which couldn't be
+/// parsed, and so was skipped.
+fn log_invalid_experiment(settings: &Config, name: &str) {
+    let mut fields = Map::new();
+    fields.insert("name".to_string(), Value::from(name));
+    cdn_logging::log_event(settings, "invalid_experiment", fields);
+}
+
+/// Log how an origin request for an A/B test variant went (sampled by
+/// `ab_test_safety.log_percentage`), for monitoring to decide whether
+/// to disable the variant (see `ab_safety`).
+fn log_ab_test_origin_response(
+    settings: &Config,
+    name: &str,
+    variant: &str,
+    latency: Duration,
+    error: bool,
+) {
+    let mut fields = Map::new();
+    fields.insert("ab_test".to_string(), Value::from(name));
+    fields.insert("variant".to_string(), Value::from(variant));
+    fields.insert(
+        "latency_ms".to_string(),
+        Value::from(latency.as_millis() as u64),
+    );
+    fields.insert("error".to_string(), Value::from(error));
+    fields.insert(
+        "percentage".to_string(),
+        Value::from(settings.ab_test_safety_log_percentage),
+    );
+    cdn_logging::log_event(settings, "ab_test_origin_response", fields);
+}
+
+/// Log the query params of a request (sampled by
+/// `querystring.learn_percentage`) which aren't in the allowlist.
+fn log_unknown_query_params(settings: &Config, unknown: &UnknownParams) {
+    let mut fields = Map::new();
+    fields.insert("pattern".to_string(), Value::from(unknown.pattern.as_str()));
+    fields.insert("params".to_string(), Value::from(unknown.names.clone()));
+    fields.insert(
+        "percentage".to_string(),
+        Value::from(settings.querystring_learn_percentage),
+    );
+    cdn_logging::log_event(settings, "unknown_query_params", fields);
+}
+
+/// Log a PURGE request, who made it, and what came of it.
+fn log_purge_audit(settings: &Config, req: &Request, resp: &Response) {
+    let mut fields = Map::new();
+    fields.insert(
+        "client_ip".to_string(),
+        req.get_client_ip_addr()
+            .map_or(Value::Null, |ip| Value::from(ip.to_string())),
+    );
+    fields.insert("url".to_string(), Value::from(req.get_url_str()));
+    fields.insert(
+        "surrogate_keys".to_string(),
+        req.get_header_str(purge::SURROGATE_KEY_HEADER_NAME)
+            .map_or(Value::Null, Value::from),
+    );
+    fields.insert(
+        "status".to_string(),
+        Value::from(resp.get_status().as_u16()),
+    );
+    cdn_logging::log_event(settings, "purge_audit", fields);
+}
+
+/// Log that a mirror served a bucket listing.
+fn log_bucket_listing(settings: &Config, backend_name: &str, path: &str) {
+    let mut fields = Map::new();
+    fields.insert("backend".to_string(), Value::from(backend_name));
+    fields.insert("path".to_string(), Value::from(path));
+    cdn_logging::log_event(settings, "mirror_bucket_listing", fields);
+}
+
+/// Log that a mirror returned an XML error, rather than the object.
+fn log_bucket_error(settings: &Config, backend_name: &str, path: &str, status: u16) {
+    let mut fields = Map::new();
+    fields.insert("backend".to_string(), Value::from(backend_name));
+    fields.insert("path".to_string(), Value::from(path));
+    fields.insert("status".to_string(), Value::from(status));
+    cdn_logging::log_event(settings, "mirror_bucket_error", fields);
+}
+
+/// Log that a form submission was rejected as spam.
+fn log_form_spam(settings: &Config, path: &str, reason: &str) {
+    let mut fields = Map::new();
+    fields.insert("path".to_string(), Value::from(path));
+    fields.insert("reason".to_string(), Value::from(reason));
+    cdn_logging::log_event(settings, "form_spam_rejected", fields);
+}
+
+/// Log that a search was rate limited.
+fn log_search_rate_limited(settings: &Config, query: &str, retry_after: Duration) {
+    let mut fields = Map::new();
+    fields.insert("query_length".to_string(), Value::from(query.len()));
+    fields.insert(
+        "retry_after_s".to_string(),
+        Value::from(retry_after.as_secs()),
+    );
+    cdn_logging::log_event(settings, "search_rate_limited", fields);
+}
+
+/// Log that a redirect rule has fired, so rules which are no longer
+/// used can be found (by counting these events) and retired.
+fn log_redirect(settings: &Config, table: &str, path: &str, destination: &str) {
+    let mut fields = Map::new();
+    fields.insert("table".to_string(), Value::from(table));
+    fields.insert("path".to_string(), Value::from(path));
+    fields.insert("destination".to_string(), Value::from(destination));
+    cdn_logging::log_event(settings, "redirect", fields);
+}
+
+/// Add the `src=edge-redirect` marker to a redirect destination, if
+/// enabled.
+fn mark_redirect(settings: &Config, destination: &str) -> String {
+    if !settings.redirect_marker {
+        destination.to_string()
+    } else if destination.contains('?') {
+        format!("{}&{}", destination, REDIRECT_MARKER)
+    } else {
+        format!("{}?{}", destination, REDIRECT_MARKER)
+    }
+}
+
+/// Sort the querystring, remove UTM params and the cache bypass token,
+/// drop some params on certain pages (and those not in the allowlist,
+/// if it's enforced: see `query_allowlist::enforce`), keep only the
+/// last value of repeated params (see `keeps_repeated_params`), and cap
+/// search pagination params (see `pagination::cap`).  A querystring
+/// which can't be parsed is dropped entirely.
+pub fn normalise_querystring(settings: &Config, req: &Request) -> Vec<(String, String)> {
+    let mut qs: Vec<(String, String)> = req.get_query().unwrap_or_default();
 
     match req.get_url().path() {
         // https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L266
@@ -287,17 +1482,61 @@ fn normalise_querystring(req: &Request) -> Vec<(String, String)> {
         _ => qs.retain(|param| !param.0.starts_with("utm_")),
     }
 
+    qs.retain(|param| {
+        param.0 != cachebust::CACHEBUST_PARAM_NAME
+            && param.0 != ab_tests::PREVIEW_PARAM_NAME
+            && param.0 != ab_tests::PREVIEW_TOKEN_PARAM_NAME
+    });
+
+    query_allowlist::enforce(settings, req.get_path(), &mut qs);
+
+    if !keeps_repeated_params(settings, req.get_path()) {
+        let mut seen = HashSet::new();
+        let mut last_values = Vec::with_capacity(qs.len());
+        for (name, value) in qs.into_iter().rev() {
+            if name.ends_with("[]") || seen.insert(name.clone()) {
+                last_values.push((name, value));
+            }
+        }
+        last_values.reverse();
+        qs = last_values;
+    }
+
+    pagination::cap(settings, req.get_path(), &mut qs);
+
+    // the sort is stable, so the values of a repeated param stay in
+    // the order they were given.
     qs.sort_by(|(a, _), (b, _)| a.cmp(b));
     qs
 }
 
+/// Check if all the values of a repeated query param are passed to
+/// origin for a path (eg, facet filters on search), rather than just
+/// the last.
+fn keeps_repeated_params(settings: &Config, path: &str) -> bool {
+    settings
+        .querystring_repeated_paths
+        .iter()
+        .any(|pattern| matches_path_pattern(pattern, path))
+}
+
+/// Check if a path (or anything else matched the same way, like a
+/// surrogate key) matches a pattern, which is either an exact value or
+/// a prefix ending in `*`.
+fn matches_path_pattern(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
 /// Parse cookies header into key/value pairs
-fn get_cookies(header_str: Option<&str>) -> HashMap<String, String> {
+pub fn get_cookies(header_str: Option<&str>) -> HashMap<String, String> {
     header_str
         .unwrap_or("")
-        .split(";")
+        .split(';')
         .filter_map(|kv| {
-            kv.find("=").map(|index| {
+            kv.find('=').map(|index| {
                 let (key, value) = kv.split_at(index);
                 let key = key.trim().to_string();
                 let value = value[1..].to_string();
@@ -310,37 +1549,463 @@ fn get_cookies(header_str: Option<&str>) -> HashMap<String, String> {
 /// Union of different backend error types.
 enum BackendError {
     MissingConfig,
-    Fastly(SendError),
+    Fastly(Box<SendError>),
+    ChecksumMismatch,
+    Incomplete,
+    Timeout,
+    BucketError,
+    ServerError(StatusCode),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::MissingConfig => write!(f, "no mirror configuration"),
+            BackendError::Fastly(error) => write!(f, "{}", error),
+            BackendError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            BackendError::Incomplete => write!(f, "incomplete response"),
+            BackendError::Timeout => write!(f, "deadline exceeded"),
+            BackendError::BucketError => write!(f, "bucket error"),
+            BackendError::ServerError(status) => write!(f, "status {}", status.as_u16()),
+        }
+    }
+}
+
+/// Remember the error from a backend, for `CdnError::AllBackendsFailed`.
+/// Only the last error from each backend is kept.
+fn record_error(errors: &mut Vec<(String, String)>, backend_name: &str, error: BackendError) {
+    errors.retain(|(name, _)| name != backend_name);
+    errors.push((backend_name.to_string(), error.to_string()));
+}
+
+/// How long is left before the deadline.
+fn remaining_before(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Send a request to a backend, giving up if there is no response by
+/// the deadline.
+fn send_before(
+    bereq: Request,
+    backend_name: &str,
+    deadline: Instant,
+) -> Result<Response, BackendError> {
+    if Instant::now() >= deadline {
+        return Err(BackendError::Timeout);
+    }
+
+    let mut pending = bereq
+        .send_async(backend_name)
+        .map_err(|err| BackendError::Fastly(Box::new(err)))?;
+    loop {
+        match pending.poll() {
+            PollResult::Done(result) => {
+                return result.map_err(|err| BackendError::Fastly(Box::new(err)))
+            }
+            PollResult::Pending(still_pending) => {
+                if Instant::now() >= deadline {
+                    return Err(BackendError::Timeout);
+                }
+                pending = still_pending;
+                thread::sleep(DEADLINE_POLL_INTERVAL);
+            }
+        }
+    }
 }
 
 /// Fetch from one of the mirrors.
+///
+/// If a large response is cut short, the rest of the body is fetched
+/// with range requests to the `resume_from` mirrors.
 fn fetch_beresp_fallback(
     settings: &Config,
     bereq: &Request,
     path: &str,
+    index_path: Option<&str>,
     backend_name: &str,
+    resume_from: &[String],
+    deadline: Instant,
 ) -> Result<Response, BackendError> {
     if let Some(mirror_config) = settings.mirrors.get(backend_name) {
         // todo https://github.com/alphagov/govuk-cdn-config/blob/master/vcl_templates/www.vcl.erb#L330
 
-        let new_path = if let Some(prefix) = &mirror_config.prefix {
-            format!("{}{}", prefix.clone(), path)
-        } else {
-            path.to_string()
-        };
+        let new_path = mirror_path(mirror_config, path, index_path);
 
-        match bereq
-            .clone_without_body()
-            .with_header("Date", fmt_http_date(SystemTime::now()))
-            .with_path(&new_path)
-            .send(backend_name)
-        {
-            Ok(beresp) => Ok(beresp
-                .with_header("Fastly-Failover", "1")
-                .with_header("Fastly-Backend-Name", backend_name)),
-            Err(e) => Err(BackendError::Fastly(e)),
-        }
+        let beresp = send_before(
+            bereq
+                .clone_without_body()
+                .with_header("Date", cdn_clock::http_date())
+                .with_path(&new_path),
+            backend_name,
+            deadline,
+        )?;
+        let mut beresp = reject_bucket_error(settings, bereq, backend_name, beresp)?;
+        mime::set_default_content_type(settings, &new_path, &mut beresp);
+        let beresp = resume_if_incomplete(
+            settings,
+            bereq,
+            path,
+            index_path,
+            resume_from,
+            deadline,
+            beresp,
+        )?;
+        let beresp = verify_checksum(
+            settings,
+            mirror_config,
+            bereq,
+            &new_path,
+            backend_name,
+            deadline,
+            beresp,
+        )?;
+        let beresp = hide_bucket_listing(settings, bereq, backend_name, beresp);
+        Ok(transform_mirror_response(settings, beresp))
     } else {
         Err(BackendError::MissingConfig)
     }
 }
+
+/// Replace a bucket listing from a mirror (an XML `ListBucketResult`,
+/// which S3 and GCS serve for a path which isn't an object if the
+/// bucket is listable) with the synthetic 404, so failover never
+/// exposes the bucket's contents.
+fn hide_bucket_listing(
+    settings: &Config,
+    bereq: &Request,
+    backend_name: &str,
+    mut beresp: Response,
+) -> Response {
+    let is_xml = beresp.get_content_type().map_or(false, |mime| {
+        mime.essence_str() == "application/xml" || mime.essence_str() == "text/xml"
+    });
+    if !beresp.get_status().is_success() || !is_xml {
+        return beresp;
+    }
+
+    let body = beresp.take_body_bytes();
+    if body
+        .windows(BUCKET_LISTING_MARKER.len())
+        .any(|window| window == BUCKET_LISTING_MARKER)
+    {
+        log_bucket_listing(settings, backend_name, bereq.get_path());
+        return synthetic_not_found_response(bereq).with_header(
+            BACKEND_NAME_HEADER_NAME,
+            BackendNameTag::BucketListing.as_str(),
+        );
+    }
+
+    beresp.with_body(body)
+}
+
+/// Reject an XML error from a mirror (a 403 or 404 with an S3 / GCS
+/// `<Error>` body), so that the next mirror is tried rather than the
+/// error being served.
+fn reject_bucket_error(
+    settings: &Config,
+    bereq: &Request,
+    backend_name: &str,
+    mut beresp: Response,
+) -> Result<Response, BackendError> {
+    let status = beresp.get_status();
+    let is_xml = beresp.get_content_type().map_or(false, |mime| {
+        mime.essence_str() == "application/xml" || mime.essence_str() == "text/xml"
+    });
+    if (status != StatusCode::FORBIDDEN && status != StatusCode::NOT_FOUND) || !is_xml {
+        return Ok(beresp);
+    }
+
+    let body = beresp.take_body_bytes();
+    if body
+        .windows(BUCKET_ERROR_MARKER.len())
+        .any(|window| window == BUCKET_ERROR_MARKER)
+    {
+        log_bucket_error(settings, backend_name, bereq.get_path(), status.as_u16());
+        return Err(BackendError::BucketError);
+    }
+
+    Ok(beresp.with_body(body))
+}
+
+/// Strip the storage service's own headers from a mirror response, and
+/// only let it be cached for `mirror_cache_max_age_s`, so clients don't
+/// hold on to the mirrored copy once the origins are back.
+fn transform_mirror_response(settings: &Config, mut beresp: Response) -> Response {
+    let names: Vec<HeaderName> = beresp
+        .get_header_names()
+        .filter(|name| {
+            MIRROR_HEADER_PREFIXES
+                .iter()
+                .any(|prefix| name.as_str().starts_with(prefix))
+        })
+        .cloned()
+        .collect();
+    for name in names {
+        beresp.remove_header(name);
+    }
+
+    beresp.set_header(
+        header::CACHE_CONTROL,
+        format!("max-age={}", settings.mirror_cache_max_age.as_secs()),
+    );
+    beresp
+}
+
+/// Get the path of an object on a mirror.  For directory URLs, this is
+/// `index_path` if the mirror stores directories that way.
+fn mirror_path(mirror_config: &MirrorConfig, path: &str, index_path: Option<&str>) -> String {
+    let path = match index_path {
+        Some(index_path) if mirror_config.directory_index => index_path,
+        _ => path,
+    };
+
+    if let Some(prefix) = &mirror_config.prefix {
+        format!("{}{}", prefix.clone(), path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Read the body of a large mirror response, and if the connection
+/// fails part-way through, fetch only the missing bytes from the next
+/// mirrors and stitch the body back together.
+///
+/// Responses smaller than `mirror_resume_min_bytes`, larger than
+/// `mirror_resume_max_bytes`, or without a `Content-Length`, are
+/// returned as-is.  No more than `Content-Length` bytes are read.
+///
+/// The range requests carry an `If-Range` with the first response's
+/// `ETag` (or `Last-Modified`), so a mirror with a different version
+/// of the object sends all of it, and only a `206` for exactly the
+/// missing bytes of an object of the same length is stitched on.
+/// Responses with neither validator are never resumed.
+fn resume_if_incomplete(
+    settings: &Config,
+    bereq: &Request,
+    path: &str,
+    index_path: Option<&str>,
+    resume_from: &[String],
+    deadline: Instant,
+    mut beresp: Response,
+) -> Result<Response, BackendError> {
+    let content_length = match beresp.get_content_length() {
+        Some(length) if beresp.get_status() == StatusCode::OK => length,
+        _ => return Ok(beresp),
+    };
+    if bereq.get_method() != Method::GET
+        || content_length < settings.mirror_resume_min_bytes
+        || content_length > settings.mirror_resume_max_bytes
+    {
+        return Ok(beresp);
+    }
+    let validator = match resume_validator(&beresp) {
+        Some(validator) => validator,
+        None => return Ok(beresp),
+    };
+
+    let mut body = Vec::with_capacity(content_length);
+    let _ = beresp
+        .take_body()
+        .take(content_length as u64)
+        .read_to_end(&mut body);
+
+    for backend_name in resume_from.iter() {
+        if body.len() >= content_length {
+            break;
+        }
+
+        if let Some(mirror_config) = settings.mirrors.get(backend_name) {
+            let range_start = body.len();
+            let ranged_beresp = send_before(
+                bereq
+                    .clone_without_body()
+                    .with_header("Date", cdn_clock::http_date())
+                    .with_header(header::RANGE, format!("bytes={}-", range_start))
+                    .with_header(header::IF_RANGE, &validator)
+                    .with_path(&mirror_path(mirror_config, path, index_path)),
+                backend_name,
+                deadline,
+            );
+
+            if let Ok(mut ranged_beresp) = ranged_beresp {
+                let is_expected_range = ranged_beresp.get_status() == StatusCode::PARTIAL_CONTENT
+                    && is_missing_range(
+                        ranged_beresp.get_header_str(header::CONTENT_RANGE),
+                        range_start,
+                        content_length,
+                    );
+                if is_expected_range {
+                    let _ = ranged_beresp
+                        .take_body()
+                        .take((content_length - range_start) as u64)
+                        .read_to_end(&mut body);
+                }
+            }
+        }
+    }
+
+    if body.len() == content_length {
+        Ok(beresp.with_body(body))
+    } else {
+        Err(BackendError::Incomplete)
+    }
+}
+
+/// Get the validator to send as `If-Range` when resuming a response:
+/// its `ETag`, if that's strong, or else its `Last-Modified`.
+fn resume_validator(beresp: &Response) -> Option<String> {
+    beresp
+        .get_header_str(header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| beresp.get_header_str(header::LAST_MODIFIED))
+        .map(|validator| validator.to_string())
+}
+
+/// Check if a `Content-Range` is exactly the bytes from `start` to the
+/// end of an object `total` bytes long.
+fn is_missing_range(content_range: Option<&str>, start: usize, total: usize) -> bool {
+    start < total
+        && content_range == Some(format!("bytes {}-{}/{}", start, total - 1, total).as_str())
+}
+
+/// Check a successful mirror response against its expected checksum,
+/// if there is one.
+///
+/// The expected checksum comes from `mirror_checksums` in the config
+/// if the path is listed there, and otherwise from the mirror's
+/// sidecar checksum file (if the mirror has them and the file
+/// exists).
+fn verify_checksum(
+    settings: &Config,
+    mirror_config: &MirrorConfig,
+    bereq: &Request,
+    mirror_path: &str,
+    backend_name: &str,
+    deadline: Instant,
+    mut beresp: Response,
+) -> Result<Response, BackendError> {
+    if !beresp.get_status().is_success() {
+        return Ok(beresp);
+    }
+
+    let expected = match settings.mirror_checksums.get(bereq.get_path()) {
+        Some(checksum) => Some(checksum.to_lowercase()),
+        None => match &mirror_config.checksum_suffix {
+            Some(suffix) => {
+                fetch_sidecar_checksum(bereq, mirror_path, suffix, backend_name, deadline)
+            }
+            None => None,
+        },
+    };
+
+    if let Some(expected) = expected {
+        let body = beresp.take_body_bytes();
+        let actual = signing::to_hex(&Sha256::digest(&body));
+
+        if actual != expected {
+            return Err(BackendError::ChecksumMismatch);
+        }
+
+        Ok(beresp.with_body(body))
+    } else {
+        Ok(beresp)
+    }
+}
+
+/// Fetch the sidecar checksum file for an object from a mirror.
+///
+/// The file is expected to be in `sha256sum` format: the hex digest
+/// followed by (optionally) whitespace and a file name.
+fn fetch_sidecar_checksum(
+    bereq: &Request,
+    mirror_path: &str,
+    suffix: &str,
+    backend_name: &str,
+    deadline: Instant,
+) -> Option<String> {
+    match send_before(
+        bereq
+            .clone_without_body()
+            .with_method("GET")
+            .with_header("Date", cdn_clock::http_date())
+            .with_path(&format!("{}{}", mirror_path, suffix)),
+        backend_name,
+        deadline,
+    ) {
+        Ok(mut resp) if resp.get_status().is_success() => resp
+            .take_body_str()
+            .split_whitespace()
+            .next()
+            .map(|checksum| checksum.to_lowercase()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdn_clock::FixedClock;
+    use crate::cdn_config::ConfigBuilder;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn malformed_cookies_are_dropped() {
+        let (valid, session_malformed) = split_valid_cookies("a=1; b=\u{7}; ; c=3 ");
+        assert_eq!(valid, vec!["a=1", "c=3"]);
+        assert!(!session_malformed);
+    }
+
+    #[test]
+    fn malformed_session_cookie_is_noticed() {
+        let header = format!("a=1; {}=\u{fffd}", accounts::ACCOUNT_COOKIE_NAME);
+        let (valid, session_malformed) = split_valid_cookies(&header);
+        assert_eq!(valid, vec!["a=1"]);
+        assert!(session_malformed);
+    }
+
+    #[test]
+    fn embargo_expires_at_the_publication_time() {
+        let publish_at = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let settings = ConfigBuilder::new()
+            .with(|settings| {
+                settings
+                    .synthetic_embargo
+                    .insert("/embargoed".to_string(), publish_at);
+            })
+            .build();
+
+        cdn_clock::set_clock(Box::new(FixedClock(publish_at - Duration::from_secs(1))));
+        assert!(is_embargoed(&settings, "/embargoed"));
+        assert!(!is_embargoed(&settings, "/published"));
+
+        cdn_clock::set_clock(Box::new(FixedClock(publish_at)));
+        assert!(!is_embargoed(&settings, "/embargoed"));
+    }
+
+    #[test]
+    fn resumes_only_the_missing_range_of_the_same_length() {
+        assert!(is_missing_range(Some("bytes 100-999/1000"), 100, 1000));
+        assert!(!is_missing_range(Some("bytes 100-999/2000"), 100, 1000));
+        assert!(!is_missing_range(Some("bytes 100-999/*"), 100, 1000));
+        assert!(!is_missing_range(Some("bytes 0-999/1000"), 100, 1000));
+        assert!(!is_missing_range(Some("bytes 100-499/1000"), 100, 1000));
+        assert!(!is_missing_range(None, 100, 1000));
+        assert!(!is_missing_range(Some("bytes 1000-999/1000"), 1000, 1000));
+    }
+
+    #[test]
+    fn resumes_with_a_strong_etag_or_last_modified() {
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let resp = Response::new()
+            .with_header(header::ETAG, "\"abc\"")
+            .with_header(header::LAST_MODIFIED, last_modified);
+        assert_eq!(resume_validator(&resp), Some("\"abc\"".to_string()));
+
+        let resp = Response::new()
+            .with_header(header::ETAG, "W/\"abc\"")
+            .with_header(header::LAST_MODIFIED, last_modified);
+        assert_eq!(resume_validator(&resp), Some(last_modified.to_string()));
+
+        assert_eq!(resume_validator(&Response::new()), None);
+    }
+}
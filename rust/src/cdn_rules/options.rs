@@ -0,0 +1,49 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_rules::{is_rejected_method, matches_path_pattern};
+
+use fastly::{Request, Response};
+
+/// Methods the service handles, before `rejected_methods` is applied
+const SUPPORTED_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+/// Return a synthetic 204 listing the allowed methods if this is an
+/// OPTIONS request which isn't a CORS preflight, unless origin needs
+/// to see OPTIONS for the path (`options.pass_paths`).  Preflights
+/// always go to origin, which knows its own CORS policy.
+pub fn recv(settings: &Config, req: &Request) -> Option<Response> {
+    if req.get_method_str() != "OPTIONS" || is_preflight(req) {
+        return None;
+    }
+
+    let path = req.get_path();
+    if settings
+        .options_pass_paths
+        .iter()
+        .any(|pattern| matches_path_pattern(pattern, path))
+    {
+        return None;
+    }
+
+    Some(
+        Response::from_status(204)
+            .with_header("Allow", allowed_methods(settings))
+            .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Options.as_str()),
+    )
+}
+
+/// The `Allow` header value: the supported methods which aren't in
+/// `rejected_methods`.
+pub fn allowed_methods(settings: &Config) -> String {
+    SUPPORTED_METHODS
+        .iter()
+        .filter(|method| !is_rejected_method(settings, method))
+        .cloned()
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Check if a request is a CORS preflight.
+fn is_preflight(req: &Request) -> bool {
+    req.contains_header("Origin") && req.contains_header("Access-Control-Request-Method")
+}
@@ -0,0 +1,34 @@
+use crate::cdn_config::Config;
+
+use fastly::Response;
+
+/// Response header an origin sends, on a 204, to redirect the path
+const EDGE_REDIRECT_HEADER_NAME: &str = "X-Edge-Redirect";
+
+/// Get the redirect an origin has asked for in its response to a
+/// path, if any.
+///
+/// Only 204 responses can create redirects, and only to a path on the
+/// same host.  Nothing is remembered here: later requests get the
+/// redirect from the 204 in the Fastly cache, if the origin made it
+/// cacheable.
+pub fn from_beresp(settings: &Config, beresp: &Response) -> Option<String> {
+    if !settings.origin_redirects_enabled || beresp.get_status() != 204 {
+        return None;
+    }
+
+    beresp
+        .get_header(EDGE_REDIRECT_HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .filter(|destination| is_local_path(destination))
+        .map(|destination| destination.to_string())
+}
+
+/// Check if a redirect destination is a path on the same host, rather
+/// than one browsers treat as protocol-relative (like `//evil.com` or
+/// `/\evil.com`).
+fn is_local_path(destination: &str) -> bool {
+    destination.starts_with('/')
+        && !destination.starts_with("//")
+        && !destination.starts_with("/\\")
+}
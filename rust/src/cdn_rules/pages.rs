@@ -0,0 +1,61 @@
+use crate::cdn_rules::locales;
+
+use fastly::handle::dictionary::DictionaryHandle;
+use fastly::http::header;
+use fastly::Request;
+
+/// Edge Dictionary holding replacement bodies for the synthetic pages,
+/// so the messaging can be changed (eg, during an incident) without a
+/// rebuild
+const SYNTHETIC_PAGES_DICTIONARY: &str = "synthetic_pages";
+
+/// Longest page which can be read from the dictionary (Edge Dictionary
+/// values are limited to 8000 characters)
+const MAX_PAGE_LENGTH: usize = 8000;
+
+/// Language code for Welsh
+pub const WELSH: &str = "cy";
+
+/// Language code for English
+pub const ENGLISH: &str = "en";
+
+/// The language to serve a synthetic page in: Welsh if the path ends
+/// in `.cy` (as Welsh GOV.UK pages do), or if Welsh is the client's
+/// most preferred language; English otherwise.
+pub fn language(req: &Request) -> &'static str {
+    let welsh_path = req.get_path().ends_with(".cy");
+    let welsh_preferred = req
+        .get_header(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(locales::preferred_language)
+        .map_or(false, |language| language == WELSH);
+
+    if welsh_path || welsh_preferred {
+        WELSH
+    } else {
+        ENGLISH
+    }
+}
+
+/// Get the body of a synthetic page in a language: as `body`, with the
+/// dictionary entry for Welsh pages being `<name>.cy`.
+pub fn localised_body(name: &str, language: &str, fallback: &str) -> String {
+    if language == ENGLISH {
+        body(name, fallback)
+    } else {
+        body(&format!("{}.{}", name, language), fallback)
+    }
+}
+
+/// Get the body of a synthetic page: the `name` entry of the
+/// `synthetic_pages` Edge Dictionary if there is one, and the
+/// compiled-in `fallback` otherwise.
+pub fn body(name: &str, fallback: &str) -> String {
+    DictionaryHandle::open(SYNTHETIC_PAGES_DICTIONARY)
+        .ok()
+        .filter(|handle| handle.is_valid())
+        .and_then(|handle| handle.get(name, MAX_PAGE_LENGTH).ok())
+        .flatten()
+        .filter(|page| !page.is_empty())
+        .unwrap_or_else(|| fallback.to_string())
+}
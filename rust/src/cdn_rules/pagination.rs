@@ -0,0 +1,63 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_rules::matches_path_pattern;
+
+use fastly::{Request, Response};
+
+/// Return a synthetic 400 if a search request has an absurd pagination
+/// param: one which isn't a whole number, or is above
+/// `search_pagination.reject_above`.  Deep pagination is expensive for
+/// the search backend, and nobody reads page 5000.
+pub fn recv(settings: &Config, req: &Request) -> Option<Response> {
+    if !is_paginated_path(settings, req.get_path()) {
+        return None;
+    }
+
+    let absurd = req
+        .get_query::<Vec<(String, String)>>()
+        .unwrap_or_default()
+        .iter()
+        .filter(|(name, _)| settings.search_pagination_max.contains_key(name))
+        .any(|(_, value)| {
+            value.parse::<usize>().map_or(true, |value| {
+                value > settings.search_pagination_reject_above
+            })
+        });
+    if !absurd {
+        return None;
+    }
+
+    Some(
+        Response::from_status(400)
+            .with_header(
+                BACKEND_NAME_HEADER_NAME,
+                BackendNameTag::BadPagination.as_str(),
+            )
+            .with_header("Cache-Control", "no-store"),
+    )
+}
+
+/// Rewrite search pagination params above their cap in
+/// `search_pagination.max` to the cap, so deep pages are never
+/// requested from origin.  Values `recv` would reject are left alone.
+pub fn cap(settings: &Config, path: &str, qs: &mut Vec<(String, String)>) {
+    if !is_paginated_path(settings, path) {
+        return;
+    }
+
+    for (name, value) in qs.iter_mut() {
+        if let Some(max) = settings.search_pagination_max.get(name) {
+            if value.parse::<usize>().map_or(false, |value| value > *max) {
+                *value = max.to_string();
+            }
+        }
+    }
+}
+
+/// Check if a path's pagination params are capped.
+fn is_paginated_path(settings: &Config, path: &str) -> bool {
+    settings
+        .search_pagination_paths
+        .iter()
+        .any(|pattern| matches_path_pattern(pattern, path))
+}
@@ -0,0 +1,118 @@
+/// Normalise a request path: turn backslashes into slashes, and
+/// resolve `.` and `..` segments (including percent-encoded ones).
+///
+/// Returns `None` for paths which look like traversal attempts: those
+/// with double-encoded dots or slashes (eg, `%252e%252e`), with an
+/// encoded slash inside a segment (eg, `/a/..%2f..%2fetc`, which we
+/// can't split the way every origin would), or with a `..` which would
+/// go above the root.
+pub fn normalise(path: &str) -> Option<String> {
+    let lower = path.to_lowercase();
+    if ["%252e", "%252f", "%255c", "%2f"]
+        .iter()
+        .any(|encoded| lower.contains(encoded))
+    {
+        return None;
+    }
+
+    let path = path
+        .replace('\\', "/")
+        .replace("%5c", "/")
+        .replace("%5C", "/");
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        trailing_slash = false;
+        match segment.to_lowercase().as_str() {
+            "." | "%2e" => trailing_slash = true,
+            ".." | "%2e." | ".%2e" | "%2e%2e" => {
+                segments.pop()?;
+                trailing_slash = true;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalised = format!("/{}", segments.join("/"));
+    if trailing_slash && !normalised.ends_with('/') {
+        normalised.push('/');
+    }
+    Some(normalised)
+}
+
+/// Canonicalise a (normalised) path the way origins route it, for
+/// matching against prefixes: repeated slashes are merged, and
+/// percent-encoded unreserved characters (letters, digits, and `-._~`)
+/// are decoded.
+pub fn canonical(path: &str) -> String {
+    let mut canonical = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(c) = rest.chars().next() {
+        let decoded = if c == '%' {
+            rest.get(1..3)
+                .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .map(char::from)
+                .filter(|c| c.is_ascii_alphanumeric() || "-._~".contains(*c))
+        } else {
+            None
+        };
+        let (c, length) = match decoded {
+            Some(decoded) => (decoded, 3),
+            None => (c, c.len_utf8()),
+        };
+        if c != '/' || !canonical.ends_with('/') {
+            canonical.push(c);
+        }
+        rest = &rest[length..];
+    }
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_resolves_dot_segments() {
+        assert_eq!(normalise("/a/./b/../c"), Some("/a/c".to_string()));
+        assert_eq!(normalise("/a/b/.."), Some("/a/".to_string()));
+        assert_eq!(normalise("/a/%2e%2e/b"), Some("/b".to_string()));
+        assert_eq!(normalise("/a/%2E./b"), Some("/b".to_string()));
+        assert_eq!(normalise("/a/.%2e/b"), Some("/b".to_string()));
+    }
+
+    #[test]
+    fn normalise_treats_backslashes_as_slashes() {
+        assert_eq!(normalise("/a\\..\\b"), Some("/b".to_string()));
+        assert_eq!(normalise("/a%5c..%5Cb"), Some("/b".to_string()));
+    }
+
+    #[test]
+    fn normalise_rejects_traversal_above_the_root() {
+        assert_eq!(normalise("/.."), None);
+        assert_eq!(normalise("/a/../../etc/passwd"), None);
+        assert_eq!(normalise("/%2e%2e/etc/passwd"), None);
+    }
+
+    #[test]
+    fn normalise_rejects_double_encoding() {
+        assert_eq!(normalise("/a/%252e%252e/b"), None);
+        assert_eq!(normalise("/a/%252E./b"), None);
+        assert_eq!(normalise("/a/..%252fb"), None);
+    }
+
+    #[test]
+    fn normalise_rejects_encoded_slashes() {
+        assert_eq!(normalise("/a/..%2f..%2fetc/passwd"), None);
+        assert_eq!(normalise("/a%2Fb"), None);
+    }
+
+    #[test]
+    fn canonical_merges_slashes_and_decodes_unreserved() {
+        assert_eq!(canonical("//admin"), "/admin");
+        assert_eq!(canonical("/%61dmin//x"), "/admin/x");
+        assert_eq!(canonical("/a%20b"), "/a%20b");
+    }
+}
@@ -0,0 +1,65 @@
+use crate::cdn_clock;
+
+use fastly::handle::dictionary::DictionaryHandle;
+
+/// Edge Dictionary holding the mirror pin, which monitoring sets when
+/// the mirrors have been serving most of the traffic
+const MIRROR_PINNING_DICTIONARY: &str = "mirror_pinning";
+
+/// Dictionary entry holding the Unix timestamp requests are pinned to
+/// the mirrors until
+const PINNED_UNTIL_KEY: &str = "pinned_until";
+
+/// Longest timestamp which can be read from a dictionary
+const MAX_TIMESTAMP_LENGTH: usize = 20;
+
+/// Check if requests are pinned to the mirrors, so the origins (which
+/// are failing anyway) can be skipped.
+///
+/// Instances share no state, so they can't count for themselves how
+/// much of the traffic the mirrors have served.  Instead monitoring
+/// does that from the access logs (their `failover` field), and when
+/// it's too much, sets `pinned_until` in the `mirror_pinning` Edge
+/// Dictionary to the end of the cool-down period.  The pin lapses on
+/// its own once that time has passed.
+pub fn is_pinned() -> bool {
+    is_set_until(MIRROR_PINNING_DICTIONARY, PINNED_UNTIL_KEY)
+}
+
+/// Check if an Edge Dictionary entry holds a Unix timestamp which
+/// hasn't passed yet, for flags which monitoring sets and which lapse
+/// on their own.  Missing or invalid entries don't.
+pub fn is_set_until(dictionary: &str, key: &str) -> bool {
+    let until = DictionaryHandle::open(dictionary)
+        .ok()
+        .filter(|handle| handle.is_valid())
+        .and_then(|handle| handle.get(key, MAX_TIMESTAMP_LENGTH).ok())
+        .flatten();
+    is_before(until.as_deref(), cdn_clock::unix_time())
+}
+
+/// Check if a time is before a timestamp, if it's a valid one.
+fn is_before(until: Option<&str>, now: u64) -> bool {
+    until
+        .and_then(|until| until.trim().parse::<u64>().ok())
+        .map_or(false, |until| now < until)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_lapses_at_the_timestamp() {
+        assert!(is_before(Some("1000"), 999));
+        assert!(!is_before(Some("1000"), 1000));
+        assert!(!is_before(Some("1000"), 1001));
+    }
+
+    #[test]
+    fn missing_or_invalid_pins_are_ignored() {
+        assert!(!is_before(None, 0));
+        assert!(!is_before(Some(""), 0));
+        assert!(!is_before(Some("soon"), 0));
+    }
+}
@@ -0,0 +1,114 @@
+use crate::cdn_config::Config;
+use crate::cdn_http;
+use crate::cdn_rules::matches_path_pattern;
+
+use fastly::Request;
+
+/// Request header telling origin what kind of request this is
+pub const PRIORITY_HEADER_NAME: &str = "GOVUK-Request-Priority";
+
+/// `Sec-Fetch-Dest` values for page loads
+const NAVIGATION_DESTINATIONS: [&str; 3] = ["document", "iframe", "frame"];
+
+/// File extensions of static assets, for clients which don't send
+/// `Sec-Fetch-Dest`
+const ASSET_EXTENSIONS: [&str; 11] = [
+    "css", "js", "png", "jpg", "jpeg", "gif", "svg", "ico", "webp", "woff", "woff2",
+];
+
+/// Media types to negotiate between, for clients which don't send
+/// `Sec-Fetch-Dest`: a client which prefers HTML is loading a page.
+const NAVIGATION_MEDIA_TYPES: [&str; 2] = ["text/html", "application/json"];
+
+/// What kind of request something is, from most to least urgent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestClass {
+    /// An HTML page load, which a user is waiting on
+    Navigation,
+    /// A request for JSON, or some other data
+    Api,
+    /// A static asset (CSS, JavaScript, images, fonts)
+    Asset,
+}
+
+impl RequestClass {
+    /// The `GOVUK-Request-Priority` value.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestClass::Navigation => "navigation",
+            RequestClass::Api => "api",
+            RequestClass::Asset => "asset",
+        }
+    }
+
+    /// The `Priority` value (RFC 9218), where a lower urgency is more
+    /// urgent.
+    pub fn priority(self) -> &'static str {
+        match self {
+            RequestClass::Navigation => "u=1",
+            RequestClass::Api => "u=3",
+            RequestClass::Asset => "u=5",
+        }
+    }
+}
+
+/// Set the `GOVUK-Request-Priority` and `Priority` headers, so origin
+/// queues and autoscaling can favour page loads during overloads.
+pub fn set_headers(settings: &Config, bereq: &mut Request) {
+    let class = classify(settings, bereq);
+    bereq.set_header(PRIORITY_HEADER_NAME, class.as_str());
+    bereq.set_header("Priority", class.priority());
+}
+
+/// Classify a request: everything on an assets host is an asset, paths
+/// in `request_priority.api_paths` are API requests, and otherwise the
+/// `Sec-Fetch-Dest` header decides.  Without that, page loads are told
+/// apart by `Sec-Fetch-Mode` or the `Accept` header, and assets by
+/// their file extension.
+pub fn classify(settings: &Config, req: &Request) -> RequestClass {
+    if settings.assets_profile {
+        return RequestClass::Asset;
+    }
+
+    let path = req.get_path();
+    if settings
+        .request_priority_api_paths
+        .iter()
+        .any(|pattern| matches_path_pattern(pattern, path))
+    {
+        return RequestClass::Api;
+    }
+
+    if let Some(destination) = req.get_header_str("Sec-Fetch-Dest") {
+        return if NAVIGATION_DESTINATIONS.contains(&destination) {
+            RequestClass::Navigation
+        } else if destination == "empty" {
+            RequestClass::Api
+        } else {
+            RequestClass::Asset
+        };
+    }
+
+    if req.get_header_str("Sec-Fetch-Mode") == Some("navigate") {
+        return RequestClass::Navigation;
+    }
+
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rfind('.').map(|index| &name[index + 1..]))
+        .map(|extension| extension.to_ascii_lowercase());
+    if let Some(extension) = extension {
+        if ASSET_EXTENSIONS.contains(&extension.as_str()) {
+            return RequestClass::Asset;
+        }
+        if extension == "json" {
+            return RequestClass::Api;
+        }
+    }
+
+    match cdn_http::negotiate_request(req, &NAVIGATION_MEDIA_TYPES) {
+        Some("text/html") => RequestClass::Navigation,
+        _ => RequestClass::Api,
+    }
+}
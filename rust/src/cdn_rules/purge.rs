@@ -0,0 +1,280 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_headers;
+use crate::cdn_http;
+use crate::cdn_rules::{ip_is_on_acl, matches_path_pattern, replay, signing};
+
+use fastly::http::request::SendError;
+use fastly::{Request, Response};
+use serde_json::{json, Value};
+use std::env;
+use std::net::IpAddr;
+
+/// Request header listing the surrogate keys to purge
+pub const SURROGATE_KEY_HEADER_NAME: &str = "Surrogate-Key";
+
+/// Request header saying a URL purge needs a `Fastly-Key`, even from
+/// an IP on the purge ACL
+const REQUIRES_AUTH_HEADER_NAME: &str = "Fastly-Purge-Requires-Auth";
+
+/// Request header holding the Fastly API token for a URL purge
+const FASTLY_KEY_HEADER_NAME: &str = "Fastly-Key";
+
+/// Surrogate key prefix for path-prefix purges.  The origin tags
+/// responses with `path:<prefix>` for every directory the path is in
+/// (eg, `path:/`, `path:/government/`, `path:/government/news/`).
+const PATH_PREFIX_KEY_PREFIX: &str = "path:";
+
+/// Media types the purge summary is available as
+const SUMMARY_MEDIA_TYPES: [&str; 1] = ["application/json"];
+
+/// Base URL of the Fastly API
+const FASTLY_API_URL: &str = "https://api.fastly.com";
+
+/// Handle a PURGE, by purging through the Fastly API.  The response
+/// is a JSON summary of what was purged, and PURGE requests (and their
+/// bodies) never reach the origin.
+///
+/// A PURGE by surrogate key (the `Surrogate-Key` header) or by path
+/// prefix (a path ending in `*`) is handled by `purge_by_keys`, and any
+/// other PURGE by `purge_by_url`.  Nothing is purged for a client
+/// which can't accept JSON.
+pub fn purge(settings: &Config, req: &Request) -> Option<Response> {
+    if req.get_method_str() != "PURGE" {
+        return None;
+    }
+
+    let keys = purge_keys(req);
+    if keys.is_empty() {
+        Some(purge_by_url(settings, req))
+    } else {
+        Some(purge_by_keys(settings, req, &keys))
+    }
+}
+
+/// Purge surrogate keys.  Only IPs on the purge ACL, or on the
+/// `purge.key_acls` ACLs for all of the keys, may do this, and the
+/// request must be signed (see `replay::verify`).
+fn purge_by_keys(settings: &Config, req: &Request, keys: &[String]) -> Response {
+    let allowed = match req.get_client_ip_addr() {
+        Some(client_ip) => keys
+            .iter()
+            .all(|key| may_purge_key(settings, &client_ip, key)),
+        None => false,
+    };
+    if !allowed {
+        return json_response(
+            403,
+            json!({ "status": "error", "error": "not allowed to purge" }),
+        );
+    }
+
+    if let Err(error) = replay::verify(settings, req, &keys.join(" ")) {
+        return json_response(403, json!({ "status": "error", "error": error }));
+    }
+
+    if cdn_http::negotiate_request(req, &SUMMARY_MEDIA_TYPES).is_none() {
+        return cdn_http::not_acceptable_response(&SUMMARY_MEDIA_TYPES);
+    }
+
+    let token = match signing::get_secret(settings, &settings.purge_api_token) {
+        Some(token) => token,
+        None => return no_token_response(),
+    };
+
+    let results = keys
+        .iter()
+        .map(|key| match purge_key(settings, &token, key) {
+            Ok(status) => json!({ "key": key, "status": status }),
+            Err(_) => json!({ "key": key, "error": "API request failed" }),
+        })
+        .collect::<Vec<_>>();
+
+    summary_response(results)
+}
+
+/// Purge the requested URL.  Like a Fastly PURGE, a request from an
+/// IP which isn't on the purge ACL, or with a
+/// `Fastly-Purge-Requires-Auth` header, needs a `Fastly-Key` header
+/// matching the API token.
+fn purge_by_url(settings: &Config, req: &Request) -> Response {
+    let on_acl = match req.get_client_ip_addr() {
+        Some(client_ip) => ip_is_on_acl(&settings.acl_fastlypurge, &client_ip, false),
+        None => false,
+    };
+    let requires_auth = !on_acl || req.contains_header(REQUIRES_AUTH_HEADER_NAME);
+
+    let token = match signing::get_secret(settings, &settings.purge_api_token) {
+        Some(token) => token,
+        None => return no_token_response(),
+    };
+
+    if requires_auth {
+        let authorised = req
+            .get_header_str(FASTLY_KEY_HEADER_NAME)
+            .map_or(false, |key| signing::secrets_match(&token, key));
+        if !authorised {
+            return json_response(
+                403,
+                json!({ "status": "error", "error": "missing or invalid Fastly-Key" }),
+            );
+        }
+    }
+
+    if cdn_http::negotiate_request(req, &SUMMARY_MEDIA_TYPES).is_none() {
+        return cdn_http::not_acceptable_response(&SUMMARY_MEDIA_TYPES);
+    }
+
+    let url = format!(
+        "{}{}",
+        req.get_header_str("host").unwrap_or(""),
+        req.get_url().path()
+    );
+    let result = match purge_url(settings, &token, &url) {
+        Ok(status) => json!({ "url": url, "status": status }),
+        Err(_) => json!({ "url": url, "error": "API request failed" }),
+    };
+
+    summary_response(vec![result])
+}
+
+/// Keep the origin's `Surrogate-Key` header (which the cache has
+/// already read) only on responses to clients which may purge some
+/// keys, so they can see what to purge, and hide it from everyone
+/// else.
+pub fn transform_beresp(settings: &Config, bereq: &Request, mut beresp: Response) -> Response {
+    let may_purge = cdn_headers::client_ip(bereq)
+        .map_or(false, |client_ip| may_purge_any_key(settings, &client_ip));
+    if !may_purge {
+        beresp.remove_header(SURROGATE_KEY_HEADER_NAME);
+    }
+    beresp
+}
+
+/// Check if a client may purge a surrogate key: if it's on the purge
+/// ACL, or on the ACL of a `purge.key_acls` entry matching the key.
+fn may_purge_key(settings: &Config, client_ip: &IpAddr, key: &str) -> bool {
+    ip_is_on_acl(&settings.acl_fastlypurge, client_ip, false)
+        || settings.purge_key_acls.iter().any(|key_acl| {
+            matches_path_pattern(&key_acl.key, key) && ip_is_on_acl(&key_acl.acl, client_ip, false)
+        })
+}
+
+/// Check if a client may purge any surrogate keys at all.
+fn may_purge_any_key(settings: &Config, client_ip: &IpAddr) -> bool {
+    ip_is_on_acl(&settings.acl_fastlypurge, client_ip, false)
+        || settings
+            .purge_key_acls
+            .iter()
+            .any(|key_acl| ip_is_on_acl(&key_acl.acl, client_ip, false))
+}
+
+/// Purge a surrogate key through the Fastly API, returning the API's
+/// response status.
+fn purge_key(settings: &Config, token: &str, key: &str) -> Result<u16, SendError> {
+    let url = format!(
+        "{}/service/{}/purge/{}",
+        FASTLY_API_URL,
+        env::var("FASTLY_SERVICE_ID").unwrap_or_default(),
+        percent_encode(key)
+    );
+    let resp = Request::post(url)
+        .with_header("Fastly-Key", token)
+        .with_header("Accept", "application/json")
+        .send(&settings.purge_api_backend)?;
+    Ok(resp.get_status().as_u16())
+}
+
+/// Purge a single URL (a host and path) through the Fastly API,
+/// returning the API's response status.
+fn purge_url(settings: &Config, token: &str, url: &str) -> Result<u16, SendError> {
+    let resp = Request::post(format!("{}/purge/{}", FASTLY_API_URL, url))
+        .with_header("Fastly-Key", token)
+        .with_header("Accept", "application/json")
+        .send(&settings.purge_api_backend)?;
+    Ok(resp.get_status().as_u16())
+}
+
+/// Get the surrogate keys to purge: either those in the
+/// `Surrogate-Key` header (space-separated), or the path-prefix key
+/// for a path ending in `*`.
+fn purge_keys(req: &Request) -> Vec<String> {
+    if let Some(keys) = req.get_header_str(SURROGATE_KEY_HEADER_NAME) {
+        return keys.split_whitespace().map(|key| key.to_string()).collect();
+    }
+
+    match req.get_path().strip_suffix('*') {
+        Some(prefix) => vec![format!("{}{}", PATH_PREFIX_KEY_PREFIX, prefix)],
+        None => Vec::new(),
+    }
+}
+
+/// Percent-encode a surrogate key for use in a URL path.
+fn percent_encode(key: &str) -> String {
+    key.bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+/// Build the summary of a purge from the result of each API request,
+/// which is a 502 unless they all succeeded.
+fn summary_response(results: Vec<Value>) -> Response {
+    let all_ok = results.iter().all(|result| result["status"] == 200);
+    json_response(
+        if all_ok { 200 } else { 502 },
+        json!({
+            "status": if all_ok { "ok" } else { "error" },
+            "purged": results,
+        }),
+    )
+}
+
+/// Build the error response for when there is no API token.
+fn no_token_response() -> Response {
+    json_response(500, json!({ "status": "error", "error": "no API token" }))
+}
+
+/// Build a JSON response.
+fn json_response(status: u16, body: Value) -> Response {
+    Response::from_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Purge.as_str())
+        .with_body(body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_come_from_header() {
+        let req = Request::get("https://www.gov.uk/*")
+            .with_header(SURROGATE_KEY_HEADER_NAME, " key-1  key-2 ");
+        assert_eq!(purge_keys(&req), vec!["key-1", "key-2"]);
+    }
+
+    #[test]
+    fn wildcard_path_purges_its_prefix_key() {
+        let req = Request::get("https://www.gov.uk/government/*");
+        assert_eq!(purge_keys(&req), vec!["path:/government/"]);
+    }
+
+    #[test]
+    fn other_paths_purge_nothing() {
+        let req = Request::get("https://www.gov.uk/government/");
+        assert!(purge_keys(&req).is_empty());
+    }
+
+    #[test]
+    fn keys_are_percent_encoded() {
+        assert_eq!(percent_encode("path:/a b/~c"), "path%3A%2Fa%20b%2F~c");
+        assert_eq!(percent_encode("key-1.2_3"), "key-1.2_3");
+    }
+}
@@ -0,0 +1,77 @@
+use crate::cdn_config::Config;
+use crate::cdn_rand;
+use crate::cdn_rules::matches_path_pattern;
+
+/// The query params of a request which aren't in the allowlist.
+pub struct UnknownParams {
+    pub pattern: String,
+    pub names: Vec<String>,
+}
+
+/// Strip the query params a path doesn't take, if the allowlist is
+/// enforced.
+pub fn enforce(settings: &Config, path: &str, qs: &mut Vec<(String, String)>) {
+    if settings.querystring_allowlist_mode != "enforce" {
+        return;
+    }
+
+    if let Some(allowed) = allowed_params(settings, path) {
+        qs.retain(|(name, _)| allowed.contains(name));
+    }
+}
+
+/// Find the query params a path doesn't take, if the allowlist is
+/// being learned, for `querystring.learn_percentage` percent of
+/// requests, to log against the path's pattern in the allowlist (or
+/// `/<first segment>*`, for paths not in it).
+///
+/// Instances don't live long enough to count params themselves, so
+/// each sampled request is logged once, and the counts are for
+/// whatever reads the logs to add up.
+pub fn learn(settings: &Config, path: &str, qs: &[(String, String)]) -> Option<UnknownParams> {
+    if settings.querystring_allowlist_mode != "learn" || qs.is_empty() {
+        return None;
+    }
+
+    let (pattern, allowed) = match allowlist_entry(settings, path) {
+        Some((pattern, allowed)) => (pattern.clone(), allowed.as_slice()),
+        None => (first_segment_pattern(path), &[][..]),
+    };
+
+    let mut names: Vec<String> = qs
+        .iter()
+        .map(|(name, _)| name)
+        .filter(|name| !allowed.contains(name))
+        .cloned()
+        .collect();
+    if names.is_empty() || !cdn_rand::percent_chance(settings.querystring_learn_percentage) {
+        return None;
+    }
+
+    names.sort();
+    names.dedup();
+    Some(UnknownParams { pattern, names })
+}
+
+/// The query params a path takes, if it's in the allowlist.
+fn allowed_params<'a>(settings: &'a Config, path: &str) -> Option<&'a Vec<String>> {
+    allowlist_entry(settings, path).map(|(_, allowed)| allowed)
+}
+
+/// The allowlist entry for a path: the longest pattern which matches
+/// it, and its params.
+fn allowlist_entry<'a>(settings: &'a Config, path: &str) -> Option<(&'a String, &'a Vec<String>)> {
+    settings
+        .querystring_allowlist
+        .iter()
+        .filter(|(pattern, _)| matches_path_pattern(pattern, path))
+        .max_by_key(|(pattern, _)| pattern.len())
+}
+
+/// The prefix pattern for a path's first segment, eg `/search*` for
+/// `/search/all`.
+fn first_segment_pattern(path: &str) -> String {
+    let rest = path.trim_start_matches('/');
+    let segment = rest.split('/').next().unwrap_or("");
+    format!("/{}*", segment)
+}
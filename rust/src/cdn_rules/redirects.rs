@@ -0,0 +1,10 @@
+// Generated by `build.rs` from `redirects.tsv`, sorted by path.
+include!(concat!(env!("OUT_DIR"), "/redirects.rs"));
+
+/// Look up a path in the bulk redirect table.
+pub fn lookup(path: &str) -> Option<&'static str> {
+    REDIRECTS
+        .binary_search_by(|(from, _)| (*from).cmp(path))
+        .ok()
+        .map(|index| REDIRECTS[index].1)
+}
@@ -0,0 +1,63 @@
+use crate::cdn_clock;
+use crate::cdn_config::Config;
+use crate::cdn_rules::signing;
+
+use fastly::Request;
+
+/// Request header holding the Unix timestamp the request was signed at
+const TIMESTAMP_HEADER_NAME: &str = "GOVUK-Admin-Timestamp";
+
+/// Request header holding a random string, unique to the request
+const NONCE_HEADER_NAME: &str = "GOVUK-Admin-Nonce";
+
+/// Request header holding the signature
+const SIGNATURE_HEADER_NAME: &str = "GOVUK-Admin-Signature";
+
+/// Check that an admin request (such as a purge) is freshly signed.
+///
+/// The signature is the hex HMAC-SHA256, with the `admin.secret` key,
+/// of the method, path, timestamp, nonce, and the extra `signed`
+/// value (eg, the surrogate keys), separated by newlines.  The
+/// timestamp must be within `admin.max_age_s` of now.
+///
+/// Replay protection is timestamp-only: instances share no state, so
+/// there's nowhere to remember which nonces have been used, and a
+/// captured request can be replayed until it's `admin.max_age_s` old.
+/// The nonce just makes each signature unique.
+pub fn verify(settings: &Config, req: &Request, signed: &str) -> Result<(), &'static str> {
+    let timestamp = req
+        .get_header_str(TIMESTAMP_HEADER_NAME)
+        .ok_or("missing timestamp")?;
+    let nonce = req
+        .get_header_str(NONCE_HEADER_NAME)
+        .ok_or("missing nonce")?;
+    let signature = req
+        .get_header_str(SIGNATURE_HEADER_NAME)
+        .ok_or("missing signature")?;
+
+    let signed_at = timestamp.parse::<u64>().map_err(|_| "invalid timestamp")?;
+    let now = cdn_clock::unix_time();
+    let age = if now > signed_at {
+        now - signed_at
+    } else {
+        signed_at - now
+    };
+    if age > settings.admin_max_age.as_secs() {
+        return Err("stale timestamp");
+    }
+
+    let secret = signing::get_secret(settings, &settings.admin_secret).ok_or("no secret")?;
+    let message = format!(
+        "{}\n{}\n{}\n{}\n{}",
+        req.get_method_str(),
+        req.get_path(),
+        timestamp,
+        nonce,
+        signed
+    );
+    if !signing::verify(&secret, &message, signature) {
+        return Err("invalid signature");
+    }
+
+    Ok(())
+}
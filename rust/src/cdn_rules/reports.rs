@@ -0,0 +1,337 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_logging;
+use crate::cdn_rand;
+use crate::cdn_rules::{ab_tests, experiments, get_cookies};
+
+use fastly::geo::geo_lookup;
+use fastly::http::header;
+use fastly::{Request, Response};
+use serde_json::{json, Map, Value};
+use std::io::Read;
+
+/// Path browsers send CSP violation reports to
+pub const CSP_REPORTS_PATH: &str = "/__edge/csp-reports";
+
+/// Path browsers send network error reports to
+pub const NEL_REPORTS_PATH: &str = "/__edge/nel-reports";
+
+/// Path pages send analytics beacons to
+pub const BEACON_PATH: &str = "/__edge/beacon";
+
+/// Reporting API group network error reports are sent to
+const NEL_GROUP_NAME: &str = "network-errors";
+
+/// Collect a CSP violation report, so a report-only policy can be
+/// rolled out without an origin app to receive the reports.
+///
+/// Both the `report-uri` format (a `csp-report` object) and the
+/// Reporting API format (an array of reports with a `body`) are
+/// accepted.  `csp_reports.percentage` percent of valid reports are
+/// logged, as `csp_report` events, to the `csp_reports.endpoint`
+/// logging endpoint; oversized or malformed bodies are dropped.
+pub fn csp(settings: &Config, req: &mut Request) -> Option<Response> {
+    if req.get_path() != CSP_REPORTS_PATH {
+        return None;
+    }
+
+    if req.get_method_str() != "POST" {
+        return Some(report_response(405).with_header("Allow", "POST"));
+    }
+
+    let body = match read_body(req, settings.csp_reports_max_size) {
+        Some(body) => body,
+        None => return Some(report_response(413)),
+    };
+
+    let reports = match parse_csp_reports(&body) {
+        Some(reports) => reports,
+        None => return Some(report_response(400)),
+    };
+
+    for report in reports {
+        if cdn_rand::percent_chance(settings.csp_reports_percentage) {
+            log_report(
+                settings.csp_reports_endpoint.as_deref(),
+                "csp_report",
+                report,
+            );
+        }
+    }
+
+    Some(report_response(204))
+}
+
+/// Collect network error reports, giving visibility into clients
+/// failing to connect (or getting errors) which never show up in the
+/// origin logs.
+///
+/// Every valid report is logged, as a `nel_report` event, to the
+/// `nel.endpoint` logging endpoint: sampling is done by the browser,
+/// using the fractions in the `NEL` header.  Oversized or malformed
+/// bodies are dropped.
+pub fn nel(settings: &Config, req: &mut Request) -> Option<Response> {
+    if req.get_path() != NEL_REPORTS_PATH {
+        return None;
+    }
+
+    if req.get_method_str() != "POST" {
+        return Some(report_response(405).with_header("Allow", "POST"));
+    }
+
+    let body = match read_body(req, settings.nel_max_size) {
+        Some(body) => body,
+        None => return Some(report_response(413)),
+    };
+
+    let reports = match parse_nel_reports(&body) {
+        Some(reports) => reports,
+        None => return Some(report_response(400)),
+    };
+
+    for report in reports {
+        log_report(settings.nel_endpoint.as_deref(), "nel_report", report);
+    }
+
+    Some(report_response(204))
+}
+
+/// Collect a first-party analytics beacon, so pages don't need a
+/// third-party collector on the critical path.
+///
+/// A beacon is a JSON object with a string `type`, whose values are
+/// all strings, numbers, booleans, or null.
+/// `beacons.percentage` percent of valid beacons are logged, as
+/// `beacon` events, to the `beacons.endpoint` logging endpoint, along
+/// with the client's country, device class (from the User-Agent), and
+/// A/B test variants (from their cookies, if they have consented);
+/// oversized or malformed bodies are dropped.
+pub fn beacon(settings: &Config, req: &mut Request) -> Option<Response> {
+    if req.get_path() != BEACON_PATH {
+        return None;
+    }
+
+    if req.get_method_str() != "POST" {
+        return Some(report_response(405).with_header("Allow", "POST"));
+    }
+
+    let body = match read_body(req, settings.beacons_max_size) {
+        Some(body) => body,
+        None => return Some(report_response(413)),
+    };
+
+    let beacon = match parse_beacon(&body) {
+        Some(beacon) => beacon,
+        None => return Some(report_response(400)),
+    };
+
+    if cdn_rand::percent_chance(settings.beacons_percentage) {
+        let mut fields = Map::new();
+        fields.insert("event".to_string(), Value::from("beacon"));
+        fields.insert("beacon".to_string(), Value::Object(beacon));
+        fields.insert(
+            "country".to_string(),
+            req.get_client_ip_addr()
+                .and_then(geo_lookup)
+                .map_or(Value::Null, |geo| Value::from(geo.country_code())),
+        );
+        fields.insert(
+            "device_class".to_string(),
+            Value::from(device_class(req.get_header_str(header::USER_AGENT))),
+        );
+        fields.insert(
+            "ab_tests".to_string(),
+            Value::Object(ab_test_variants(settings, req)),
+        );
+        cdn_logging::write_line(
+            settings.beacons_endpoint.as_deref(),
+            &Value::Object(fields).to_string(),
+        );
+    }
+
+    Some(report_response(204))
+}
+
+/// Add the `Report-To` and `NEL` headers, telling browsers to send
+/// network error reports to `nel.report_url`, if `nel.enabled` is set.
+pub fn transform_beresp(settings: &Config, mut resp: Response) -> Response {
+    if !settings.nel_enabled {
+        return resp;
+    }
+
+    let report_to = json!({
+        "group": NEL_GROUP_NAME,
+        "max_age": settings.nel_max_age,
+        "endpoints": [{ "url": settings.nel_report_url }],
+    });
+    let nel = json!({
+        "report_to": NEL_GROUP_NAME,
+        "max_age": settings.nel_max_age,
+        "success_fraction": settings.nel_success_fraction,
+        "failure_fraction": settings.nel_failure_fraction,
+    });
+
+    resp.set_header("Report-To", report_to.to_string());
+    resp.set_header("NEL", nel.to_string());
+    resp
+}
+
+/// Read the request body, if it's no bigger than `max_size` bytes.
+pub fn read_body(req: &mut Request, max_size: usize) -> Option<Vec<u8>> {
+    let declared_size = req
+        .get_header_str(header::CONTENT_LENGTH)
+        .and_then(|length| length.parse::<usize>().ok());
+    if declared_size.map_or(false, |size| size > max_size) {
+        return None;
+    }
+
+    let mut body = Vec::new();
+    req.take_body()
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut body)
+        .ok()?;
+
+    if body.len() > max_size {
+        None
+    } else {
+        Some(body)
+    }
+}
+
+/// Parse a body of CSP violation reports.  Each report must say which
+/// document the violation was in, and which directive it violated.
+fn parse_csp_reports(body: &[u8]) -> Option<Vec<Map<String, Value>>> {
+    let reports = match serde_json::from_slice(body).ok()? {
+        Value::Object(mut object) => match object.remove("csp-report") {
+            Some(Value::Object(report)) => vec![report],
+            _ => return None,
+        },
+        Value::Array(array) => array
+            .into_iter()
+            .map(|report| match report {
+                Value::Object(mut object)
+                    if object.get("type") == Some(&Value::from("csp-violation")) =>
+                {
+                    match object.remove("body") {
+                        Some(Value::Object(report)) => Some(report),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+
+    let is_valid = |report: &Map<String, Value>, document_key: &str, directive_key: &str| {
+        report.get(document_key).map_or(false, Value::is_string)
+            && report.get(directive_key).map_or(false, Value::is_string)
+    };
+    let all_valid = !reports.is_empty()
+        && reports.iter().all(|report| {
+            is_valid(report, "document-uri", "violated-directive")
+                || is_valid(report, "documentURL", "effectiveDirective")
+        });
+
+    if all_valid {
+        Some(reports)
+    } else {
+        None
+    }
+}
+
+/// Parse a body of network error reports: a Reporting API array of
+/// reports, each with the URL it's about and a `body` giving the type
+/// of error.
+fn parse_nel_reports(body: &[u8]) -> Option<Vec<Map<String, Value>>> {
+    let array = match serde_json::from_slice(body).ok()? {
+        Value::Array(array) if !array.is_empty() => array,
+        _ => return None,
+    };
+
+    array
+        .into_iter()
+        .map(|report| match report {
+            Value::Object(object)
+                if object.get("type") == Some(&Value::from("network-error"))
+                    && object.get("url").map_or(false, Value::is_string)
+                    && object
+                        .get("body")
+                        .and_then(|body| body.get("type"))
+                        .map_or(false, Value::is_string) =>
+            {
+                Some(object)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse an analytics beacon: a flat JSON object with a string `type`.
+fn parse_beacon(body: &[u8]) -> Option<Map<String, Value>> {
+    match serde_json::from_slice(body).ok()? {
+        Value::Object(object)
+            if object.get("type").map_or(false, Value::is_string)
+                && object
+                    .values()
+                    .all(|value| !value.is_object() && !value.is_array()) =>
+        {
+            Some(object)
+        }
+        _ => None,
+    }
+}
+
+/// Classify a User-Agent as `mobile`, `tablet`, `desktop`, or
+/// `unknown` (if there isn't one).
+fn device_class(user_agent: Option<&str>) -> &'static str {
+    let user_agent = match user_agent {
+        Some(user_agent) => user_agent,
+        None => return "unknown",
+    };
+
+    if user_agent.contains("iPad") || user_agent.contains("Tablet") {
+        "tablet"
+    } else if user_agent.contains("Mobi") || user_agent.contains("Android") {
+        "mobile"
+    } else {
+        "desktop"
+    }
+}
+
+/// Get the variants of the active A/B tests a client is in, from their
+/// `ABTest-<Name>` cookies, if they have consented to A/B tests.
+fn ab_test_variants(settings: &Config, req: &Request) -> Map<String, Value> {
+    let cookies = get_cookies(req.get_header_str("cookie"));
+    let mut variants = Map::new();
+    if !ab_tests::has_consented_to_ab_tests(settings, &cookies) {
+        return variants;
+    }
+
+    for (name, ab_test) in experiments::ab_tests(settings).iter() {
+        if !ab_test.active {
+            continue;
+        }
+        if let Some(variant) = cookies.get(&format!("ABTest-{}", name)) {
+            if ab_test.variants.contains_key(variant) {
+                variants.insert(name.clone(), Value::from(variant.as_str()));
+            }
+        }
+    }
+    variants
+}
+
+/// Log a report, as an event, to a logging endpoint (or stderr).
+fn log_report(endpoint: Option<&str>, event: &str, report: Map<String, Value>) {
+    let mut fields = Map::new();
+    fields.insert("event".to_string(), Value::from(event));
+    fields.insert("report".to_string(), Value::Object(report));
+    cdn_logging::write_line(endpoint, &Value::Object(fields).to_string());
+}
+
+/// A synthetic response to a report submission.
+fn report_response(status: u16) -> Response {
+    Response::from_status(status)
+        .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Report.as_str())
+        .with_header("Cache-Control", "no-store")
+}
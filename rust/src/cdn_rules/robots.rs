@@ -0,0 +1,30 @@
+use crate::cdn_config::Config;
+
+use fastly::{Request, Response};
+
+/// Environment which search engines may index
+const PRODUCTION_ENVIRONMENT: &str = "production";
+
+/// Keep search engines out of non-production environments, and out of
+/// the paths in `robots.noindex_paths`.
+///
+/// Paths are prefixes of the path and querystring, so `/search?`
+/// matches searches but not the search page itself.
+pub fn transform_beresp(settings: &Config, bereq: &Request, mut resp: Response) -> Response {
+    let target = match bereq.get_query_str() {
+        Some(query) => format!("{}?{}", bereq.get_path(), query),
+        None => bereq.get_path().to_string(),
+    };
+
+    let noindex = settings.environment != PRODUCTION_ENVIRONMENT
+        || settings
+            .robots_noindex_paths
+            .iter()
+            .any(|prefix| target.starts_with(prefix));
+
+    if noindex {
+        resp.set_header("X-Robots-Tag", "noindex, nofollow");
+    }
+
+    resp
+}
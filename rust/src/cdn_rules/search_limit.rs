@@ -0,0 +1,128 @@
+use crate::cdn_config::Config;
+use crate::cdn_rules::memo::Lru;
+
+use fastly::Request;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Query parameters which don't count as filters
+const NON_FILTER_PARAMS: [&str; 3] = ["q", "page", "order"];
+
+/// How many clients to remember the token buckets of
+const BUCKETS_CACHE_SIZE: usize = 4096;
+
+thread_local! {
+    /// Token buckets, by client IP, holding the tokens left and when they
+    /// were last counted.
+    static BUCKETS: RefCell<Lru<IpAddr, (f64, Instant)>> = RefCell::new(Lru::new(BUCKETS_CACHE_SIZE));
+}
+
+/// Check a search request against the client's token bucket, returning
+/// how long to wait before trying again if it can't be afforded.
+///
+/// Each search costs one token, plus one for every filter, plus one
+/// for every `search_rate_limit.query_length_cost` characters of query
+/// string; so long queries and many filters, which are expensive for
+/// the search backend, run out sooner.  No search costs more than a
+/// full bucket, so a long query is slowed down rather than refused
+/// outright.  Buckets hold up to `search_rate_limit.capacity` tokens,
+/// refilling at `search_rate_limit.refill_per_second`, and are kept
+/// per client IP: not per edge ID, which clients choose themselves, so
+/// a fresh cookie on every request would get a fresh bucket.
+///
+/// Buckets are only kept by this instance: Compute@Edge instances share
+/// no state, and this SDK has no rate limiting API, so a client whose
+/// requests are spread over many instances is hardly limited at all.
+/// This only catches bursts which one instance sees.
+pub fn check(settings: &Config, req: &Request) -> Option<Duration> {
+    if settings.search_rate_limit_capacity == 0 || req.get_path() != settings.search_rate_limit_path
+    {
+        return None;
+    }
+
+    let client = req.get_client_ip_addr()?;
+
+    let capacity = settings.search_rate_limit_capacity as f64;
+    let cost = cost(req, settings.search_rate_limit_query_length_cost).min(capacity);
+    let refill = settings.search_rate_limit_refill_per_second as f64;
+    let now = Instant::now();
+
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        let bucket = buckets.get_or_insert_with(&client, || (capacity, now));
+        let (bucket, wait) = take(bucket, cost, capacity, refill, now);
+        buckets.replace(&client, bucket);
+        wait
+    })
+}
+
+/// Take `cost` tokens from a bucket (of the tokens left, and when they
+/// were last counted), after refilling it.  Returns the new bucket, and
+/// how long to wait if there weren't enough tokens, in which case none
+/// are taken.
+fn take(
+    (tokens, updated): (f64, Instant),
+    cost: f64,
+    capacity: f64,
+    refill: f64,
+    now: Instant,
+) -> ((f64, Instant), Option<Duration>) {
+    let tokens = (tokens + now.duration_since(updated).as_secs_f64() * refill).min(capacity);
+    if tokens >= cost {
+        ((tokens - cost, now), None)
+    } else {
+        let wait = (cost - tokens) / refill;
+        ((tokens, now), Some(Duration::from_secs_f64(wait.ceil())))
+    }
+}
+
+/// The number of tokens a search costs, with another token every
+/// `query_length_cost` characters of query string.
+fn cost(req: &Request, query_length_cost: usize) -> f64 {
+    let query_length = req.get_query_str().map_or(0, str::len);
+    let filters = req
+        .get_query::<Vec<(String, String)>>()
+        .unwrap_or_default()
+        .iter()
+        .filter(|(name, _)| !NON_FILTER_PARAMS.contains(&name.as_str()))
+        .count();
+    let length_cost = match query_length_cost {
+        0 => 0,
+        per_token => query_length / per_token,
+    };
+
+    (1 + filters + length_cost) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches_cost_more_with_filters_and_long_queries() {
+        let req = Request::get("https://www.gov.uk/search?q=tax");
+        assert_eq!(cost(&req, 10) as usize, 1);
+
+        let req = Request::get("https://www.gov.uk/search?q=tax&order=a&topic=b&org=c");
+        assert_eq!(cost(&req, 10) as usize, 5);
+    }
+
+    #[test]
+    fn buckets_run_out_and_refill() {
+        let start = Instant::now();
+        let (bucket, wait) = take((3.0, start), 2.0, 3.0, 1.0, start);
+        assert_eq!((bucket.0 as usize, wait), (1, None));
+
+        let (bucket, wait) = take(bucket, 2.0, 3.0, 1.0, start);
+        assert_eq!((bucket.0 as usize, wait), (1, Some(Duration::from_secs(1))));
+
+        let later = start + Duration::from_secs(1);
+        let (bucket, wait) = take(bucket, 2.0, 3.0, 1.0, later);
+        assert_eq!((bucket.0 as usize, wait), (0, None));
+
+        let much_later = later + Duration::from_secs(60);
+        let (bucket, _) = take(bucket, 0.0, 3.0, 1.0, much_later);
+        assert_eq!(bucket.0 as usize, 3);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::cdn_config::Config;
+
+use fastly::handle::dictionary::DictionaryHandle;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// Longest secret which can be read from the secrets dictionary
+const MAX_SECRET_LENGTH: usize = 8000;
+
+/// Look up a secret in the secrets Edge Dictionary.
+///
+/// Returns `None` if the dictionary or the secret do not exist.
+pub fn get_secret(settings: &Config, name: &str) -> Option<String> {
+    DictionaryHandle::open(&settings.secrets_dictionary)
+        .ok()
+        .filter(|handle| handle.is_valid())
+        .and_then(|handle| handle.get(name, MAX_SECRET_LENGTH).ok())
+        .flatten()
+}
+
+/// Sign a message with HMAC-SHA256, giving a hex-encoded signature.
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(message.as_bytes());
+    to_hex(&mac.finalize().into_bytes())
+}
+
+/// Check a hex-encoded HMAC-SHA256 signature, in constant time.
+pub fn verify(secret: &str, message: &str, signature: &str) -> bool {
+    let signature_bytes = match from_hex(signature) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(message.as_bytes());
+    mac.verify(&signature_bytes).is_ok()
+}
+
+/// Compare two secrets, in constant time for secrets of the same
+/// length.
+pub fn secrets_match(secret: &str, candidate: &str) -> bool {
+    secret.len() == candidate.len()
+        && secret
+            .bytes()
+            .zip(candidate.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Encode bytes as lowercase hex.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode a hex string.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
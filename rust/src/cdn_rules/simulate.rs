@@ -0,0 +1,234 @@
+use crate::cdn_config::Config;
+use crate::cdn_headers;
+use crate::cdn_rules::{
+    ab_tests, authorized, classify_path, crawler, edge_id, get_cookies, internal, ip_is_on_acl,
+    is_embargoed, is_rejected_method, is_special_redirect, locales, mark_redirect,
+    normalise_querystring, options, pagination, paths, priority, redirects, reports, ssl,
+};
+
+use fastly::http::header;
+use fastly::Request;
+use std::net::IpAddr;
+
+/// What the edge would do at one step of handling a request.
+pub struct Decision {
+    /// The rule
+    pub step: &'static str,
+    /// What it decided
+    pub outcome: String,
+}
+
+/// Work out what the edge would do with a client request, without
+/// sending it anywhere: which synthetic response it would get (if
+/// any), and otherwise what the backend request would look like.
+///
+/// This follows the same steps as `synthetic_response` and
+/// `build_bereq`, except for those which need the Fastly runtime
+/// (cookie sanitisation, secrets, purging, cachebusting, the search
+/// rate limit, the request body checks, the remote experiments
+/// manifest, and the A/B test safety valve), so it can be run outside
+/// Fastly.
+pub fn recv(settings: &Config, req: &Request, client_ip: Option<IpAddr>) -> Vec<Decision> {
+    let mut decisions = Vec::new();
+    let mut decide = |step: &'static str, outcome: String| {
+        decisions.push(Decision { step, outcome });
+    };
+
+    let path = match paths::normalise(req.get_path()) {
+        Some(path) => path,
+        None => {
+            decide("normalise_path", "400 (invalid path)".to_string());
+            return decisions;
+        }
+    };
+    if path != req.get_path() {
+        decide("normalise_path", format!("path is {}", path));
+    }
+    let mut req = req.clone_without_body();
+    req.set_path(&path);
+
+    if path == reports::CSP_REPORTS_PATH
+        || path == reports::NEL_REPORTS_PATH
+        || path == reports::BEACON_PATH
+    {
+        decide("collect_report", "collected as a report".to_string());
+        return decisions;
+    }
+
+    if let Some(client_ip) = client_ip {
+        if !ip_is_on_acl(&settings.acl_allowlist, &client_ip, true) {
+            decide("acl_allowlist", "403 (not on the allowlist)".to_string());
+            return decisions;
+        }
+        if ip_is_on_acl(&settings.acl_denylist, &client_ip, false) {
+            decide("acl_denylist", "403 (on the denylist)".to_string());
+            return decisions;
+        }
+    }
+
+    if is_rejected_method(settings, req.get_method_str()) {
+        decide("rejected_methods", "405".to_string());
+        return decisions;
+    }
+
+    if req.get_method_str() == "PURGE" {
+        decide(
+            "purge",
+            "handled by the purge rules (not simulated)".to_string(),
+        );
+        return decisions;
+    }
+
+    if internal::is_internal_path(settings, req.get_path()) {
+        let on_acl = client_ip.map_or(false, |client_ip| {
+            ip_is_on_acl(&settings.acl_internal, &client_ip, false)
+        });
+        let has_token = req
+            .get_header_str("Authorization")
+            .map_or(false, |value| value.starts_with("Bearer "));
+        if !on_acl && !has_token {
+            decide("internal_paths", "404 (internal path)".to_string());
+            return decisions;
+        }
+        if !on_acl {
+            decide(
+                "internal_paths",
+                "404 unless the bearer token is valid (not simulated)".to_string(),
+            );
+        }
+    }
+
+    if !authorized(settings, &req) {
+        decide("authorization", "401".to_string());
+        return decisions;
+    }
+
+    if let Some(response) = ssl::redirect(settings, &req) {
+        let outcome = match response.get_header_str("Location") {
+            Some(location) => format!("{} to {}", response.get_status().as_u16(), location),
+            None => response.get_status().as_u16().to_string(),
+        };
+        decide("ssl", outcome);
+        return decisions;
+    }
+
+    if path == ab_tests::REPORT_PATH && req.get_method_str() == "GET" {
+        let on_acl = client_ip.map_or(false, |client_ip| {
+            ip_is_on_acl(&settings.acl_abtest_report, &client_ip, false)
+        });
+        let outcome = if on_acl {
+            "200 (A/B test report)"
+        } else {
+            "403 (not on the abtest_report ACL)"
+        };
+        decide("ab_test_report", outcome.to_string());
+        return decisions;
+    }
+
+    if options::recv(settings, &req).is_some() {
+        decide("options", "204 (synthetic OPTIONS)".to_string());
+        return decisions;
+    }
+
+    if pagination::recv(settings, &req).is_some() {
+        decide("search_pagination", "400 (absurd pagination)".to_string());
+        return decisions;
+    }
+
+    if classify_path(settings, &path).not_found {
+        decide("special_paths.not_found", "404".to_string());
+        return decisions;
+    }
+
+    if is_embargoed(settings, &path) {
+        decide("special_paths.embargo", "404 (embargoed)".to_string());
+        return decisions;
+    }
+
+    if let Some(destination) = is_special_redirect(settings, &path) {
+        decide(
+            "special_paths.redirect",
+            format!("302 to {}", mark_redirect(settings, destination)),
+        );
+        return decisions;
+    }
+
+    let cookies = get_cookies(req.get_header_str("cookie"));
+    if let Some(response) = locales::redirect(settings, &cookies, &req) {
+        let destination = response.get_header_str(header::LOCATION).unwrap_or("");
+        decide("locale_redirect", format!("302 to {}", destination));
+        return decisions;
+    }
+
+    if let Some(destination) = redirects::lookup(&path) {
+        decide(
+            "bulk_redirect",
+            format!("301 to {}", mark_redirect(settings, destination)),
+        );
+        return decisions;
+    }
+
+    let mut bereq = req.clone_without_body();
+    let query = normalise_querystring(settings, &req)
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&");
+    if query.is_empty() {
+        decide("querystring", "none".to_string());
+    } else {
+        decide("querystring", format!("?{}", query));
+    }
+
+    decide(
+        "request_priority",
+        priority::classify(settings, &req).as_str().to_string(),
+    );
+
+    let method = req.get_method_str();
+    if method != "HEAD" && method != "GET" {
+        decide("pass", format!("{} request", method));
+    }
+    if classify_path(settings, &path).personalised {
+        decide("pass", "personalised path".to_string());
+    }
+    for (name, threshold) in &[
+        (header::COOKIE, settings.pass_cookie_size),
+        (header::AUTHORIZATION, settings.pass_authorization_size),
+    ] {
+        let size = req.get_header(name).map_or(0, |value| value.len());
+        if size > *threshold {
+            decide("pass", format!("{} header is {} bytes", name, size));
+        }
+    }
+
+    if let Some(client_ip) = client_ip {
+        cdn_headers::set_client_ip(&mut bereq, &client_ip.to_string());
+    }
+    if !settings.assets_profile {
+        let crawler = crawler::is_crawler_worker(settings, &bereq);
+        if crawler {
+            decide("crawler", "crawler worker (cookies removed)".to_string());
+        } else {
+            edge_id::transform_bereq(settings, &cookies, &mut bereq);
+        }
+        ab_tests::assign(
+            settings,
+            &settings.ab_tests,
+            &cookies,
+            &mut bereq,
+            crawler,
+            |_, _| false,
+        );
+
+        let mut ab_tests = settings.ab_tests.iter().collect::<Vec<_>>();
+        ab_tests.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, ab_test) in ab_tests {
+            if let Some(variant) = bereq.get_header_str(ab_test.variant_header_name(name)) {
+                decide("ab_test", format!("{} = {}", name, variant));
+            }
+        }
+    }
+
+    decisions
+}
@@ -0,0 +1,65 @@
+use crate::cdn_config::Config;
+use crate::cdn_rules::reports;
+
+use fastly::Request;
+
+/// Why a submission is rejected if its body is bigger than
+/// `form_spam.max_size`.
+pub const TOO_BIG: &str = "too big";
+
+/// Check a POST to a form path for obvious spam, returning the reason
+/// to reject it, if it should be.
+///
+/// A submission is spam if its body is bigger than
+/// `form_spam.max_size`, if it has more than `form_spam.max_fields`
+/// fields, or if it fills in the `form_spam.honeypot_field` (which is
+/// hidden from people, but not from bots).  Only
+/// `application/x-www-form-urlencoded` bodies have their fields
+/// checked.  No more than `form_spam.max_size` bytes of the body are
+/// read.
+pub fn check(settings: &Config, req: &mut Request) -> Option<&'static str> {
+    if req.get_method_str() != "POST"
+        || !settings
+            .form_spam_paths
+            .iter()
+            .any(|path| path == req.get_path())
+    {
+        return None;
+    }
+
+    let body = match reports::read_body(req, settings.form_spam_max_size) {
+        Some(body) => body,
+        None => return Some(TOO_BIG),
+    };
+    let reason = if is_form(req) {
+        let mut fields = 0;
+        let mut honeypot = false;
+        for (name, value) in form_urlencoded::parse(&body) {
+            fields += 1;
+            if name == settings.form_spam_honeypot_field.as_str() && !value.is_empty() {
+                honeypot = true;
+            }
+        }
+
+        if honeypot {
+            Some("honeypot")
+        } else if fields > settings.form_spam_max_fields {
+            Some("too many fields")
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    req.set_body(body);
+
+    reason
+}
+
+/// Check if a request body is a urlencoded form.
+fn is_form(req: &Request) -> bool {
+    match req.get_content_type() {
+        Some(mime) => mime.essence_str() == "application/x-www-form-urlencoded",
+        None => false,
+    }
+}
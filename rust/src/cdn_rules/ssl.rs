@@ -0,0 +1,91 @@
+use crate::cdn_backend_name::{BackendNameTag, BACKEND_NAME_HEADER_NAME};
+use crate::cdn_config::Config;
+use crate::cdn_headers::FASTLY_SSL_HEADER_NAME;
+
+use fastly::http::Method;
+use fastly::{Request, Response};
+
+/// Redirect a plain HTTP request to HTTPS.  GET and HEAD get a 301;
+/// other methods get a 308, so the method and body are kept, or a 403
+/// if `ssl.redirect_unsafe_methods` is off.
+pub fn redirect(settings: &Config, req: &Request) -> Option<Response> {
+    if req.contains_header(FASTLY_SSL_HEADER_NAME) {
+        return None;
+    }
+
+    let safe = req.get_method() == Method::GET || req.get_method() == Method::HEAD;
+    if !safe && !settings.ssl_redirect_unsafe_methods() {
+        return Some(
+            Response::from_status(403)
+                .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Ssl.as_str()),
+        );
+    }
+
+    let mut url = req.get_url().clone();
+    // This only fails for URLs which can't carry an https scheme, which
+    // we can't redirect anyway.
+    url.set_scheme("https").ok()?;
+    Some(
+        Response::from_status(if safe { 301 } else { 308 })
+            .with_header("Location", url.to_string())
+            .with_header(BACKEND_NAME_HEADER_NAME, BackendNameTag::Ssl.as_str()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdn_config::ConfigBuilder;
+
+    fn settings() -> Config {
+        ConfigBuilder::new().build()
+    }
+
+    fn request(method: Method) -> Request {
+        Request::new(method, "http://www.gov.uk/search?q=tax")
+    }
+
+    #[test]
+    fn https_requests_are_not_redirected() {
+        let req = request(Method::POST).with_header(FASTLY_SSL_HEADER_NAME, "1");
+        assert!(redirect(&settings(), &req).is_none());
+    }
+
+    #[test]
+    fn get_and_head_get_a_301() {
+        for method in &[Method::GET, Method::HEAD] {
+            let resp = redirect(&settings(), &request(method.clone())).unwrap();
+            assert_eq!(resp.get_status().as_u16(), 301);
+            assert_eq!(
+                resp.get_header_str("Location"),
+                Some("https://www.gov.uk/search?q=tax")
+            );
+        }
+    }
+
+    #[test]
+    fn other_methods_get_a_308() {
+        for method in &[Method::POST, Method::PUT, Method::DELETE] {
+            let resp = redirect(&settings(), &request(method.clone())).unwrap();
+            assert_eq!(resp.get_status().as_u16(), 308);
+            assert_eq!(
+                resp.get_header_str("Location"),
+                Some("https://www.gov.uk/search?q=tax")
+            );
+        }
+    }
+
+    #[test]
+    fn other_methods_can_be_rejected() {
+        let settings = ConfigBuilder::new()
+            .ssl_redirect_unsafe_methods(false)
+            .build();
+
+        let resp = redirect(&settings, &request(Method::POST)).unwrap();
+        assert_eq!(resp.get_status().as_u16(), 403);
+        assert!(resp.get_header_str("Location").is_none());
+
+        let resp = redirect(&settings, &request(Method::GET)).unwrap();
+        assert_eq!(resp.get_status().as_u16(), 301);
+    }
+}
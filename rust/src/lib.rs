@@ -0,0 +1,125 @@
+//! GOV.UK's CDN rules, as a library: configuration parsing, and the
+//! request pipeline run by the Fastly binary (`main.rs`).
+
+pub mod cdn_backend_name;
+pub mod cdn_clock;
+pub mod cdn_compare;
+pub mod cdn_config;
+pub mod cdn_error;
+pub mod cdn_headers;
+pub mod cdn_http;
+pub mod cdn_logging;
+pub mod cdn_memory;
+pub mod cdn_metrics;
+pub mod cdn_rand;
+pub mod cdn_rules;
+pub mod cdn_secrets;
+
+use cdn_config::Config;
+use cdn_error::CdnError;
+use fastly::{Request, Response};
+use std::time::Instant;
+
+/// Handle a request with the configuration for its host: requests
+/// from another node of this service are handled as the shield, other
+/// requests with `Fastly-FF` are rejected, and all others are handled
+/// as the edge.
+pub fn handle(settings: &Config, req: &mut Request) -> Response {
+    cdn_memory::reset_peak();
+    let path = req.get_path().to_string();
+
+    let response = if cdn_rules::is_shield_request(settings, req) {
+        cdn_rand::seed_request(req, true);
+        shield(settings, req)
+    } else if let Some(response) = cdn_rules::reject_forged_shield_request(req) {
+        cdn_rules::log_access(settings, req, &response);
+        response
+    } else {
+        cdn_rand::seed_request(req, false);
+        let response = edge(settings, req);
+        cdn_rules::log_access(settings, req, &response);
+        response
+    };
+
+    cdn_memory::log_usage(settings, &path);
+    response
+}
+
+/// Handle a request from a client: the edge does everything
+/// client-facing (access control, synthetic responses, cookies, A/B
+/// tests, and response transforms).
+pub fn edge(settings: &Config, req: &mut Request) -> Response {
+    if let Some(response) = cdn_rules::legacy_response(settings, req) {
+        return response;
+    }
+
+    match cdn_compare::sample(settings, req) {
+        Some(comparison) => {
+            let mut response = cdn_http::normalise_response_headers(compute(settings, req));
+            cdn_compare::compare(settings, comparison, &mut response);
+            response
+        }
+        None => cdn_http::normalise_response_headers(compute(settings, req)),
+    }
+}
+
+/// Handle a request from a client with this implementation, rather
+/// than the legacy VCL service.
+pub fn compute(settings: &Config, req: &mut Request) -> Response {
+    cdn_rules::sanitise_cookies(settings, req);
+
+    if let Some(response) = cdn_rules::normalise_path(req) {
+        return response;
+    }
+
+    if let Some(response) = cdn_rules::collect_report(settings, req) {
+        return response;
+    }
+
+    if let Some(response) = cdn_rules::synthetic_response(settings, req) {
+        return response;
+    }
+    if let Some(response) = cdn_rules::apply_header_rules(settings, req) {
+        return response;
+    }
+    if let Some(response) = cdn_secrets::recv(settings, req) {
+        return response;
+    }
+    if let Some(response) = cdn_rules::check_request_body(settings, req) {
+        return response;
+    }
+
+    let bereq = match cdn_rules::build_bereq(settings, req) {
+        Ok(bereq) => bereq,
+        Err(error) => return error_response(settings, req, error),
+    };
+
+    let original_bereq = bereq.clone_without_body();
+    let started = Instant::now();
+    match cdn_rules::fetch_beresp(settings, bereq) {
+        Ok((beresp, served_by)) => {
+            let latency = started.elapsed();
+            let resp = cdn_rules::transform_beresp(settings, &original_bereq, beresp, &served_by);
+            cdn_metrics::record_response(settings, &served_by.backend, latency, &resp);
+            resp
+        }
+        Err(error) => error_response(settings, req, error),
+    }
+}
+
+/// Handle a request from another Fastly node: the shield only does
+/// origin-facing work (normalisation and failover), as the edge has
+/// already handled the client.
+pub fn shield(settings: &Config, req: &mut Request) -> Response {
+    let bereq = cdn_rules::build_shield_bereq(req);
+    match cdn_rules::fetch_beresp(settings, bereq) {
+        Ok((beresp, _)) => beresp,
+        Err(error) => error_response(settings, req, error),
+    }
+}
+
+/// Log a failure, and generate its synthetic error page.
+fn error_response(settings: &Config, req: &Request, error: CdnError) -> Response {
+    error.log(settings);
+    error.response(req)
+}
@@ -1,34 +1,183 @@
-mod cdn_config;
-mod cdn_rules;
-mod cdn_secrets;
-
+use fastly::handle::dictionary::DictionaryHandle;
 use fastly::{Error, Request, Response};
+use govuk_edge::cdn_config::{self, HostConfigs};
+use govuk_edge::cdn_error::CdnError;
+use govuk_edge::cdn_logging;
+use govuk_edge::cdn_memory::TrackingAllocator;
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::panic;
+use std::rc::Rc;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+/// Count heap usage, so large requests and panics can be logged with
+/// how much memory they used.
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+/// The parsed compiled-in (or snapshot) configuration, shared by every
+/// request the instance handles.
+static SETTINGS: OnceCell<Option<HostConfigs>> = OnceCell::new();
+
+thread_local! {
+    /// The configuration from the config dictionary, if it has been
+    /// read.
+    static LIVE_SETTINGS: RefCell<Option<LiveSettings>> = RefCell::new(None);
+}
+
+/// Installs the panic hook, the first time a request is handled.
+static PANIC_HOOK: Once = Once::new();
+
+/// Edge Dictionary holding a snapshot of the last configuration which
+/// was deployed successfully, written by the deploy pipeline.
+const CONFIG_SNAPSHOT_DICTIONARY: &str = "config_snapshot";
+
+/// Longest chunk of configuration in the snapshot dictionary (Edge
+/// Dictionary values are limited to 8000 characters, so the snapshot
+/// is split over the keys `config.yaml.0`, `config.yaml.1`, etc)
+const CONFIG_SNAPSHOT_CHUNK_LENGTH: usize = 8000;
+
+/// Edge Dictionary holding a configuration to use instead of the
+/// compiled-in one, split into chunks like the snapshot, so the
+/// configuration can be changed without a rebuild
+const CONFIG_DICTIONARY: &str = "config";
+
+/// How long an instance uses the configuration it read from the
+/// config dictionary before checking it for changes
+const CONFIG_DICTIONARY_TTL: Duration = Duration::from_secs(60);
+
+/// The configuration read from the config dictionary: its text (if
+/// there was any), the parsed configuration (if it parsed), and when
+/// it was read.
+struct LiveSettings {
+    config_str: Option<String>,
+    host_configs: Option<Rc<HostConfigs>>,
+    read_at: Instant,
+}
 
 #[fastly::main]
 fn main(mut req: Request) -> Result<Response, Error> {
-    if let Ok(settings) = cdn_config::parse_config(include_str!("../config.yaml")) {
-        if let Some(response) = cdn_rules::synthetic_response(&settings, &req) {
-            return Ok(response);
+    PANIC_HOOK.call_once(|| {
+        panic::set_hook(Box::new(|info| {
+            let settings = SETTINGS.get().and_then(Option::as_ref);
+            cdn_logging::log_panic(settings.map(|host_configs| &host_configs.default), info);
+        }))
+    });
+
+    if let Some(host_configs) = live_settings() {
+        let settings = host_configs.for_host(req.get_header_str("host"));
+        Ok(govuk_edge::handle(settings, &mut req))
+    } else if let Some(host_configs) = SETTINGS.get_or_init(load_settings) {
+        let settings = host_configs.for_host(req.get_header_str("host"));
+        Ok(govuk_edge::handle(settings, &mut req))
+    } else {
+        Ok(CdnError::Config.response(&req))
+    }
+}
+
+/// Get the configuration from the config dictionary, if there is one
+/// and it parses.  It's cached for `CONFIG_DICTIONARY_TTL`, and only
+/// parsed again if it has changed since.  A configuration which fails
+/// to parse is logged as a `config_error`, and the compiled-in one is
+/// used instead until it's fixed.
+fn live_settings() -> Option<Rc<HostConfigs>> {
+    LIVE_SETTINGS.with(|live| {
+        let mut live = live.borrow_mut();
+        let now = Instant::now();
+
+        if let Some(cached) = live.as_mut() {
+            if now.duration_since(cached.read_at) < CONFIG_DICTIONARY_TTL {
+                return cached.host_configs.clone();
+            }
         }
-        if let Some(response) = cdn_secrets::recv(&settings, &req) {
-            return Ok(response);
+
+        let config_str = read_chunked_dictionary(CONFIG_DICTIONARY);
+        if let Some(cached) = live.as_mut() {
+            if cached.config_str == config_str {
+                cached.read_at = now;
+                return cached.host_configs.clone();
+            }
         }
 
-        match cdn_rules::build_bereq(&settings, &mut req) {
-            Some(bereq) => {
-                let original_bereq = bereq.clone_without_body();
-                match cdn_rules::fetch_beresp(&settings, bereq) {
-                    Some(beresp) => Ok(cdn_rules::transform_beresp(
-                        &settings,
-                        &original_bereq,
-                        beresp,
-                    )),
-                    None => Ok(cdn_rules::synthetic_error_response()),
+        let host_configs = config_str.as_ref().and_then(|config_str| {
+            match cdn_config::parse_host_configs(config_str) {
+                Ok(host_configs) => Some(Rc::new(host_configs)),
+                Err(error) => {
+                    log_dictionary_config_error(&error.to_string());
+                    None
                 }
             }
-            None => Ok(cdn_rules::synthetic_error_response()),
+        });
+        *live = Some(LiveSettings {
+            config_str,
+            host_configs: host_configs.clone(),
+            read_at: now,
+        });
+        host_configs
+    })
+}
+
+/// Log that the configuration in the config dictionary failed to parse.
+fn log_dictionary_config_error(error: &str) {
+    let mut fields = Map::new();
+    fields.insert("error".to_string(), Value::from(error));
+    fields.insert("source".to_string(), Value::from(CONFIG_DICTIONARY));
+    match SETTINGS.get_or_init(load_settings) {
+        Some(host_configs) => cdn_logging::log_event(&host_configs.default, "config_error", fields),
+        None => cdn_logging::log_event_to_stderr("config_error", fields),
+    }
+}
+
+/// Parse the compiled-in configuration.  If that fails, fall back to
+/// the last known good configuration from the snapshot dictionary,
+/// logging the failure either way.
+fn load_settings() -> Option<HostConfigs> {
+    let error = match cdn_config::parse_host_configs(include_str!("../config.yaml")) {
+        Ok(host_configs) => return Some(host_configs),
+        Err(error) => error,
+    };
+
+    let snapshot = read_chunked_dictionary(CONFIG_SNAPSHOT_DICTIONARY)
+        .and_then(|config_str| cdn_config::parse_host_configs(&config_str).ok());
+
+    let mut fields = Map::new();
+    fields.insert("error".to_string(), Value::from(error.to_string()));
+    fields.insert(
+        "using_snapshot".to_string(),
+        Value::from(snapshot.is_some()),
+    );
+    match &snapshot {
+        Some(host_configs) => cdn_logging::log_event(&host_configs.default, "config_error", fields),
+        None => cdn_logging::log_event_to_stderr("config_error", fields),
+    }
+
+    snapshot
+}
+
+/// Read a configuration split over the `config.yaml.<n>` keys of a
+/// dictionary, if there is one.
+fn read_chunked_dictionary(name: &str) -> Option<String> {
+    let handle = DictionaryHandle::open(name)
+        .ok()
+        .filter(|handle| handle.is_valid())?;
+
+    let mut config_str = String::new();
+    for index in 0.. {
+        match handle.get(
+            &format!("config.yaml.{}", index),
+            CONFIG_SNAPSHOT_CHUNK_LENGTH,
+        ) {
+            Ok(Some(chunk)) => config_str.push_str(&chunk),
+            Ok(None) => break,
+            Err(_) => return None,
         }
+    }
+
+    if config_str.is_empty() {
+        None
     } else {
-        Ok(cdn_rules::synthetic_error_response())
+        Some(config_str)
     }
 }